@@ -8,14 +8,14 @@ CLIENT_IP=192.168.43.206
 function server(){
     cd server
     ~/.cargo/bin/cross build --target arm-unknown-linux-gnueabihf
-    sshpass -p $PASSWORD scp target/arm-unknown-linux-gnueabihf/debug/rasp_lora_server pi@$SERVER_IP:/home/pi/
+    sshpass -p $PASSWORD scp ../target/arm-unknown-linux-gnueabihf/debug/rasp_lora_server pi@$SERVER_IP:/home/pi/
     cd ..
 }
 
 function client(){
     cd client
     ~/.cargo/bin/cross build --target arm-unknown-linux-gnueabihf
-    sshpass -p $PASSWORD scp target/arm-unknown-linux-gnueabihf/debug/rasp_lora_client pi@$CLIENT_IP:/home/pi/
+    sshpass -p $PASSWORD scp ../target/arm-unknown-linux-gnueabihf/debug/rasp_lora_client pi@$CLIENT_IP:/home/pi/
     cd ..
 }
 
@@ -0,0 +1,102 @@
+use sx127x_lora::LoRa;
+
+use rppal::gpio::OutputPin;
+use rppal::spi::Spi;
+
+use crate::{
+    filehandling::Config,
+    radio::{RadioProfile, PA_OUTPUT_PA_BOOST_PIN},
+};
+
+/// Switches to the data rate and TX power the AS asked for, if the sx1276 and our region allow them.
+/// Without a region the AS answers in RX1 with the data rate of our uplink, so RX1 follows along.
+///
+/// # Arguments
+///
+/// * `lora` - The radio
+/// * `config` - The config holding our radio profile, which is updated
+/// * `spreading_factor` - The spreading factor to use for our uplinks
+/// * `tx_power` - The TX power in dBm to use for our uplinks
+pub fn set_link(
+    lora: &mut LoRa<Spi, OutputPin, OutputPin>,
+    config: &mut Config,
+    spreading_factor: u8,
+    tx_power: i32,
+) -> Result<(), String> {
+    let mut radio = config.radio;
+    radio.spreading_factor = spreading_factor;
+    radio.tx_power = tx_power;
+    radio.validate().map_err(|x| x.join(", "))?;
+    if let Some(region) = config.region {
+        region
+            .channel_plan()
+            .validate(spreading_factor, radio.bandwidth, tx_power, config.sub_band)
+            .map_err(|x| x.join(", "))?;
+    }
+    lora.set_tx_power(tx_power, PA_OUTPUT_PA_BOOST_PIN)
+        .map_err(|x| format!("Setting tx power failed {:?}", x))?;
+    config.radio = radio;
+    config.rx1_spreading_factor = spreading_factor;
+    println!("Using SF{:?} at {:?} dBm", spreading_factor, tx_power);
+    Ok(())
+}
+
+/// Steps to a more robust link after the AS stopped answering us, if `adr_fallback` is set. First
+/// we go back to the highest TX power we may use, then one spreading factor up, at most to
+/// `adr_max_spreading_factor`. Without ADR the AS only listens on the spreading factor of its
+/// profile, so falling back would lock us out.
+///
+/// # Arguments
+///
+/// * `lora` - The radio
+/// * `config` - The config holding our radio profile, which is updated
+pub fn link_failed(lora: &mut LoRa<Spi, OutputPin, OutputPin>, config: &mut Config) {
+    if !config.adr_fallback {
+        return;
+    }
+    let max_tx_power = match config.region {
+        Some(region) => config
+            .adr_max_tx_power
+            .min(region.channel_plan().max_tx_power),
+        None => config.adr_max_tx_power,
+    };
+    let (spreading_factor, tx_power) = if config.radio.tx_power < max_tx_power {
+        (config.radio.spreading_factor, max_tx_power)
+    } else if config.radio.spreading_factor < config.adr_max_spreading_factor {
+        (config.radio.spreading_factor + 1, config.radio.tx_power)
+    } else {
+        return;
+    };
+    println!("The AS does not hear us, falling back to a more robust link");
+    if let Err(x) = set_link(lora, config, spreading_factor, tx_power) {
+        println!("Falling back failed: {}", x);
+    }
+}
+
+/// Goes back to the spreading factor and TX power of our config file, after falling back or after
+/// the AS changed them in an earlier session.
+///
+/// # Arguments
+///
+/// * `lora` - The radio
+/// * `config` - The config holding our radio profile, which is updated
+/// * `configured` - The radio profile of our config file
+pub fn restore_link(
+    lora: &mut LoRa<Spi, OutputPin, OutputPin>,
+    config: &mut Config,
+    configured: &RadioProfile,
+) {
+    if config.radio.spreading_factor == configured.spreading_factor
+        && config.radio.tx_power == configured.tx_power
+    {
+        return;
+    }
+    if let Err(x) = set_link(
+        lora,
+        config,
+        configured.spreading_factor,
+        configured.tx_power,
+    ) {
+        println!("Restoring the configured link failed: {}", x);
+    }
+}
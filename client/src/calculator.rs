@@ -0,0 +1,323 @@
+use std::time::Duration;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_core::OsRng;
+
+use oscore::edhoc::{PartyI, PartyR};
+
+use twoRatchet::{AS::ASRatchet, ED::EDRatchet};
+
+use x25519_dalek_ng::{PublicKey, StaticSecret};
+
+use crate::{
+    airtime::Modulation,
+    edhoc::{ED_KID, METHOD_TYPE_I, SUITE_I},
+    filehandling::Config,
+};
+
+/// The kid the AS identifies with in the handshake we measure.
+const AS_KID: [u8; 1] = [0xA3];
+
+/// The header the ED puts in front of the first EDHOC message, the mtype and frame counter.
+const ED_FIRST_HEADER: usize = 3;
+/// The header the ED puts in front of its other EDHOC messages, the mtype, frame counter and devaddr.
+const ED_HEADER: usize = 7;
+/// The header the AS puts in front of its EDHOC messages, the mtype and devaddr.
+const AS_HEADER: usize = 5;
+
+/// A frame we send or receive.
+struct Frame {
+    name: &'static str,
+    uplink: bool,
+    length: usize,
+}
+
+/// The `airtime` subcommand. Prints the time on air of every frame type, the data rates of the
+/// region, the link budget and what our uplinks cost per hour, using the radio settings of `config`.
+///
+/// # Arguments
+///
+/// * `config` - The config holding the radio profile and region
+/// * `dhr_const` - The uplinks between DHRs, the `dhr_const` of the config when `None`
+/// * `interval` - The ms between uplinks, the `uplink_interval` of the config when `None`
+/// * `payload_length` - The bytes of application data in an uplink
+pub fn run(
+    config: &Config,
+    dhr_const: Option<u16>,
+    interval: Option<u64>,
+    payload_length: usize,
+) -> Result<(), String> {
+    let dhr_const = dhr_const.unwrap_or(config.dhr_const);
+    let interval = Duration::from_millis(interval.unwrap_or(config.uplink_interval));
+    if interval.is_zero() {
+        return Err("An interval of 0 ms is not possible".to_string());
+    }
+
+    let uplink = Modulation::from_profile(&config.radio);
+    let downlink = match config.region {
+        Some(region) => {
+            let plan = region.channel_plan();
+            let data_rate = plan.rx1_data_rate(
+                plan.uplink_data_rate(uplink.spreading_factor, uplink.bandwidth)
+                    .unwrap_or(plan.rx2_data_rate),
+            );
+            uplink.with_data_rate(data_rate.spreading_factor, data_rate.bandwidth)
+        }
+        None => uplink.with_data_rate(config.rx1_spreading_factor, config.rx1_bandwidth),
+    };
+    let frames = measure_frames(config, payload_length)?;
+
+    println!(
+        "Uplinks with SF{:?} at {:?} Hz, downlinks in RX1 with SF{:?} at {:?} Hz",
+        uplink.spreading_factor, uplink.bandwidth, downlink.spreading_factor, downlink.bandwidth
+    );
+    println!();
+    println!("{:<24} {:>8} {:>12}", "Frame", "Bytes", "Airtime");
+    for frame in frames.iter() {
+        let modulation = if frame.uplink { uplink } else { downlink };
+        println!(
+            "{:<24} {:>8} {:>12?}",
+            frame.name,
+            frame.length,
+            modulation.time_on_air(frame.length)
+        );
+    }
+
+    if let Some(region) = config.region {
+        let plan = region.channel_plan();
+        println!();
+        println!("Uplink data rates in {:?}", region);
+        println!(
+            "{:<16} {:>12} {:>16} {:>16}",
+            "Data rate", "Max payload", "Max airtime", "Fits dwell time"
+        );
+        for data_rate in plan.uplink_data_rates.iter() {
+            let modulation = uplink.with_data_rate(data_rate.spreading_factor, data_rate.bandwidth);
+            let fits = match plan.uplink_dwell_time {
+                Some(dwell_time) => match modulation.max_payload(dwell_time) {
+                    Some(max_payload) => format!("{:?}", max_payload.min(data_rate.max_payload)),
+                    None => "none".to_string(),
+                },
+                None => format!("{:?}", data_rate.max_payload),
+            };
+            println!(
+                "{:<16} {:>12} {:>16?} {:>16}",
+                format!(
+                    "SF{:?}/{:?}",
+                    data_rate.spreading_factor, data_rate.bandwidth
+                ),
+                data_rate.max_payload,
+                modulation.time_on_air(data_rate.max_payload),
+                fits
+            );
+        }
+    }
+
+    println!();
+    println!(
+        "Link budget {:.1} dB, at {:?} dBm and a sensitivity of {:.1} dBm",
+        uplink.link_budget(config.radio.tx_power),
+        config.radio.tx_power,
+        uplink.sensitivity()
+    );
+
+    let frame_airtime = |name: &str| {
+        frames
+            .iter()
+            .find(|frame| frame.name == name)
+            .map(|frame| {
+                let modulation = if frame.uplink { uplink } else { downlink };
+                modulation.time_on_air(frame.length)
+            })
+            .unwrap_or_default()
+    };
+    let uplinks = 3600.0 / interval.as_secs_f64();
+    // A `dhr_const` of 0 never asks for a DHR after a number of uplinks
+    let dhr_requests = if dhr_const == 0 {
+        0.0
+    } else {
+        uplinks / dhr_const as f64
+    };
+    let uplink_airtime = frame_airtime("Ratchet uplink").mul_f64(uplinks)
+        + frame_airtime("DHR request").mul_f64(dhr_requests);
+    let downlink_airtime = frame_airtime("DHR answer").mul_f64(dhr_requests);
+    println!();
+    if dhr_const == 0 {
+        println!(
+            "An uplink every {:?} without DHRs after a number of uplinks costs per hour:",
+            interval
+        );
+    } else {
+        println!(
+            "An uplink every {:?} with a DHR every {:?} uplinks costs per hour:",
+            interval, dhr_const
+        );
+    }
+    println!(
+        "{:.1} uplinks and {:.1} DHR requests, {:?} on air ({:.3}% duty cycle)",
+        uplinks,
+        dhr_requests,
+        uplink_airtime,
+        uplink_airtime.as_secs_f64() / 3600.0 * 100.0
+    );
+    println!(
+        "{:.1} DHR answers from the AS, {:?} on air ({:.3}% duty cycle)",
+        dhr_requests,
+        downlink_airtime,
+        downlink_airtime.as_secs_f64() / 3600.0 * 100.0
+    );
+    if let Some(region) = config.region {
+        let plan = region.channel_plan();
+        for sub_band in plan.sub_bands.iter() {
+            let uplink_channels = plan
+                .enabled_channels(uplink.bandwidth, config.sub_band)
+                .iter()
+                .filter(|channel| sub_band.contains(channel.frequency))
+                .count();
+            if uplink_channels > 0 {
+                println!(
+                    "The {:?} to {:?} Hz sub-band allows {:?} on air per hour",
+                    sub_band.low,
+                    sub_band.high,
+                    sub_band.budget()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs a handshake and a ratchet session between a local ED and AS with throwaway keys, so we know
+/// the exact length of every frame type.
+///
+/// # Arguments
+///
+/// * `config` - The config holding our deveui and appeui, which are part of the first message
+/// * `payload_length` - The length of the payload in our uplinks and the downlinks of the AS
+fn measure_frames(config: &Config, payload_length: usize) -> Result<Vec<Frame>, String> {
+    let mut r: StdRng = StdRng::from_entropy();
+    let ed_static_priv = StaticSecret::from(r.gen::<[u8; 32]>());
+    let ed_static_pub = PublicKey::from(&ed_static_priv);
+    let as_static_priv = StaticSecret::from(r.gen::<[u8; 32]>());
+    let as_static_pub = PublicKey::from(&as_static_priv);
+    let devaddr: [u8; 4] = r.gen();
+
+    let msg1_sender = PartyI::new(
+        config.deveui.to_vec(),
+        config.appeui.to_vec(),
+        r.gen::<[u8; 32]>(),
+        ed_static_priv,
+        ed_static_pub,
+        ED_KID.to_vec(),
+    );
+    let msg1_receiver = PartyR::new(
+        r.gen::<[u8; 32]>(),
+        as_static_priv,
+        as_static_pub,
+        AS_KID.to_vec(),
+    );
+    let (msg1, msg2_receiver) = msg1_sender
+        .generate_message_1(METHOD_TYPE_I, SUITE_I)
+        .map_err(|x| format!("{:?}", x))?;
+    let (msg2_sender, _ad_r, _ad_i) = msg1_receiver
+        .handle_message_1(msg1.clone())
+        .map_err(|x| format!("{:?}", x))?;
+    let (msg2, msg3_receiver) = msg2_sender
+        .generate_message_2()
+        .map_err(|x| format!("{:?}", x))?;
+    let (_as_kid, _ad_r, msg2_verifier) = msg2_receiver
+        .unpack_message_2_return_kid(msg2.clone())
+        .map_err(|x| format!("{:?}", x))?;
+    let msg3_sender = msg2_verifier
+        .verify_message_2(as_static_pub.as_bytes().as_ref())
+        .map_err(|x| format!("{:?}", x))?;
+    let (msg4_receiver, msg3) = msg3_sender
+        .generate_message_3()
+        .map_err(|x| format!("{:?}", x))?;
+    let (msg3_verifier, _ed_kid) = msg3_receiver
+        .unpack_message_3_return_kid(msg3.clone())
+        .map_err(|x| format!("{:?}", x))?;
+    let (msg4_sender, as_sck, as_rck, as_master) = msg3_verifier
+        .verify_message_3(ed_static_pub.as_bytes().as_ref())
+        .map_err(|x| format!("{:?}", x))?;
+    let msg4 = msg4_sender
+        .generate_message_4()
+        .map_err(|x| format!("{:?}", x))?;
+    let (ed_sck, ed_rck, ed_rk) = msg4_receiver
+        .handle_message_4(msg4.clone())
+        .map_err(|x| format!("{:?}", x))?;
+
+    let mut ed_ratchet = EDRatchet::new(
+        ed_rk.try_into().unwrap(),
+        ed_rck.try_into().unwrap(),
+        ed_sck.try_into().unwrap(),
+        devaddr,
+        OsRng,
+    );
+    let mut as_ratchet = ASRatchet::new(
+        as_master.try_into().unwrap(),
+        as_rck.try_into().unwrap(),
+        as_sck.try_into().unwrap(),
+        devaddr,
+        OsRng,
+    );
+    // The port goes in front of the payload
+    let payload = vec![0; payload_length + 1];
+    let uplink = ed_ratchet.ratchet_encrypt_payload(&payload, &devaddr);
+    as_ratchet
+        .receive(uplink.clone())
+        .map_err(|x| format!("{:?}", x))?;
+    let downlink = as_ratchet.ratchet_encrypt_payload(&payload, &devaddr);
+    let dhr_request = ed_ratchet.initiate_ratch();
+    let (dhr_answer, _) = as_ratchet
+        .receive(dhr_request.clone())
+        .map_err(|x| format!("{:?}", x))?;
+
+    Ok(vec![
+        Frame {
+            name: "EDHOC message 1",
+            uplink: true,
+            length: ED_FIRST_HEADER + msg1.len(),
+        },
+        Frame {
+            name: "EDHOC message 2",
+            uplink: false,
+            length: AS_HEADER + msg2.len(),
+        },
+        Frame {
+            name: "EDHOC message 3",
+            uplink: true,
+            length: ED_HEADER + msg3.len(),
+        },
+        Frame {
+            name: "EDHOC message 4",
+            uplink: false,
+            length: AS_HEADER + msg4.len(),
+        },
+        Frame {
+            name: "Ratchet uplink",
+            uplink: true,
+            length: uplink.len(),
+        },
+        Frame {
+            name: "Ratchet downlink",
+            uplink: false,
+            length: downlink.len(),
+        },
+        Frame {
+            name: "DHR request",
+            uplink: true,
+            length: dhr_request.len(),
+        },
+        Frame {
+            name: "DHR answer",
+            uplink: false,
+            length: dhr_answer.len(),
+        },
+        Frame {
+            name: "Unknown session",
+            uplink: false,
+            length: 5,
+        },
+    ])
+}
@@ -0,0 +1,125 @@
+use std::time::{Duration, Instant};
+
+use rand_core::OsRng;
+
+use twoRatchet::ED::EDRatchet;
+
+use crate::{filehandling::Config, generics::backoff};
+
+/// Decides when we perform a Diffie-Hellman ratchet step. A DHR is due once any of the limits of the
+/// config is reached: `dhr_const` uplinks, `dhr_max_age` seconds or `dhr_max_bytes` bytes sent since
+/// the last DHR. This bounds how long a compromised key stays useful, whether the ED sends often or
+/// rarely.
+///
+/// A DHR request whose answer was lost is retransmitted unchanged, up to `dhr_retransmissions`
+/// times, so the AS can answer it from its cache. Every missing answer pushes the next attempt back
+/// exponentially, from `dhr_backoff_base` up to `dhr_backoff_max` ms.
+pub struct DhrPolicy {
+    uplinks: u32,
+    bytes: u64,
+    last_dhr: Instant,
+    missing_answers: u32,
+    retry_at: Option<Instant>,
+    pending: Option<Vec<u8>>,
+    retransmissions: u32,
+}
+
+impl DhrPolicy {
+    /// A policy for a session which just started, the handshake counts as the last DH step.
+    pub fn new() -> Self {
+        DhrPolicy {
+            uplinks: 0,
+            bytes: 0,
+            last_dhr: Instant::now(),
+            missing_answers: 0,
+            retry_at: None,
+            pending: None,
+            retransmissions: 0,
+        }
+    }
+
+    /// Counts an uplink sent with the current keys.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The amount of bytes sent
+    pub fn uplink_sent(&mut self, size: usize) {
+        self.uplinks += 1;
+        self.bytes += size as u64;
+    }
+
+    /// Returns which limit makes a DHR due, or `None` while no limit is reached or we are backing off
+    /// after a missing answer.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The config holding the limits
+    pub fn due(&self, config: &Config) -> Option<String> {
+        if self
+            .retry_at
+            .is_some_and(|retry_at| Instant::now() < retry_at)
+        {
+            return None;
+        }
+        if config.dhr_const != 0 && self.uplinks >= config.dhr_const as u32 {
+            Some(format!("{:?} uplinks sent", self.uplinks))
+        } else if config
+            .dhr_max_age
+            .is_some_and(|max_age| self.last_dhr.elapsed() >= Duration::from_secs(max_age))
+        {
+            Some(format!("{:?} since the last DHR", self.last_dhr.elapsed()))
+        } else if config
+            .dhr_max_bytes
+            .is_some_and(|max_bytes| self.bytes >= max_bytes)
+        {
+            Some(format!("{:?} bytes sent", self.bytes))
+        } else {
+            None
+        }
+    }
+
+    /// The DHR request to send, the unanswered one again while it has retransmissions left,
+    /// otherwise a new one.
+    ///
+    /// # Arguments
+    ///
+    /// * `ed_ratchet` - The ratchet of our session
+    /// * `config` - The config holding `dhr_retransmissions`
+    pub fn request(&mut self, ed_ratchet: &mut EDRatchet<OsRng>, config: &Config) -> Vec<u8> {
+        if let Some(pending) = &self.pending {
+            if self.retransmissions < config.dhr_retransmissions {
+                self.retransmissions += 1;
+                println!(
+                    "Retransmitting DHR request, retransmission {:?}",
+                    self.retransmissions
+                );
+                return pending.clone();
+            }
+        }
+        let request = ed_ratchet.initiate_ratch();
+        self.pending = Some(request.clone());
+        self.retransmissions = 0;
+        request
+    }
+
+    /// The AS answered our DHR request, so we start counting towards the next one.
+    pub fn answered(&mut self) {
+        *self = DhrPolicy::new();
+    }
+
+    /// The AS did not answer our DHR request, we back off before trying again.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The config holding the backoff
+    pub fn missing(&mut self, config: &Config) {
+        self.missing_answers += 1;
+        let delay = backoff(
+            config.dhr_backoff_base,
+            config.dhr_backoff_max,
+            self.missing_answers,
+        );
+        println!("DHR request not answered, trying again in {:?} ms", delay);
+        self.retry_at = Some(Instant::now() + Duration::from_millis(delay));
+    }
+}
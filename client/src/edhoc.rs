@@ -2,7 +2,7 @@ use sx127x_lora::LoRa;
 
 use rppal::gpio::OutputPin;
 use rppal::spi::Spi;
-use x25519_dalek_ng::{StaticSecret, PublicKey};
+use x25519_dalek_ng::{PublicKey, StaticSecret};
 
 use oscore::edhoc::{
     api::{Msg1Sender, Msg2Receiver, Msg4ReceiveVerify},
@@ -10,28 +10,70 @@ use oscore::edhoc::{
     PartyI,
 };
 
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use std::fmt;
 use std::{error::Error as stdError, result::Result};
-use rand::{rngs::StdRng, Rng, SeedableRng};
 
 use crate::{
-    filehandling::{load_static_keys, StaticKeys, Config},
-    generics::{get_message_lenght, recieve_window, prepare_message, remove_message}
+    duty_cycle::TxGate,
+    filehandling::{Config, StaticKeys},
+    generics::{prepare_message, recieve_window, remove_message, transmit_uplink, ReceiveWindow},
 };
 
-const SUITE_I: u8 = 3;
-const METHOD_TYPE_I: u8 = 0;
+pub const SUITE_I: u8 = 3;
+pub const METHOD_TYPE_I: u8 = 0;
+/// The kid we identify with towards the AS, unless the keys file says otherwise.
+pub const ED_KID: [u8; 1] = [0xA2];
 
+/// Why a handshake attempt failed.
 #[derive(Debug)]
-struct MyError(String);
+pub enum HandshakeError {
+    /// Nothing was recieved in the receive windows after sending the message with this mtype.
+    Timeout(u8),
+    /// We recieved a message with another mtype than the one we expected.
+    WrongType { expected: u8, got: u8 },
+    /// The AS reported an error.
+    Peer(String),
+    /// We failed handling the message from the AS.
+    Own(String),
+    /// The AS identified itself with a kid we have no key for.
+    UnknownKid(Vec<u8>),
+}
 
-impl fmt::Display for MyError {
+impl fmt::Display for HandshakeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "There is an error: {}", self.0)
+        match self {
+            HandshakeError::Timeout(mtype) => {
+                write!(
+                    f,
+                    "Recieved nothing in our allocated time span after m type {}",
+                    mtype
+                )
+            }
+            HandshakeError::WrongType { expected, got } => {
+                write!(
+                    f,
+                    "Wrong order, expected m type {} but got m type {}",
+                    expected, got
+                )
+            }
+            HandshakeError::Peer(x) => write!(f, "Peer error {}", x),
+            HandshakeError::Own(x) => write!(f, "Own error {}", x),
+            HandshakeError::UnknownKid(kid) => write!(f, "No key on kid {:?}", kid),
+        }
     }
 }
 
-impl stdError for MyError {}
+impl stdError for HandshakeError {}
+
+impl From<OwnOrPeerError> for HandshakeError {
+    fn from(error: OwnOrPeerError) -> Self {
+        match error {
+            OwnOrPeerError::OwnError(x) => HandshakeError::Own(format!("{:?}", x)),
+            OwnOrPeerError::PeerError(x) => HandshakeError::Peer(x),
+        }
+    }
+}
 
 pub struct RatchetKeys {
     pub ed_sck: Vec<u8>,
@@ -40,14 +82,16 @@ pub struct RatchetKeys {
     pub devaddr: Vec<u8>,
 }
 
+/// Runs a single EDHOC handshake with the AS, using fresh ephemeral keys.
 pub fn handshake(
     lora: &mut LoRa<Spi, OutputPin, OutputPin>,
-    enc_keys: StaticKeys,
+    gate: &mut TxGate,
+    enc_keys: &StaticKeys,
     deveui: [u8; 8],
     appeui: [u8; 8],
     config: Config,
-) -> Result<RatchetKeys, Box<dyn stdError>> {
-    let ed_kid = [0xA2].to_vec();
+) -> Result<RatchetKeys, HandshakeError> {
+    let ed_kid = enc_keys.ed_kid.clone();
     let ed_static_priv = StaticSecret::from(enc_keys.ed_static_material);
     let ed_static_pub = PublicKey::from(&ed_static_priv);
     //let as_static_pub = PublicKey::from(enc_keys.as_static_material);
@@ -63,82 +107,94 @@ pub fn handshake(
         ed_static_pub,
         ed_kid,
     );
-    let (payload1, msg2_reciever) = edhoc_first_message(msg1_sender);
-    let (msg, len) = get_message_lenght(payload1);
+    let (payload1, msg2_reciever) = edhoc_first_message(msg1_sender)?;
+    let incoming = exchange(lora, gate, payload1, 1, config)?;
+    let (msg3, msg4_reciever) = edhoc_third_message(incoming, msg2_reciever, enc_keys)?;
+    let incoming = exchange(lora, gate, msg3, 3, config)?;
+    let values = handle_message_fourth(incoming, msg4_reciever)?;
+    Ok(RatchetKeys {
+        ed_sck: values.ed_sck,
+        ed_rck: values.ed_rck,
+        ed_rk: values.ed_rk,
+        devaddr: values.devaddr,
+    })
+}
 
-    let transmit = lora.transmit_payload_busy(msg, len);
-    match transmit {
-        Ok(packet_size) => println!("Sent packet with size: {:?}", packet_size),
-        Err(_) => println!("Error"),
+/// Transmits an EDHOC message and waits for the answer. As long as we recieve nothing the exact same message is
+/// retransmitted, up to `edhoc_retransmissions` times. Since the message is unchanged the AS recognizes it as a
+/// duplicate and answers with its cached response, so our state and the state of the AS stay the same.
+fn exchange(
+    lora: &mut LoRa<Spi, OutputPin, OutputPin>,
+    gate: &mut TxGate,
+    msg: Vec<u8>,
+    expected: u8,
+    config: Config,
+) -> Result<Vec<u8>, HandshakeError> {
+    let sent = msg[0];
+    for attempt in 0..=config.edhoc_retransmissions {
+        if attempt > 0 {
+            println!(
+                "Retransmitting m type {:?}, retransmission {:?}",
+                sent, attempt
+            );
+        }
+        let uplink = match transmit_uplink(lora, gate, msg.clone(), config) {
+            Ok(uplink) => uplink,
+            Err(x) => {
+                println!("Error {}", x);
+                continue;
+            }
+        };
+        println!("Sent packet with size: {:?}", uplink.size);
+        match expect_message(recieve_window(lora, config, &uplink), sent, expected) {
+            Err(HandshakeError::Timeout(_)) => continue,
+            incoming => return incoming,
+        }
     }
+    Err(HandshakeError::Timeout(sent))
+}
 
-    let incoming = recieve_window(lora, config);
-    match incoming[0] {
-        1 => match edhoc_third_message(incoming.to_vec(), msg2_reciever) {
-            Ok((msg3, msg4_reciever)) => {
-                let (msg, len) = get_message_lenght(msg3);
-                let transmit = lora.transmit_payload_busy(msg, len);
-                match transmit {
-                    Ok(packet_size) => {
-                        println!("Sent packet with size: {:?}", packet_size)
-                    }
-                    Err(_) => println!("Error"),
-                }
-                let incoming = recieve_window(lora, config);
-                match incoming[0] {
-                    3 => {
-                        //let (ed_sck, ed_rck, ed_rk, devaddr) =
-                        //    handle_message_fourth(incoming, msg4_reciever);
-                        let rtn = handle_message_fourth(incoming, msg4_reciever);
-                        match rtn {
-                            Ok(values) => {
-                                let ratchet_keys = RatchetKeys {
-                                    ed_sck: values.ed_sck,
-                                    ed_rck: values.ed_rck,
-                                    ed_rk: values.ed_rk,
-                                    devaddr: values.devaddr,
-                                };
-                                Ok(ratchet_keys)
-                            }
-                            Err(OwnOrPeerError::OwnError(x)) => {
-                                println!("Got my own error {:?}", x);
-                                Err(Box::new(MyError("Own error in m_type 3".to_string())))
-                            }
-                            Err(OwnOrPeerError::PeerError(x)) => {
-                                println!("Got peer error {:?}", x);
-                                Err(Box::new(MyError("Peer error in m_type 3".to_string())))
-                            }
-                        }
-                    }
-                    _ => Err(Box::new(MyError(
-                        "Wrong order, got some other message than mtype 3".to_string(),
-                    ))),
-                }
-            }
-            Err(OwnOrPeerError::PeerError(x)) => Err(Box::new(MyError(x))),
-            Err(OwnOrPeerError::OwnError(_)) => Err(Box::new(MyError("Own error".to_string()))),
-        },
-        _ => Err(Box::new(MyError(
-            "Recieved nothing in our allocated time span".to_string(),
-        ))),
+/// Checks that we recieved something in the receive windows after sending `sent`, and that it has the mtype we expect.
+fn expect_message(
+    incoming: Option<ReceiveWindow>,
+    sent: u8,
+    expected: u8,
+) -> Result<Vec<u8>, HandshakeError> {
+    let incoming = match incoming {
+        Some(downlink) => downlink.buffer,
+        None => return Err(HandshakeError::Timeout(sent)),
+    };
+    match incoming.first() {
+        None => Err(HandshakeError::Timeout(sent)),
+        Some(mtype) if *mtype == expected => Ok(incoming),
+        Some(mtype) => Err(HandshakeError::WrongType {
+            expected,
+            got: *mtype,
+        }),
     }
 }
 
-fn edhoc_first_message(msg1_sender: PartyI<Msg1Sender>) -> (Vec<u8>, PartyI<Msg2Receiver>) {
+fn edhoc_first_message(
+    msg1_sender: PartyI<Msg1Sender>,
+) -> Result<(Vec<u8>, PartyI<Msg2Receiver>), HandshakeError> {
     let (msg1_bytes, msg2_receiver) =
     // If an error happens here, we just abort. No need to send a message,
     // since the protocol hasn't started yet.
-    msg1_sender.generate_message_1(METHOD_TYPE_I, SUITE_I).unwrap();
+    match msg1_sender.generate_message_1(METHOD_TYPE_I, SUITE_I) {
+        Err(OwnError(b)) => return Err(HandshakeError::Own(format!("{:?}", b))),
+        Ok(val) => val,
+    };
 
-    let payload1 = prepare_message(msg1_bytes, 0, true, [0,0,0,0]);
-    (payload1, msg2_receiver)
+    let payload1 = prepare_message(msg1_bytes, 0, true, [0, 0, 0, 0]);
+    Ok((payload1, msg2_receiver))
 }
 
 fn edhoc_third_message(
     msg2: Vec<u8>,
     msg2_receiver: PartyI<Msg2Receiver>,
+    enc_keys: &StaticKeys,
     //as_static_pub: PublicKey,
-) -> Result<(Vec<u8>, PartyI<Msg4ReceiveVerify>), OwnOrPeerError> {
+) -> Result<(Vec<u8>, PartyI<Msg4ReceiveVerify>), HandshakeError> {
     let msg_struc = remove_message(msg2);
     /*unsafe {
         DEVADDR = msg_struc.devaddr;
@@ -148,20 +204,16 @@ fn edhoc_third_message(
     // Needs to be used when verififying message2 instead of &as_static_pub.as_bytes()
     let (as_kid, _ad_r, msg2_verifier) =
         match msg2_receiver.unpack_message_2_return_kid(msg_struc.msg) {
-            Err(OwnOrPeerError::PeerError(s)) => {
-                return Err(OwnOrPeerError::PeerError(s));
-            }
-            Err(OwnOrPeerError::OwnError(b)) => {
-                return Err(OwnOrPeerError::OwnError(b));
+            Err(error) => {
+                return Err(HandshakeError::from(error));
             }
             Ok(val) => val,
         };
 
-    let enc_keys: StaticKeys = load_static_keys("./keys.json".to_string());
     let mut opt_as_static_pub: Option<PublicKey> = None;
-    for each in enc_keys.as_keys {
+    for each in &enc_keys.as_keys {
         if each.kid.to_vec() == as_kid {
-            opt_as_static_pub = Some(PublicKey::from(each.as_static_material));
+            opt_as_static_pub = Some(PublicKey::from(each.as_public_key));
         }
     }
 
@@ -171,14 +223,14 @@ fn edhoc_third_message(
             let msg3_sender =
                 match msg2_verifier.verify_message_2(as_static_pub.as_bytes().as_ref()) {
                     Err(OwnError(b)) => {
-                        return Err(OwnOrPeerError::OwnError(b));
+                        return Err(HandshakeError::Own(format!("{:?}", b)));
                     }
                     Ok(val) => val,
                 };
 
             let (msg4_receiver_verifier, msg3_bytes) = match msg3_sender.generate_message_3() {
                 Err(OwnError(b)) => {
-                    return Err(OwnOrPeerError::OwnError(b));
+                    return Err(HandshakeError::Own(format!("{:?}", b)));
                 }
                 Ok(val) => val,
             };
@@ -186,7 +238,7 @@ fn edhoc_third_message(
             let payload3 = prepare_message(msg3_bytes, 2, false, msg_struc.devaddr);
             Ok((payload3, msg4_receiver_verifier))
         }
-        None => panic!("No key on KID"),
+        None => Err(HandshakeError::UnknownKid(as_kid)),
     }
 }
 
@@ -206,6 +258,11 @@ fn handle_message_fourth(
     match out {
         Err(OwnOrPeerError::PeerError(s)) => Err(OwnOrPeerError::PeerError(s)),
         Err(OwnOrPeerError::OwnError(s)) => Err(OwnOrPeerError::OwnError(s)),
-        Ok((ed_sck, ed_rck, ed_rk)) => Ok(FourthMessage{ed_sck, ed_rck, ed_rk, devaddr: msg_struc.devaddr.to_vec()}),
+        Ok((ed_sck, ed_rck, ed_rk)) => Ok(FourthMessage {
+            ed_sck,
+            ed_rck,
+            ed_rk,
+            devaddr: msg_struc.devaddr.to_vec(),
+        }),
     }
 }
@@ -1,41 +1,179 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
+use std::io;
+use x25519_dalek_ng::{PublicKey, StaticSecret};
+
+use crate::{
+    codec::Schema,
+    edhoc::ED_KID,
+    outbox::OutboxConfig,
+    peer_key::{derived_public_key, PeerKey},
+    radio::RadioProfile,
+    region::Region,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StaticKeys {
+    /// The kid the AS knows our static key by
+    #[serde(default = "default_ed_kid")]
+    pub ed_kid: Vec<u8>,
     pub ed_static_material: [u8; 32],
     pub as_keys: Vec<AsKeys>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+fn default_ed_kid() -> Vec<u8> {
+    ED_KID.to_vec()
+}
+
+impl StaticKeys {
+    /// Checks that no AS entry holds our own key or the secret key of another AS, and returns all
+    /// the problems found.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let ed_public_key = PublicKey::from(&StaticSecret::from(self.ed_static_material));
+        let errors: Vec<String> = self
+            .as_keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, x)| {
+                let derived = derived_public_key(&x.as_public_key);
+                if x.as_public_key == self.ed_static_material {
+                    Some(format!("AS kid {:?} holds our static secret", x.kid))
+                } else if &x.as_public_key == ed_public_key.as_bytes() {
+                    Some(format!("AS kid {:?} holds our own public key", x.kid))
+                } else if let Some((_, other)) = self
+                    .as_keys
+                    .iter()
+                    .enumerate()
+                    .find(|(j, other)| *j != i && other.as_public_key == derived)
+                {
+                    Some(format!(
+                        "AS kid {:?} holds the secret key of AS kid {:?}",
+                        x.kid, other.kid
+                    ))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// The static public key of an AS. It is stored as a `PeerKey`, and an entry which is malformed or
+/// holds secret material is refused when the keys are loaded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "PeerKey", into = "PeerKey")]
 pub struct AsKeys {
     pub kid: Vec<u8>,
-    pub as_static_material: [u8; 32],
+    pub as_public_key: [u8; 32],
+}
+
+impl TryFrom<PeerKey> for AsKeys {
+    type Error = String;
+
+    fn try_from(entry: PeerKey) -> Result<Self, String> {
+        let (kid, as_public_key) = entry.verify()?;
+        Ok(AsKeys { kid, as_public_key })
+    }
+}
+
+impl From<AsKeys> for PeerKey {
+    fn from(keys: AsKeys) -> Self {
+        PeerKey::new(&keys.kid, &keys.as_public_key)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub struct Config {
     pub deveui: [u8; 8],
     pub appeui: [u8; 8],
+    /// We perform a DHR after this many uplinks, never when 0
     pub dhr_const: u16,
+    /// We perform a DHR after this many seconds, never when `None`
+    pub dhr_max_age: Option<u64>,
+    /// We perform a DHR after sending this many bytes, never when `None`
+    pub dhr_max_bytes: Option<u64>,
+    /// How often an unanswered DHR request is sent again before we make a new one
+    pub dhr_retransmissions: u32,
+    pub dhr_backoff_base: u64,
+    pub dhr_backoff_max: u64,
+    /// How many ms we sleep between uplinks
+    pub uplink_interval: u64,
+    /// How many ms we wait after joining before our first uplink, so the AS is ready for it
+    pub start_delay: u64,
+    /// Every this many uplinks we ask the AS how well it hears us, never when 0
+    pub link_check_interval: u16,
+    /// Whether our application uplinks ask the AS for an acknowledgement
+    pub confirmed: bool,
+    /// How often an unacknowledged confirmed uplink is sent again before we give up on it
+    pub confirmed_retransmissions: u32,
+    pub confirmed_backoff_base: u64,
+    pub confirmed_backoff_max: u64,
+    /// Where application messages wait until the AS acknowledged them, without one they are lost
+    /// while the AS can not be reached
+    pub outbox: Option<OutboxConfig>,
+    pub radio: RadioProfile,
+    /// The regional parameters to follow, without one we only use the frequency of the radio profile
+    pub region: Option<Region>,
+    /// The sub-band from 0 to 7 our gateway listens on, only used in US915 and AU915
+    pub sub_band: Option<u8>,
+    /// Whether frames split into fragments get a parity fragment, which can replace any lost fragment
+    pub fragment_fec: bool,
     pub rx1_delay: u64,
     pub rx1_duration: i32,
+    pub rx1_frequency: i64,
+    pub rx1_spreading_factor: u8,
+    pub rx1_bandwidth: i64,
     pub rx2_delay: u64,
     pub rx2_duration: i32,
+    pub rx2_frequency: i64,
+    pub rx2_spreading_factor: u8,
+    pub rx2_bandwidth: i64,
+    pub join_retries: u32,
+    pub join_backoff_base: u64,
+    pub join_backoff_max: u64,
+    pub join_cooldown: u64,
+    pub edhoc_retransmissions: u32,
+    pub max_decrypt_failures: u32,
+    pub max_missing_dhr_answers: u32,
+    /// Whether we fall back to a more robust link on our own when the AS stops hearing us. Only
+    /// for an AS running ADR, which listens on every spreading factor we may fall back to
+    #[serde(default)]
+    pub adr_fallback: bool,
+    /// The highest spreading factor we fall back to when the AS stops hearing us
+    pub adr_max_spreading_factor: u8,
+    /// The highest TX power in dBm we fall back to when the AS stops hearing us
+    pub adr_max_tx_power: i32,
 }
 
-fn load_file(path: String) -> String {
-    fs::read_to_string(path).expect("Unable to read file")
+/// Loads our static key and those of the ASs, failing when the file is missing or an AS entry is
+/// malformed or holds secret material.
+pub fn load_static_keys(path: String) -> Result<StaticKeys, String> {
+    let static_data =
+        fs::read_to_string(&path).map_err(|x| format!("Unable to read {}: {}", path, x))?;
+    serde_json::from_str(&static_data).map_err(|x| format!("{}: {}", path, x))
 }
 
-pub fn load_static_keys(path: String) -> StaticKeys {
-    let static_data = load_file(path);
-    let static_keys: StaticKeys = serde_json::from_str(&static_data).unwrap();
-    static_keys
+/// Loads our config, failing when the file is missing or malformed.
+pub fn load_config(path: String) -> Result<Config, String> {
+    let config_data =
+        fs::read_to_string(&path).map_err(|x| format!("Unable to read {}: {}", path, x))?;
+    serde_json::from_str(&config_data).map_err(|x| format!("{}: {}", path, x))
 }
 
-pub fn load_config(path: String) -> Config {
-    let config_data = load_file(path);
-    let config: Config = serde_json::from_str(&config_data).unwrap();
-    config
-}
\ No newline at end of file
+/// Loads the schema of each port we encode our application data with. Without the file, or on
+/// ports it has no schema for, the data is sent as raw bytes. A file which can not be read or
+/// parsed is an error.
+pub fn load_schemas(path: String) -> Result<HashMap<u8, Schema>, String> {
+    let schema_data = match fs::read_to_string(&path) {
+        Ok(schema_data) => schema_data,
+        Err(x) if x.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(x) => return Err(format!("Unable to read {}: {}", path, x)),
+    };
+    serde_json::from_str(&schema_data).map_err(|x| format!("{}: {}", path, x))
+}
@@ -1,14 +1,29 @@
+use rand::Rng;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
 use sx127x_lora::LoRa;
-use std::{thread, time};
 
 use rppal::gpio::OutputPin;
 use rppal::hal::Delay;
 use rppal::spi::Spi;
 
-use crate::filehandling::Config;
+use crate::{
+    airtime::{Modulation, MAX_FRAME_LENGTH},
+    duty_cycle::{GateError, TxGate},
+    filehandling::Config,
+    fragment::{self, Reassembler, FRAGMENT_GAP, REASSEMBLY_TIMEOUT},
+};
 
 static mut FCNTUP: u16 = 0;
 
+/// The mtype of the notification from the AS telling us it has no ratchet on our devaddr.
+pub const UNKNOWN_SESSION: u8 = 9;
+
+/// How often an uplink waits for the duty cycle before it is given up.
+const MAX_DUTY_CYCLE_WAITS: u32 = 3;
+
 pub struct MessageStruct {
     pub _m: u8,
     pub _fcntdown: [u8; 2],
@@ -21,7 +36,7 @@ pub fn remove_message(ogmsg: Vec<u8>) -> MessageStruct {
         _m: ogmsg[0],
         _fcntdown: ogmsg[1..3].try_into().unwrap(),
         devaddr: ogmsg[3..7].try_into().unwrap(),
-        msg: ogmsg[7..].try_into().unwrap(),
+        msg: ogmsg[7..].to_vec(),
     }
 }
 
@@ -39,35 +54,422 @@ pub fn prepare_message(msg: Vec<u8>, mtype: u8, first_msg: bool, devaddr: [u8; 4
     buffer
 }
 
+/// The ms to wait after the given failed attempt, counting from 1. It doubles for every attempt
+/// from `base` up to `max`, and half of it is random jitter so EDs failing together do not retry
+/// together.
+///
+/// # Arguments
+///
+/// * `base` - The wait after the first attempt, in ms
+/// * `max` - The longest wait, in ms
+/// * `attempt` - The attempts which failed so far
+pub fn backoff(base: u64, max: u64, attempt: u32) -> u64 {
+    let exponential = base.saturating_mul(1 << attempt.saturating_sub(1).min(16));
+    let delay = exponential.min(max);
+    delay / 2 + rand::thread_rng().gen_range(0..=delay / 2)
+}
+
+/// Returns the frame counter of a LoRaRatchet message, which is the first two bytes after the mtype.
+///
+/// # Arguments
+///
+/// * `message` - The LoRaRatchet message
+pub fn get_ratchet_fcnt(message: &[u8]) -> u16 {
+    u16::from_be_bytes([message[1], message[2]])
+}
+
+/// Checks if a downlink is the AS telling us it has no ratchet on our devaddr.
+pub fn is_unknown_session(incoming: &[u8], devaddr: &[u8]) -> bool {
+    incoming.len() >= 5 && incoming[0] == UNKNOWN_SESSION && incoming[1..5] == *devaddr
+}
+
+/// Copies a frame into the buffer the radio transmits from. Anything beyond 255 bytes does not fit
+/// in a single frame and is cut off, larger frames have to be split with `fragment::split` first.
 pub fn get_message_lenght(message: Vec<u8>) -> ([u8; 255], usize) {
     let mut buffer = [0; 255];
-    for (i, byte) in message.iter().enumerate() {
-        buffer[i] = *byte;
+    for (slot, byte) in buffer.iter_mut().zip(message.iter()) {
+        *slot = *byte;
+    }
+    (buffer, message.len().min(buffer.len()))
+}
+
+/// The radio settings we transmit or listen with.
+#[derive(Debug, Clone, Copy)]
+pub struct RadioSettings {
+    /// The frequency in Hz
+    pub frequency: i64,
+    pub spreading_factor: u8,
+    pub bandwidth: i64,
+}
+
+impl RadioSettings {
+    /// The settings of the radio profile, which we transmit with unless a region makes us hop channels.
+    pub fn uplink(config: &Config) -> Self {
+        RadioSettings {
+            frequency: config.radio.frequency * 1_000_000,
+            spreading_factor: config.radio.spreading_factor,
+            bandwidth: config.radio.bandwidth,
+        }
+    }
+
+    /// The settings of the RX1 window. With a region these follow from the channel and data rate of
+    /// the uplink, otherwise they are taken from the config.
+    pub fn rx1(config: &Config, uplink: &Uplink) -> Self {
+        match config.region {
+            Some(region) => {
+                let plan = region.channel_plan();
+                let data_rate = plan.rx1_data_rate(
+                    plan.uplink_data_rate(config.radio.spreading_factor, config.radio.bandwidth)
+                        .unwrap_or(plan.rx2_data_rate),
+                );
+                RadioSettings {
+                    frequency: plan.rx1_frequency(uplink.frequency),
+                    spreading_factor: data_rate.spreading_factor,
+                    bandwidth: data_rate.bandwidth,
+                }
+            }
+            None => RadioSettings {
+                frequency: config.rx1_frequency * 1_000_000,
+                spreading_factor: config.rx1_spreading_factor,
+                bandwidth: config.rx1_bandwidth,
+            },
+        }
+    }
+
+    /// The settings of the RX2 window. With a region these are the RX2 defaults of the region,
+    /// otherwise they are taken from the config.
+    pub fn rx2(config: &Config) -> Self {
+        match config.region {
+            Some(region) => {
+                let plan = region.channel_plan();
+                RadioSettings {
+                    frequency: plan.rx2_frequency,
+                    spreading_factor: plan.rx2_data_rate.spreading_factor,
+                    bandwidth: plan.rx2_data_rate.bandwidth,
+                }
+            }
+            None => RadioSettings {
+                frequency: config.rx2_frequency * 1_000_000,
+                spreading_factor: config.rx2_spreading_factor,
+                bandwidth: config.rx2_bandwidth,
+            },
+        }
+    }
+}
+
+pub fn apply_radio_settings(lora: &mut LoRa<Spi, OutputPin, OutputPin>, settings: RadioSettings) {
+    if lora.set_frequency_hz(settings.frequency).is_err() {
+        println!("Setting frequency {:?} Hz failed", settings.frequency);
+    }
+    if lora
+        .set_spreading_factor(settings.spreading_factor)
+        .is_err()
+    {
+        println!(
+            "Setting spreading factor {:?} failed",
+            settings.spreading_factor
+        );
+    }
+    if lora.set_signal_bandwidth(settings.bandwidth).is_err() {
+        println!("Setting bandwidth {:?} failed", settings.bandwidth);
+    }
+}
+
+/// An uplink we transmitted, the receive windows are timed from and tuned after it.
+#[derive(Debug, Clone, Copy)]
+pub struct Uplink {
+    /// The amount of bytes sent
+    pub size: usize,
+    /// When we were done transmitting
+    pub tx_end: Instant,
+    /// The frequency in Hz we transmitted on
+    pub frequency: i64,
+}
+
+/// Transmits an uplink. With a region we hop pseudo-randomly over its uplink channels, without one
+/// we transmit on the frequency of the radio profile. An uplink larger than the region allows at our
+/// data rate, or than fits its dwell time, is split into fragments which are sent back to back on
+/// the same channel. Every uplink passes the TX gate: we only hop to channels whose sub-band has
+/// budget left for all fragments, and wait for budget when none has.
+///
+/// # Arguments
+///
+/// * `lora` - The radio
+/// * `gate` - The TX gate keeping us within the duty cycle and dwell time of the region
+/// * `msg` - The frame which should be sent
+/// * `config` - The config holding the radio profile and region
+pub fn transmit_uplink(
+    lora: &mut LoRa<Spi, OutputPin, OutputPin>,
+    gate: &mut TxGate,
+    msg: Vec<u8>,
+    config: Config,
+) -> Result<Uplink, String> {
+    let mut settings = RadioSettings::uplink(&config);
+    let modulation = Modulation::from_profile(&config.radio);
+    let mut max_length = MAX_FRAME_LENGTH;
+    let mut channels = vec![settings.frequency];
+    if let Some(region) = config.region {
+        let plan = region.channel_plan();
+        if let Some(data_rate) =
+            plan.uplink_data_rate(settings.spreading_factor, settings.bandwidth)
+        {
+            max_length = max_length.min(data_rate.max_payload);
+        }
+        if let Some(dwell_time) = plan.uplink_dwell_time {
+            max_length = max_length.min(modulation.max_payload(dwell_time).unwrap_or(0));
+        }
+        let enabled = plan.enabled_channels(settings.bandwidth, config.sub_band);
+        if !enabled.is_empty() {
+            channels = enabled.iter().map(|channel| channel.frequency).collect();
+        }
+    }
+    let frames = if msg.len() > max_length {
+        let fragments = fragment::split(&msg, max_length, config.fragment_fec).ok_or(format!(
+            "{:?} bytes is more than the {:?} bytes we can fragment at SF{:?}",
+            msg.len(),
+            fragment::max_frame_length(max_length),
+            settings.spreading_factor
+        ))?;
+        println!(
+            "Splitting {:?} bytes into {:?} fragments",
+            msg.len(),
+            fragments.len()
+        );
+        fragments
+    } else {
+        vec![msg]
+    };
+    let airtimes: Vec<Duration> = frames
+        .iter()
+        .map(|frame| modulation.time_on_air(frame.len()))
+        .collect();
+    let mut waits = 0;
+    settings.frequency = loop {
+        let mut open = Vec::new();
+        let mut wait: Option<Duration> = None;
+        let mut refused = None;
+        for frequency in channels.iter() {
+            match gate.check(*frequency, &airtimes) {
+                Ok(()) => open.push(*frequency),
+                Err(GateError::DutyCycle { wait: until }) => {
+                    wait = Some(wait.map_or(until, |wait| wait.min(until)))
+                }
+                Err(x) => refused = Some(x),
+            }
+        }
+        if !open.is_empty() {
+            break open[rand::random::<usize>() % open.len()];
+        }
+        let wait = match (wait, refused) {
+            (Some(wait), _) if waits < MAX_DUTY_CYCLE_WAITS => wait,
+            (_, Some(x)) => return Err(format!("{:?}", x)),
+            _ => return Err("Duty cycle still used up after waiting".to_string()),
+        };
+        waits += 1;
+        println!("Duty cycle used up, waiting {:?}", wait);
+        thread::sleep(wait);
+    };
+    apply_radio_settings(lora, settings);
+    let mut size = 0;
+    for (frame, airtime) in frames.into_iter().zip(airtimes) {
+        let (buffer, len) = get_message_lenght(frame);
+        size += lora
+            .transmit_payload_busy(buffer, len)
+            .map_err(|x| format!("{:?}", x))?;
+        gate.record(settings.frequency, airtime);
     }
-    (buffer, message.len())
+    let tx_end = Instant::now();
+    if let Some(budget) = gate.budget(settings.frequency) {
+        println!(
+            "Duty-cycle budget of {:?} to {:?} Hz used {:?}, left {:?}",
+            budget.sub_band.low, budget.sub_band.high, budget.used, budget.remaining
+        );
+    }
+    Ok(Uplink {
+        size,
+        tx_end,
+        frequency: settings.frequency,
+    })
+}
+
+/// The receive window a downlink arrived in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Window {
+    Rx1,
+    Rx2,
+}
+
+/// When and where a downlink was received, and how well.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameMeta {
+    pub received: Instant,
+    /// The frequency in Hz
+    pub frequency: i64,
+    pub spreading_factor: u8,
+    /// The signal bandwidth in Hz
+    pub bandwidth: i64,
+    /// The signal strength of the downlink in dBm
+    pub rssi: i32,
+    /// The signal to noise ratio of the downlink in dB
+    pub snr: f64,
+}
+
+pub struct ReceiveWindow {
+    pub window: Window,
+    pub buffer: Vec<u8>,
+    pub meta: FrameMeta,
 }
 
-pub fn recieve_window(lora: &mut LoRa<Spi, OutputPin, OutputPin>, config: Config) -> Vec<u8> {
-    //Result<ReceiveWindow, Box<dyn stdError>> {
-    thread::sleep(time::Duration::from_millis(config.rx1_delay));
-    let poll = lora.poll_irq(Some(config.rx1_duration), &mut Delay);
-    match poll {
+/// Listens for a downlink in the RX1 window and, if nothing arrived, in the RX2 window. Both windows are timed from
+/// the moment `uplink` finished, and use their own delay, duration, frequency, spreading factor and bandwidth.
+/// When a fragment arrives, we keep listening in the window until the frame is complete.
+/// Afterwards the radio is set back to the uplink settings.
+pub fn recieve_window(
+    lora: &mut LoRa<Spi, OutputPin, OutputPin>,
+    config: Config,
+    uplink: &Uplink,
+) -> Option<ReceiveWindow> {
+    let mut incoming = listen_window(
+        lora,
+        config,
+        uplink.tx_end,
+        config.rx1_delay,
+        config.rx1_duration,
+        RadioSettings::rx1(&config, uplink),
+    )
+    .map(|(buffer, meta)| ReceiveWindow {
+        window: Window::Rx1,
+        buffer,
+        meta,
+    });
+    if incoming.is_none() {
+        incoming = listen_window(
+            lora,
+            config,
+            uplink.tx_end,
+            config.rx2_delay,
+            config.rx2_duration,
+            RadioSettings::rx2(&config),
+        )
+        .map(|(buffer, meta)| ReceiveWindow {
+            window: Window::Rx2,
+            buffer,
+            meta,
+        });
+    }
+    apply_radio_settings(lora, RadioSettings::uplink(&config));
+    if let Some(downlink) = &incoming {
+        println!(
+            "Recieved packet in {:?}, {:?} after the uplink, on {:?} Hz with SF{:?} and {:?} Hz, RSSI {:?} dBm, SNR {:?} dB",
+            downlink.window,
+            downlink.meta.received.duration_since(uplink.tx_end),
+            downlink.meta.frequency,
+            downlink.meta.spreading_factor,
+            downlink.meta.bandwidth,
+            downlink.meta.rssi,
+            downlink.meta.snr
+        );
+    }
+    incoming
+}
+
+/// Listens in a single receive window, and for the rest of the fragments if a fragment arrives. The
+/// fragments follow each other back to back, so each has the time on air of the largest frame to
+/// arrive. Returns the whole frame, with how its last fragment was received.
+fn listen_window(
+    lora: &mut LoRa<Spi, OutputPin, OutputPin>,
+    config: Config,
+    tx_end: Instant,
+    delay: u64,
+    duration: i32,
+    settings: RadioSettings,
+) -> Option<(Vec<u8>, FrameMeta)> {
+    let (buffer, mut meta) = listen(lora, tx_end, delay, duration, settings)?;
+    if !fragment::is_fragment(&buffer) {
+        return Some((buffer, meta));
+    }
+    let gap = Modulation::from_profile(&config.radio)
+        .with_data_rate(settings.spreading_factor, settings.bandwidth)
+        .time_on_air(MAX_FRAME_LENGTH)
+        .as_millis() as i32
+        + FRAGMENT_GAP;
+    let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT);
+    let mut fragment = buffer;
+    loop {
+        match reassembler.add(&fragment) {
+            Ok(Some(frame)) => return Some((frame, meta)),
+            Ok(None) => (),
+            Err(x) => println!("{}", x),
+        }
+        if !reassembler.is_pending() {
+            return None;
+        }
+        let (buffer, next_meta) = listen(lora, meta.received, 0, gap, settings)?;
+        fragment = buffer;
+        meta = next_meta;
+    }
+}
+
+/// Opens a single receive window `delay` ms after `tx_end`, listening for `duration` ms. If the window should
+/// already have opened, we listen right away. A downlink is returned with how we received it.
+fn listen(
+    lora: &mut LoRa<Spi, OutputPin, OutputPin>,
+    tx_end: Instant,
+    delay: u64,
+    duration: i32,
+    settings: RadioSettings,
+) -> Option<(Vec<u8>, FrameMeta)> {
+    let opens = tx_end + Duration::from_millis(delay);
+    let now = Instant::now();
+    if opens > now {
+        thread::sleep(opens - now);
+    }
+    apply_radio_settings(lora, settings);
+    match lora.poll_irq(Some(duration), &mut Delay) {
         Ok(size) => {
-            let buffer = lora.read_packet().unwrap();
+            let received = Instant::now();
             println!("Recieved packet with size: {:?}", size);
-            buffer
+            let buffer = lora.read_packet().ok()?;
+            let meta = FrameMeta {
+                received,
+                frequency: settings.frequency,
+                spreading_factor: settings.spreading_factor,
+                bandwidth: settings.bandwidth,
+                rssi: lora.get_packet_rssi().ok()?,
+                snr: lora.get_packet_snr().ok()?,
+            };
+            Some((buffer, meta))
         }
-        Err(_) => {
-            thread::sleep(time::Duration::from_millis(config.rx1_delay));
-            let poll = lora.poll_irq(Some(config.rx1_duration), &mut Delay);
-            match poll {
-                Ok(size) => {
-                    let buffer = lora.read_packet().unwrap();
-                    println!("Recieved packet with size: {:?}", size);
-                    buffer
-                }
-                Err(_) => Vec::new(),
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        for (attempt, delay) in [
+            (0, 1000),
+            (1, 1000),
+            (2, 2000),
+            (3, 4000),
+            (5, 10000),
+            (64, 10000),
+        ] {
+            for _ in 0..20 {
+                let backoff = backoff(1000, 10000, attempt);
+                assert!(
+                    (delay / 2..=delay).contains(&backoff),
+                    "attempt {:?} waited {:?} ms",
+                    attempt,
+                    backoff
+                );
             }
         }
+        // Large bases and attempts saturate instead of overflowing
+        assert!(backoff(u64::MAX, u64::MAX, 40) >= u64::MAX / 2);
+        assert_eq!(backoff(0, 10000, 3), 0);
     }
-}
\ No newline at end of file
+}
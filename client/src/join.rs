@@ -0,0 +1,79 @@
+use std::{thread, time};
+
+use sx127x_lora::LoRa;
+
+use rppal::gpio::OutputPin;
+use rppal::spi::Spi;
+
+use crate::{
+    adr,
+    duty_cycle::TxGate,
+    edhoc::{handshake, HandshakeError, RatchetKeys},
+    filehandling::{Config, StaticKeys},
+    generics::backoff,
+    radio::RadioProfile,
+};
+
+/// The states of joining the network.
+///
+/// * `Attempt` - Running handshake number `attempt`, each with fresh ephemeral keys
+/// * `Backoff` - Waiting before the next attempt, after `attempt` failed
+/// * `Cooldown` - All `join_retries` attempts failed, waiting `join_cooldown` before starting over
+enum JoinState {
+    Attempt(u32),
+    Backoff(u32),
+    Cooldown,
+}
+
+/// Keeps running EDHOC handshakes with the AS until one succeeds. Failed attempts are retried with
+/// exponential backoff and jitter, and once `join_retries` attempts have failed we cool down and
+/// start over, instead of giving up. When the AS does not answer an attempt, we may fall back to a
+/// more robust data rate, and once joined we return to the link of our config file.
+pub fn join(
+    lora: &mut LoRa<Spi, OutputPin, OutputPin>,
+    gate: &mut TxGate,
+    enc_keys: &StaticKeys,
+    config: &mut Config,
+    configured: &RadioProfile,
+) -> RatchetKeys {
+    let mut state = JoinState::Attempt(1);
+    loop {
+        state = match state {
+            JoinState::Attempt(attempt) => {
+                println!("Join attempt {:?}", attempt);
+                match handshake(lora, gate, enc_keys, config.deveui, config.appeui, *config) {
+                    Ok(ratchet_keys) => {
+                        println!("Joined with devaddr {:?}", ratchet_keys.devaddr);
+                        adr::restore_link(lora, config, configured);
+                        return ratchet_keys;
+                    }
+                    Err(error) => {
+                        println!("Join attempt {:?} failed: {}", attempt, error);
+                        if matches!(error, HandshakeError::Timeout(_)) {
+                            adr::link_failed(lora, config);
+                        }
+                        if config.join_retries != 0 && attempt >= config.join_retries {
+                            JoinState::Cooldown
+                        } else {
+                            JoinState::Backoff(attempt)
+                        }
+                    }
+                }
+            }
+            JoinState::Backoff(attempt) => {
+                let delay = backoff(config.join_backoff_base, config.join_backoff_max, attempt);
+                println!("Retrying join in {:?} ms", delay);
+                thread::sleep(time::Duration::from_millis(delay));
+                JoinState::Attempt(attempt + 1)
+            }
+            JoinState::Cooldown => {
+                println!(
+                    "Join failed {:?} times, waiting {:?} ms before trying again",
+                    config.join_retries, config.join_cooldown
+                );
+                thread::sleep(time::Duration::from_millis(config.join_cooldown));
+                JoinState::Attempt(1)
+            }
+        }
+    }
+}
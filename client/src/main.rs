@@ -7,34 +7,272 @@ use rppal::gpio::{Gpio, OutputPin};
 use rppal::hal::Delay;
 use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
 
-mod filehandling;
+use clap::{Parser, Subcommand};
+
+use std::fs;
+use std::io;
+
+use rasp_lora_common::{airtime, codec, duty_cycle, fragment, mac, peer_key, port, radio, region};
+
+use outbox::{Outbox, OUTBOX_PATH};
+use port::{MAC_PORT, MAX_PORT, TELEMETRY_PORT};
+
+mod adr;
+mod calculator;
+mod dhr_policy;
 mod edhoc;
+mod filehandling;
 mod generics;
+mod join;
+mod outbox;
 mod ratchet;
+mod session;
+mod status;
+
+/// The LoRaRatchet client, running on a sx1276 module. The options can also be set by environment
+/// variables, so test benches can script the client without editing its files.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// The JSON config file
+    #[arg(long, env = "LORA_CONFIG", default_value = "./config.json")]
+    config: String,
+    /// The JSON file with our static key and those of the AS
+    #[arg(long, env = "LORA_KEYS", default_value = "./keys.json")]
+    keys: String,
+    /// The JSON file with the schema of each port
+    #[arg(long, env = "LORA_SCHEMAS", default_value = "./schemas.json")]
+    schemas: String,
+    /// The kid the AS knows our static key by, as hex, overriding the keys file
+    #[arg(long, env = "LORA_KID")]
+    kid: Option<String>,
+    /// How many ms we sleep between uplinks, overriding the config
+    #[arg(long, env = "LORA_INTERVAL")]
+    interval: Option<u64>,
+    /// How many ms we wait after joining before our first uplink, overriding the config
+    #[arg(long, env = "LORA_START_DELAY")]
+    start_delay: Option<u64>,
+    /// The GPIO pin wired to the chip select of the sx1276
+    #[arg(long, env = "LORA_CS_PIN", default_value_t = 8)]
+    cs_pin: u8,
+    /// The GPIO pin wired to the reset of the sx1276
+    #[arg(long, env = "LORA_RESET_PIN", default_value_t = 22)]
+    reset_pin: u8,
+    /// What to do, `run` when left out
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-const LORA_CS_PIN: u8 = 8;
-const LORA_RESET_PIN: u8 = 22;
-const FREQUENCY: i64 = 915;
+#[derive(Subcommand)]
+enum Command {
+    /// Join and keep the session running as a service, joining again whenever it ends
+    Run,
+    /// Join once, print our devaddr and exit
+    Join,
+    /// Join, send a single uplink, listen for the answer of the AS and exit
+    Send {
+        /// The port to send the data on
+        #[arg(long, default_value_t = TELEMETRY_PORT)]
+        port: u8,
+        /// Ask the AS to acknowledge the uplink, and fail when it does not
+        #[arg(long)]
+        confirmed: bool,
+        /// The application data, as hex
+        data: String,
+    },
+    /// Print our identity, battery, temperature, uptime and outbox, without using the radio
+    Status,
+    /// Join, ask the AS for a DH ratchet step and exit, failing when the AS does not answer
+    Rekey,
+    /// Clear the outbox, so the next run does not send messages queued in earlier sessions
+    ResetSession,
+    /// Print the time on air of every frame type with our radio settings, and what our uplinks
+    /// cost per hour
+    Airtime {
+        /// The uplinks between DHRs, the `dhr_const` of the config when left out
+        #[arg(long)]
+        dhr_const: Option<u16>,
+        /// How many ms between uplinks, the uplink interval of the config when left out
+        #[arg(long)]
+        interval: Option<u64>,
+        /// The bytes of application data in an uplink
+        #[arg(long, default_value_t = 8)]
+        payload: usize,
+    },
+}
 
 fn main() {
-    let config: filehandling::Config = filehandling::load_config("./config.json".to_string());
-    let enc_keys: filehandling::StaticKeys = filehandling::load_static_keys("./keys.json".to_string());
-    let lora = &mut setup_sx127x(125000, 7);
-    let rtn = edhoc::handshake(lora, enc_keys, config.deveui, config.appeui, config).unwrap();
-    ratchet::run(lora, rtn, config.dhr_const, config);
+    let args = Args::parse();
+    let mut config: filehandling::Config =
+        exit_on_error(filehandling::load_config(args.config.clone()));
+    config.uplink_interval = args.interval.unwrap_or(config.uplink_interval);
+    config.start_delay = args.start_delay.unwrap_or(config.start_delay);
+    let command = args.command.unwrap_or(Command::Run);
+    if let Command::Airtime {
+        dhr_const,
+        interval,
+        payload,
+    } = command
+    {
+        exit_on_error(calculator::run(&config, dhr_const, interval, payload));
+        return;
+    }
+    let mut enc_keys: filehandling::StaticKeys =
+        exit_on_error(filehandling::load_static_keys(args.keys));
+    if let Err(errors) = enc_keys.validate() {
+        for error in errors {
+            println!("Invalid keys: {}", error);
+        }
+        std::process::exit(1);
+    }
+    if let Some(kid) = &args.kid {
+        enc_keys.ed_kid = exit_on_error(parse_hex(kid));
+    }
+    match command {
+        Command::Status => return print_status(&config, &enc_keys),
+        Command::ResetSession => return exit_on_error(reset_session()),
+        _ => (),
+    }
+    if let Err(errors) = config.radio.validate() {
+        for error in errors {
+            println!("Invalid radio profile: {}", error);
+        }
+        std::process::exit(1);
+    }
+    if let Some(region) = config.region {
+        if let Err(errors) = region.channel_plan().validate(
+            config.radio.spreading_factor,
+            config.radio.bandwidth,
+            config.radio.tx_power,
+            config.sub_band,
+        ) {
+            for error in errors {
+                println!("Radio profile not allowed: {}", error);
+            }
+            std::process::exit(1);
+        }
+    }
+    if args.cs_pin == args.reset_pin {
+        println!(
+            "The chip select and reset both use GPIO pin {:?}",
+            args.cs_pin
+        );
+        std::process::exit(1);
+    }
+    let schemas = exit_on_error(filehandling::load_schemas(args.schemas));
+    let lora = &mut setup_sx127x(&config.radio, args.cs_pin, args.reset_pin);
+    // The link falling back or the AS changing it only lasts until we join again
+    let configured = config.radio;
+    let gate = &mut duty_cycle::TxGate::uplink(config.region);
+    match command {
+        Command::Join => {
+            join::join(lora, gate, &enc_keys, &mut config, &configured);
+        }
+        Command::Send {
+            port,
+            confirmed,
+            data,
+        } => {
+            let data = exit_on_error(parse_hex(&data));
+            if port == MAC_PORT || port > MAX_PORT {
+                println!("Port {:?} can not carry application data", port);
+                std::process::exit(1);
+            }
+            let rtn = join::join(lora, gate, &enc_keys, &mut config, &configured);
+            exit_on_error(ratchet::send(
+                lora,
+                gate,
+                rtn,
+                &mut config,
+                port,
+                &data,
+                confirmed,
+            ));
+        }
+        Command::Rekey => {
+            let rtn = join::join(lora, gate, &enc_keys, &mut config, &configured);
+            exit_on_error(ratchet::rekey(lora, gate, rtn, &mut config));
+        }
+        // Keep running as a service, whenever the session ends we join again
+        _ => loop {
+            let rtn = join::join(lora, gate, &enc_keys, &mut config, &configured);
+            ratchet::run(lora, gate, rtn, &mut config, &schemas);
+        },
+    }
 }
 
-fn setup_sx127x(bandwidth: i64, spreadfactor: u8) -> LoRa<Spi, OutputPin, OutputPin> {
+/// Prints the error and exits with a failure, so scripts running the client notice.
+fn exit_on_error<T>(result: Result<T, String>) -> T {
+    result.unwrap_or_else(|x| {
+        println!("{}", x);
+        std::process::exit(1);
+    })
+}
+
+/// Parses hex digits into bytes.
+fn parse_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(format!("{:?} is not hex, two digits per byte", hex));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| format!("{:?} is not hex, two digits per byte", hex))
+        })
+        .collect()
+}
+
+/// Prints who we are and how the device is doing.
+///
+/// # Arguments
+///
+/// * `config` - The config holding our EUIs and outbox
+/// * `enc_keys` - The keys holding our kid
+fn print_status(config: &filehandling::Config, enc_keys: &filehandling::StaticKeys) {
+    println!("DevEUI {:?}, AppEUI {:?}", config.deveui, config.appeui);
+    println!("Kid {:?}", enc_keys.ed_kid);
+    println!(
+        "Battery {:?}, temperature {:?} C, uptime {:?} s",
+        status::battery(),
+        status::temperature(),
+        status::uptime()
+    );
+    match config.outbox.map(|x| Outbox::summary(OUTBOX_PATH, x)) {
+        Some(Ok(summary)) => println!(
+            "Outbox holds {:?} messages, {:?} of them expired, the oldest queued {:?} s ago",
+            summary.messages,
+            summary.expired,
+            summary.oldest.unwrap_or(0)
+        ),
+        Some(Err(x)) => println!("Unable to read the outbox: {}", x),
+        None => println!("No outbox"),
+    }
+}
+
+/// Removes the outbox, the only state we keep between runs.
+fn reset_session() -> Result<(), String> {
+    match fs::remove_file(OUTBOX_PATH) {
+        Ok(()) => println!("Cleared the outbox"),
+        Err(x) if x.kind() == io::ErrorKind::NotFound => println!("The outbox is already empty"),
+        Err(x) => return Err(format!("Unable to clear the outbox: {}", x)),
+    }
+    Ok(())
+}
+
+fn setup_sx127x(
+    profile: &radio::RadioProfile,
+    cs_pin: u8,
+    reset_pin: u8,
+) -> LoRa<Spi, OutputPin, OutputPin> {
     let spi = Spi::new(Bus::Spi0, SlaveSelect::Ss0, 8_000_000, Mode::Mode0).unwrap();
 
     let gpio = Gpio::new().unwrap();
 
-    let cs = gpio.get(LORA_CS_PIN).unwrap().into_output();
-    let reset = gpio.get(LORA_RESET_PIN).unwrap().into_output();
+    let cs = gpio.get(cs_pin).unwrap().into_output();
+    let reset = gpio.get(reset_pin).unwrap().into_output();
 
-    let mut lora = sx127x_lora::LoRa::new(spi, cs, reset, FREQUENCY, &mut Delay).unwrap();
-    //let _ = lora.set_signal_bandwidth()
-    let _ = lora.set_signal_bandwidth(bandwidth);
-    let _ = lora.set_spreading_factor(spreadfactor);
+    let mut lora = sx127x_lora::LoRa::new(spi, cs, reset, profile.frequency, &mut Delay).unwrap();
+    profile.apply(&mut lora);
     lora
-}
\ No newline at end of file
+}
@@ -0,0 +1,280 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::fs;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where the outbox is stored, so queued messages survive a restart of the ED.
+pub const OUTBOX_PATH: &str = "./outbox.json";
+
+/// Which message makes room when the outbox is full.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// The oldest message is dropped
+    Oldest,
+    /// The oldest message with the lowest priority is dropped, or the new one if its priority is lower
+    LowestPriority,
+    /// The new message is dropped
+    Newest,
+}
+
+/// How the outbox holds application messages while the AS can not be reached.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct OutboxConfig {
+    /// The most messages the outbox holds
+    pub capacity: usize,
+    /// Messages are dropped after this many seconds in the outbox, never when `None`
+    pub max_age: Option<u64>,
+    pub drop_policy: DropPolicy,
+    /// The priority of our application data, higher priorities are sent first
+    pub priority: u8,
+    /// How many ms we sleep between uplinks while sending the messages which piled up
+    pub drain_interval: u64,
+}
+
+/// An application message waiting to be delivered.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub port: u8,
+    pub payload: Vec<u8>,
+    pub priority: u8,
+    /// When the message was queued, in seconds since the Unix epoch
+    pub queued: u64,
+}
+
+/// What the outbox holds, read without changing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    /// The amount of messages waiting, including the expired ones
+    pub messages: usize,
+    /// The messages older than `max_age`, dropped the next time the outbox is used
+    pub expired: usize,
+    /// How many seconds ago the oldest message was queued
+    pub oldest: Option<u64>,
+}
+
+/// The seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+/// A bounded queue of application messages, stored in a file. A message stays in the outbox until
+/// the AS acknowledged it, so nothing is lost while the gateway is down.
+pub struct Outbox {
+    config: OutboxConfig,
+    path: String,
+    messages: Vec<Message>,
+}
+
+impl Outbox {
+    /// Loads the outbox from `path`, or starts an empty one if there is none.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Where the outbox is stored
+    /// * `config` - The limits of the outbox
+    pub fn load(path: &str, config: OutboxConfig) -> Self {
+        let messages = match fs::read_to_string(path) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|x| {
+                println!("Unable to parse the outbox {:?}, starting empty", x);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        let mut outbox = Outbox {
+            config,
+            path: path.to_string(),
+            messages,
+        };
+        outbox.prune();
+        println!("Outbox holds {:?} messages", outbox.len());
+        outbox
+    }
+
+    /// Summarizes the outbox stored at `path`, without pruning or rewriting it like `load` does.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Where the outbox is stored
+    /// * `config` - The limits of the outbox
+    pub fn summary(path: &str, config: OutboxConfig) -> Result<Summary, String> {
+        let messages: Vec<Message> = match fs::read_to_string(path) {
+            Ok(data) => serde_json::from_str(&data).map_err(|x| format!("{}: {}", path, x))?,
+            Err(x) if x.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(x) => return Err(format!("Unable to read {}: {}", path, x)),
+        };
+        let now = now();
+        let age = |message: &Message| now.saturating_sub(message.queued);
+        Ok(Summary {
+            messages: messages.len(),
+            expired: match config.max_age {
+                Some(max_age) => messages.iter().filter(|x| age(x) >= max_age).count(),
+                None => 0,
+            },
+            oldest: messages.iter().map(age).max(),
+        })
+    }
+
+    /// The amount of messages waiting.
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Whether no messages are waiting.
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Queues a message, making room following the drop policy when the outbox is full.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The port the message is sent on
+    /// * `payload` - The application data
+    /// * `priority` - Higher priorities are sent first
+    pub fn push(&mut self, port: u8, payload: Vec<u8>, priority: u8) {
+        self.prune();
+        let message = Message {
+            port,
+            payload,
+            priority,
+            queued: now(),
+        };
+        if self.messages.len() >= self.config.capacity.max(1) {
+            let dropped = match self.config.drop_policy {
+                DropPolicy::Oldest => Some(0),
+                DropPolicy::LowestPriority => self
+                    .messages
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(index, queued)| (queued.priority, *index))
+                    .filter(|(_, queued)| queued.priority <= priority)
+                    .map(|(index, _)| index),
+                DropPolicy::Newest => None,
+            };
+            match dropped {
+                Some(index) => {
+                    let dropped = self.messages.remove(index);
+                    println!("Outbox full, dropped {:?}", dropped);
+                }
+                None => {
+                    println!("Outbox full, dropped {:?}", message);
+                    return;
+                }
+            }
+        }
+        self.messages.push(message);
+        self.save();
+    }
+
+    /// The message to send next, the oldest of those with the highest priority.
+    pub fn next(&mut self) -> Option<Message> {
+        self.prune();
+        self.messages
+            .iter()
+            .enumerate()
+            .max_by_key(|(index, message)| (message.priority, Reverse(*index)))
+            .map(|(_, message)| message.clone())
+    }
+
+    /// Removes a message the AS acknowledged.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message returned by `next`
+    pub fn delivered(&mut self, message: &Message) {
+        if let Some(index) = self.messages.iter().position(|queued| queued == message) {
+            self.messages.remove(index);
+            self.save();
+        }
+    }
+
+    /// Drops the messages older than `max_age`.
+    fn prune(&mut self) {
+        if let Some(max_age) = self.config.max_age {
+            let now = now();
+            let before = self.messages.len();
+            self.messages
+                .retain(|message| now.saturating_sub(message.queued) < max_age);
+            if self.messages.len() != before {
+                println!(
+                    "Dropped {:?} expired messages from the outbox",
+                    before - self.messages.len()
+                );
+                self.save();
+            }
+        }
+    }
+
+    fn save(&self) {
+        let data = match serde_json::to_string(&self.messages) {
+            Ok(data) => data,
+            Err(x) => return println!("Unable to serialize the outbox {:?}", x),
+        };
+        if let Err(x) = fs::write(&self.path, data) {
+            println!("Unable to store the outbox {:?}", x);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> OutboxConfig {
+        OutboxConfig {
+            capacity: 10,
+            max_age: Some(60),
+            drop_policy: DropPolicy::Oldest,
+            priority: 0,
+            drain_interval: 1000,
+        }
+    }
+
+    #[test]
+    fn summary_leaves_the_outbox_untouched() {
+        let path = std::env::temp_dir().join(format!("outbox-summary-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let messages = vec![
+            Message {
+                port: 1,
+                payload: vec![1],
+                priority: 0,
+                queued: now() - 120,
+            },
+            Message {
+                port: 2,
+                payload: vec![2],
+                priority: 1,
+                queued: now(),
+            },
+        ];
+        let data = serde_json::to_string(&messages).unwrap();
+        fs::write(path, &data).unwrap();
+        let summary = Outbox::summary(path, config()).unwrap();
+        assert_eq!(summary.messages, 2);
+        assert_eq!(summary.expired, 1);
+        assert!(summary.oldest.unwrap() >= 120);
+        assert_eq!(fs::read_to_string(path).unwrap(), data);
+        // Loading drops the expired message for good
+        assert_eq!(Outbox::load(path, config()).len(), 1);
+        assert_eq!(Outbox::summary(path, config()).unwrap().messages, 1);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn summary_of_a_missing_outbox_is_empty() {
+        let summary = Outbox::summary("./no-such-outbox.json", config()).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                messages: 0,
+                expired: 0,
+                oldest: None,
+            }
+        );
+    }
+}
@@ -1,7 +1,12 @@
+use std::collections::HashMap;
+use std::process::Command;
+use std::time::Instant;
 use std::{thread, time};
 
 use rand_core::OsRng;
 
+use serde_json::json;
+
 use twoRatchet::ED::EDRatchet;
 
 use sx127x_lora::LoRa;
@@ -11,109 +16,613 @@ use rppal::gpio::OutputPin;
 use rppal::spi::Spi;
 
 use crate::{
-    filehandling::{Config},
-    generics::{get_message_lenght, recieve_window},
-    edhoc::{RatchetKeys}
+    adr,
+    codec::{self, Schema},
+    dhr_policy::DhrPolicy,
+    duty_cycle::TxGate,
+    edhoc::RatchetKeys,
+    filehandling::Config,
+    generics::{backoff, get_ratchet_fcnt, is_unknown_session, recieve_window, transmit_uplink},
+    mac::{self, MacCommand},
+    outbox::{Outbox, OUTBOX_PATH},
+    port::{self, ALARM_PORT, CONFIG_PORT, MAC_PORT, TELEMETRY_PORT},
+    session::SessionHealth,
+    status::{battery, device_status, temperature, uptime, LOW_BATTERY},
 };
 
+/// What the AS asked us to do once we have acknowledged it.
+enum Restart {
+    Rejoin,
+    Reboot,
+}
+
+/// Our answers to the MAC commands of the AS which we still have to send, whether the AS asked us
+/// for a DHR or to restart, and the frame counter of the latest uplink it acknowledged. Also holds
+/// the configuration an application sent us, until we acknowledge its version.
+#[derive(Default)]
+struct MacState {
+    answers: Vec<MacCommand>,
+    dhr_requested: bool,
+    restart: Option<Restart>,
+    acknowledged: Option<u16>,
+    configuration: Option<Vec<u8>>,
+}
+
+/// What became of an uplink with application data.
+enum Delivery {
+    /// It was sent without asking the AS for an acknowledgement.
+    Unconfirmed,
+    /// The AS acknowledged it, after we sent it `retransmissions` more times.
+    Acknowledged { retransmissions: u32 },
+    /// The AS did not acknowledge it, not even after all retransmissions.
+    Failed,
+}
+
+/// Our ratchet session with the AS: the ratchet, how healthy the session is, what the AS asked of
+/// us, and when we ask for the next DH step.
+struct Session {
+    ed_ratchet: EDRatchet<OsRng>,
+    devaddr: Vec<u8>,
+    health: SessionHealth,
+    mac_state: MacState,
+    dhr_policy: DhrPolicy,
+}
+
+impl Session {
+    /// Starts the session with the keys of the handshake, after giving the AS `start_delay` ms to
+    /// get ready for our first uplink.
+    fn start(ratchetkeys: RatchetKeys, config: &Config) -> Self {
+        let ed_ratchet = EDRatchet::new(
+            ratchetkeys.ed_rk.try_into().unwrap(),
+            ratchetkeys.ed_rck.try_into().unwrap(),
+            ratchetkeys.ed_sck.try_into().unwrap(),
+            ratchetkeys.devaddr.clone().try_into().unwrap(),
+            OsRng,
+        );
+        thread::sleep(time::Duration::from_millis(config.start_delay));
+        Session {
+            ed_ratchet,
+            devaddr: ratchetkeys.devaddr,
+            health: SessionHealth::new(*config),
+            mac_state: MacState::default(),
+            dhr_policy: DhrPolicy::new(),
+        }
+    }
+}
+
+/// Runs the ratchet session until it is desynchronized, after which we have to join again.
 pub fn run(
     lora: &mut LoRa<Spi, OutputPin, OutputPin>,
+    gate: &mut TxGate,
     ratchetkeys: RatchetKeys,
-    dhr_const: u16,
-    config: Config,
+    config: &mut Config,
+    schemas: &HashMap<u8, Schema>,
 ) {
-    let ed_ratchet = EDRatchet::new(
-        ratchetkeys.ed_rk.try_into().unwrap(),
-        ratchetkeys.ed_rck.try_into().unwrap(),
-        ratchetkeys.ed_sck.try_into().unwrap(),
-        ratchetkeys.devaddr.clone().try_into().unwrap(),
-        OsRng,
-    );
+    let session = Session::start(ratchetkeys, config);
+    message(lora, gate, session, config, schemas);
+}
 
-    thread::sleep(time::Duration::from_millis(5000));
+/// Sends a single uplink in the session of a fresh join and listens for the answer of the AS, so
+/// the client can be scripted. Fails when a confirmed uplink is not acknowledged.
+///
+/// # Arguments
+///
+/// * `ratchetkeys` - The keys of the handshake
+/// * `config` - Our config
+/// * `port` - The port to send the data on
+/// * `data` - The application data
+/// * `confirmed` - Whether the AS has to acknowledge the uplink
+pub fn send(
+    lora: &mut LoRa<Spi, OutputPin, OutputPin>,
+    gate: &mut TxGate,
+    ratchetkeys: RatchetKeys,
+    config: &mut Config,
+    port: u8,
+    data: &[u8],
+    confirmed: bool,
+) -> Result<(), String> {
+    let mut session = Session::start(ratchetkeys, config);
+    let payload = port::encode(port, confirmed, data);
+    match deliver(lora, gate, &mut session, config, &payload, confirmed) {
+        (Delivery::Failed, _) => Err("The AS did not acknowledge the uplink".to_string()),
+        _ => Ok(()),
+    }
+}
 
-    message(lora, ed_ratchet, dhr_const, 1, config, ratchetkeys.devaddr);
+/// Asks the AS for a DH ratchet step right after joining, so the client can be scripted. Fails
+/// when the AS does not answer.
+///
+/// # Arguments
+///
+/// * `ratchetkeys` - The keys of the handshake
+/// * `config` - Our config
+pub fn rekey(
+    lora: &mut LoRa<Spi, OutputPin, OutputPin>,
+    gate: &mut TxGate,
+    ratchetkeys: RatchetKeys,
+    config: &mut Config,
+) -> Result<(), String> {
+    let mut session = Session::start(ratchetkeys, config);
+    if request_dhr(lora, gate, &mut session, config) {
+        println!("DHR completed, dh_id {:?}", session.ed_ratchet.dh_id);
+        Ok(())
+    } else {
+        Err("The AS did not answer the DHR request".to_string())
+    }
 }
 
 fn message(
     lora: &mut LoRa<Spi, OutputPin, OutputPin>,
-    mut ed_ratchet: EDRatchet<OsRng>,
-    dhr_const: u16,
-    n: i32,
-    config: Config,
-    devaddr: Vec<u8>,
+    gate: &mut TxGate,
+    mut session: Session,
+    config: &mut Config,
+    schemas: &HashMap<u8, Schema>,
 ) {
+    let mut outbox = config
+        .outbox
+        .map(|outbox_config| Outbox::load(OUTBOX_PATH, outbox_config));
+    // Whether the AS acknowledged or answered us lately, until then the outbox holds our messages
+    let mut reachable = true;
+    let mut next_reading = Instant::now();
+    let mut low_battery = false;
+    // Without an outbox, the application data which did not fit in an uplink waits for the next one
+    let mut deferred: Vec<(u8, Vec<u8>)> = Vec::new();
     loop {
-        println!("{:?}", n);
-        let random_message: [u8; 8] = rand::random();
-        let uplink = ed_ratchet.ratchet_encrypt_payload(&random_message, &devaddr);
-        let (msg_uplink, len_uplink) = get_message_lenght(uplink);
-        let transmit = lora.transmit_payload_busy(msg_uplink, len_uplink);
-        match transmit {
-            Ok(packet_size) => {
-                /*unsafe {
-                    MESSAGENUMBER += 1;
-                }*/
-                println!("Uplink message {:?}", n);
-                println!("Sent packet with size: {:?}", packet_size)
+        // Our application data, most important first. Telemetry is taken every `uplink_interval` ms,
+        // also while draining the outbox, and an alarm is sent once the battery runs low
+        let mut readings = Vec::new();
+        let due = Instant::now() >= next_reading;
+        if due {
+            next_reading = Instant::now() + time::Duration::from_millis(config.uplink_interval);
+            let battery = battery();
+            let low = (1..LOW_BATTERY).contains(&battery);
+            if low && !low_battery {
+                readings.push((ALARM_PORT, vec![battery]));
+            }
+            low_battery = low;
+        }
+        if let Some(configuration) = session.mac_state.configuration.take() {
+            if let Some(version) = config_version(schemas, &configuration) {
+                readings.push((CONFIG_PORT, vec![version]));
+            }
+        }
+        if due {
+            if let Some(telemetry) = telemetry(schemas) {
+                readings.push((TELEMETRY_PORT, telemetry));
             }
-            Err(_) => println!("Error uplink"),
-        }
-        let incoming = recieve_window(lora, config);
-        if !incoming.is_empty() {
-            match ed_ratchet.receive(incoming.to_vec()) {
-                Ok(x) => match x {
-                    Some(y) => {
-                        println!("receiving message from server {:?}", y)
+        }
+        let mut commands = session.mac_state.answers.clone();
+        let probe = outbox.is_some() && !reachable;
+        if probe
+            || config.link_check_interval != 0
+                && session
+                    .ed_ratchet
+                    .fcnt_up
+                    .is_multiple_of(config.link_check_interval)
+        {
+            commands.push(MacCommand::LinkCheckReq);
+        }
+        if let (Some(outbox), Some(outbox_config)) = (&mut outbox, config.outbox) {
+            for (port, data) in readings.drain(..) {
+                // Alarms and acknowledgements go before the telemetry which piled up
+                let priority = if port == TELEMETRY_PORT {
+                    outbox_config.priority
+                } else {
+                    u8::MAX
+                };
+                outbox.push(port, data, priority);
+            }
+        } else {
+            // Only the latest telemetry is worth sending, and it goes after alarms and acknowledgements
+            for (port, data) in readings {
+                if port == TELEMETRY_PORT {
+                    deferred.retain(|(port, _)| *port != TELEMETRY_PORT);
+                }
+                deferred.push((port, data));
+            }
+            deferred.sort_by_key(|(port, _)| *port == TELEMETRY_PORT);
+        }
+        // MAC commands take the place of our application data, which waits for a later uplink
+        let mut message = None;
+        let application = if !commands.is_empty() {
+            None
+        } else if let Some(outbox) = &mut outbox {
+            message = outbox.next();
+            message
+                .as_ref()
+                .map(|message| (message.port, message.payload.clone()))
+        } else if deferred.is_empty() {
+            None
+        } else {
+            Some(deferred.remove(0))
+        };
+        // The outbox only lets go of a message once the AS acknowledged it
+        let confirmed = application.is_some() && (config.confirmed || message.is_some());
+        let payload = match application {
+            Some((port, data)) => port::encode(port, confirmed, &data),
+            None if !commands.is_empty() => mac::encode(&commands),
+            None => {
+                sleep(lora, next_reading.saturating_duration_since(Instant::now()));
+                continue;
+            }
+        };
+        println!("Uplink message {:?}", session.ed_ratchet.fcnt_up);
+        let (delivery, answered) = deliver(lora, gate, &mut session, config, &payload, confirmed);
+        if answered && probe {
+            println!("The AS answered our link check, sending the outbox");
+            reachable = true;
+        }
+        match delivery {
+            Delivery::Unconfirmed => (),
+            Delivery::Acknowledged { .. } => {
+                reachable = true;
+                if let (Some(outbox), Some(message)) = (&mut outbox, &message) {
+                    outbox.delivered(message);
+                }
+            }
+            Delivery::Failed => {
+                reachable = false;
+                if let Some(outbox) = &outbox {
+                    println!("Keeping {:?} messages in the outbox", outbox.len());
+                }
+                adr::link_failed(lora, config);
+            }
+        }
+        if let Some(restart) = session.mac_state.restart.take() {
+            acknowledge(lora, gate, &mut session, config);
+            match restart {
+                Restart::Rejoin => println!("The AS asked us to join again"),
+                Restart::Reboot => {
+                    println!("The AS asked us to reboot");
+                    if let Err(x) = Command::new("reboot").status() {
+                        println!("Rebooting failed {:?}, joining again instead", x);
                     }
-                    None => println!("test"),
-                },
-                Err(x) => {
-                    println!("{:?}", x)
                 }
-            };
-        }
-        if ed_ratchet.fcnt_up >= dhr_const {
-            //println!("BEFORE: fcnt_up {:?} dh_id {:?}", ed_ratchet.fcnt_up, ed_ratchet.dh_id);
-            let dhr_req = ed_ratchet.initiate_ratch(); //ed_initiate_ratch();
-            let (msg_dhr_req, len_dhr_req) = get_message_lenght(dhr_req);
-            println!("DHR_REQ payload print {:?}", &msg_dhr_req);
-            let transmit = lora.transmit_payload_busy(msg_dhr_req, len_dhr_req);
-            match transmit {
-                Ok(packet_size) => {
-                    /*unsafe {
-                        MESSAGENUMBER += 1;
-                    }*/
-                    println!("Sent packet with size: {:?}", packet_size);
-                    let incoming = recieve_window(lora, config);
-                    if !incoming.is_empty() {
-                        match ed_ratchet.receive(incoming.to_vec()) {
-                            Ok(x) => match x {
-                                Some(y) => {
-                                    println!("receiving message from server {:?}", y)
-                                }
-                                None => println!("test"),
-                            },
-                            Err(x) => {
-                                println!("{:?}", x)
-                            }
-                        };
+            }
+            return;
+        }
+        let dhr_due = match session.dhr_policy.due(config) {
+            Some(reason) => Some(reason),
+            None if session.mac_state.dhr_requested => Some("the AS asked for it".to_string()),
+            None => None,
+        };
+        if let (Some(reason), None) = (dhr_due, session.health.desynchronized()) {
+            println!("DHR due, {}", reason);
+            request_dhr(lora, gate, &mut session, config);
+        }
+
+        if let Some(reason) = session.health.desynchronized() {
+            println!("Session desynchronized, {}. Joining again", reason);
+            return;
+        }
+        let draining = reachable && outbox.as_ref().is_some_and(|outbox| !outbox.is_empty());
+        match (draining, config.outbox) {
+            (true, Some(outbox_config)) => sleep(
+                lora,
+                time::Duration::from_millis(outbox_config.drain_interval),
+            ),
+            _ => sleep(lora, time::Duration::from_millis(config.uplink_interval)),
+        }
+    }
+}
+
+/// Sends an uplink and handles the answer of the AS in the receive windows. A confirmed uplink is
+/// sent again with backoff until the AS acknowledges it or we run out of retransmissions. Returns
+/// what became of the uplink, and whether the AS answered us at all.
+///
+/// # Arguments
+///
+/// * `session` - Our ratchet session, which encrypts the uplink
+/// * `config` - Our config, which the MAC commands of the AS may change
+/// * `payload` - The plaintext of the uplink, starting with its port
+/// * `confirmed` - Whether the AS has to acknowledge the uplink
+fn deliver(
+    lora: &mut LoRa<Spi, OutputPin, OutputPin>,
+    gate: &mut TxGate,
+    session: &mut Session,
+    config: &mut Config,
+    payload: &[u8],
+    confirmed: bool,
+) -> (Delivery, bool) {
+    let uplink = session
+        .ed_ratchet
+        .ratchet_encrypt_payload(payload, &session.devaddr);
+    let fcnt = get_ratchet_fcnt(&uplink);
+    let mut answered = false;
+    // A retransmission is the exact same frame, the AS answers it from its cache instead of
+    // decrypting a frame counter it already used
+    let mut retransmissions = 0;
+    let delivery = loop {
+        match transmit_uplink(lora, gate, uplink.clone(), *config) {
+            Ok(sent) => {
+                session.mac_state.answers.clear();
+                if retransmissions == 0 {
+                    session.dhr_policy.uplink_sent(sent.size);
+                }
+                println!("Sent packet with size: {:?}", sent.size);
+                if let Some(downlink) = recieve_window(lora, *config, &sent) {
+                    answered |= handle_downlink(lora, config, session, downlink.buffer);
+                }
+            }
+            Err(x) => println!("Error uplink {}", x),
+        }
+        if !confirmed {
+            break Delivery::Unconfirmed;
+        }
+        if session.mac_state.acknowledged == Some(fcnt) {
+            break Delivery::Acknowledged { retransmissions };
+        }
+        if retransmissions >= config.confirmed_retransmissions
+            || session.mac_state.restart.is_some()
+            || session.health.desynchronized().is_some()
+        {
+            break Delivery::Failed;
+        }
+        retransmissions += 1;
+        let delay = backoff(
+            config.confirmed_backoff_base,
+            config.confirmed_backoff_max,
+            retransmissions,
+        );
+        println!(
+            "Uplink #{:?} not acknowledged, retransmitting in {:?} ms",
+            fcnt, delay
+        );
+        thread::sleep(time::Duration::from_millis(delay));
+    };
+    match delivery {
+        Delivery::Unconfirmed => (),
+        Delivery::Acknowledged { retransmissions } => println!(
+            "Uplink #{:?} acknowledged after {:?} retransmissions",
+            fcnt, retransmissions
+        ),
+        Delivery::Failed => println!("Uplink #{:?} was not acknowledged, giving up", fcnt),
+    }
+    (delivery, answered)
+}
+
+/// Sends a DHR request, or retransmits the pending one, and handles the answer of the AS. Returns
+/// whether the AS answered.
+///
+/// # Arguments
+///
+/// * `session` - Our ratchet session, which takes the DH step
+/// * `config` - Our config, which the MAC commands of the AS may change
+fn request_dhr(
+    lora: &mut LoRa<Spi, OutputPin, OutputPin>,
+    gate: &mut TxGate,
+    session: &mut Session,
+    config: &mut Config,
+) -> bool {
+    let dhr_req = session.dhr_policy.request(&mut session.ed_ratchet, config);
+    match transmit_uplink(lora, gate, dhr_req, *config) {
+        Ok(uplink) => {
+            session.mac_state.dhr_requested = false;
+            println!("Sent packet with size: {:?}", uplink.size);
+            match recieve_window(lora, *config, &uplink) {
+                None => {
+                    session.health.dhr_missing();
+                    session.dhr_policy.missing(config);
+                    adr::link_failed(lora, config);
+                    false
+                }
+                Some(downlink) => {
+                    if handle_downlink(lora, config, session, downlink.buffer) {
+                        session.health.dhr_answered();
+                        session.dhr_policy.answered();
+                        true
+                    } else {
+                        session.dhr_policy.missing(config);
+                        false
                     }
-                    //lora = res.lora;
                 }
-                Err(er) => println!("Error {:?}, {:?}", n, er),
             }
-            //println!("AFTER: fcnt_up {:?} dh_id {:?}", ed_ratchet.fcnt_up, ed_ratchet.dh_id);
         }
+        Err(er) => {
+            println!("Error {:?}", er);
+            false
+        }
+    }
+}
 
-        /*unsafe {
-            println!("Message sent: {:?}", MESSAGENUMBER);
-        }*/
-        let lora_set_mode = lora.set_mode(RadioMode::Sleep);
-        match lora_set_mode {
-            Ok(_) => println!("Set to sleep mode success"),
-            Err(_) => println!("Set to sleep mode failed"),
+/// Our telemetry, encoded with the schema of the telemetry port. Without a schema we send random
+/// bytes.
+///
+/// # Arguments
+///
+/// * `schemas` - The schema of each port
+fn telemetry(schemas: &HashMap<u8, Schema>) -> Option<Vec<u8>> {
+    let schema = match schemas.get(&TELEMETRY_PORT) {
+        Some(schema) => schema,
+        None => return Some(rand::random::<[u8; 8]>().to_vec()),
+    };
+    let telemetry = json!({
+        "temperature": temperature(),
+        "battery": battery(),
+        "uptime": uptime(),
+    });
+    match codec::encode(schema, &telemetry) {
+        Ok(data) => Some(data),
+        Err(x) => {
+            println!(
+                "Unable to encode {} with the telemetry schema: {}",
+                telemetry, x
+            );
+            None
         }
-        thread::sleep(time::Duration::from_millis(10000));
     }
-}
\ No newline at end of file
+}
+
+/// The version of a configuration an application sent us, its `version` field when the
+/// configuration port has a schema, otherwise its first byte.
+///
+/// # Arguments
+///
+/// * `schemas` - The schema of each port
+/// * `configuration` - The data of the downlink on the configuration port
+fn config_version(schemas: &HashMap<u8, Schema>, configuration: &[u8]) -> Option<u8> {
+    let schema = match schemas.get(&CONFIG_PORT) {
+        Some(schema) => schema,
+        None => return configuration.first().copied(),
+    };
+    match codec::decode(schema, configuration) {
+        Ok(object) => {
+            println!("Configuration {}", object);
+            object
+                .get("version")
+                .and_then(|version| version.as_u64())
+                .map(|version| version as u8)
+        }
+        Err(x) => {
+            println!("Unable to decode the configuration: {}", x);
+            None
+        }
+    }
+}
+
+/// Puts the radio to sleep, and waits until the next uplink.
+fn sleep(lora: &mut LoRa<Spi, OutputPin, OutputPin>, duration: time::Duration) {
+    let lora_set_mode = lora.set_mode(RadioMode::Sleep);
+    match lora_set_mode {
+        Ok(_) => println!("Set to sleep mode success"),
+        Err(_) => println!("Set to sleep mode failed"),
+    }
+    thread::sleep(duration);
+}
+
+/// Sends our answers to the MAC commands of the AS right away, without listening for a downlink,
+/// before we restart.
+fn acknowledge(
+    lora: &mut LoRa<Spi, OutputPin, OutputPin>,
+    gate: &mut TxGate,
+    session: &mut Session,
+    config: &Config,
+) {
+    let uplink = session
+        .ed_ratchet
+        .ratchet_encrypt_payload(&mac::encode(&session.mac_state.answers), &session.devaddr);
+    if let Err(x) = transmit_uplink(lora, gate, uplink, *config) {
+        println!("Error acknowledging {}", x);
+    }
+}
+
+/// Handles a downlink from the AS, and records the outcome in the session health. Returns whether
+/// the downlink was decrypted. MAC commands in the downlink are applied, and their answers added to
+/// the MAC state of the session.
+fn handle_downlink(
+    lora: &mut LoRa<Spi, OutputPin, OutputPin>,
+    config: &mut Config,
+    session: &mut Session,
+    incoming: Vec<u8>,
+) -> bool {
+    let Session {
+        ed_ratchet,
+        devaddr,
+        health,
+        mac_state,
+        ..
+    } = session;
+    if is_unknown_session(&incoming, devaddr) {
+        println!("The AS has no ratchet on our devaddr");
+        health.unknown_session();
+        return false;
+    }
+    match ed_ratchet.receive(incoming) {
+        Ok(x) => {
+            if let Some(y) = x {
+                match port::decode(&y) {
+                    Ok(payload) if payload.port == MAC_PORT => match mac::decode(payload.data) {
+                        Ok(commands) => {
+                            for command in commands {
+                                apply_mac_command(lora, config, ed_ratchet, command, mac_state);
+                            }
+                        }
+                        Err(x) => println!("{}", x),
+                    },
+                    // We acknowledge the version of the configuration with our next uplink
+                    Ok(payload) if payload.port == CONFIG_PORT => {
+                        println!("Recieved configuration {:?}", payload.data);
+                        mac_state.configuration = Some(payload.data.to_vec());
+                    }
+                    Ok(payload) => println!(
+                        "Recieved {:?} on the {} port",
+                        payload.data,
+                        port::name(payload.port)
+                    ),
+                    Err(x) => println!("{}", x),
+                }
+            }
+            health.decrypted();
+            true
+        }
+        Err(x) => {
+            println!("{:?}", x);
+            health.decrypt_failed();
+            false
+        }
+    }
+}
+
+/// Applies a MAC command from the AS, and adds our answer to `mac_state`.
+fn apply_mac_command(
+    lora: &mut LoRa<Spi, OutputPin, OutputPin>,
+    config: &mut Config,
+    ed_ratchet: &EDRatchet<OsRng>,
+    command: MacCommand,
+    mac_state: &mut MacState,
+) {
+    println!("Recieved MAC command {:?}", command);
+    let cid = command.cid();
+    let accepted = match command {
+        MacCommand::SetUplinkInterval { seconds } => {
+            if seconds > 0 {
+                config.uplink_interval = seconds as u64 * 1000;
+            }
+            seconds > 0
+        }
+        MacCommand::SetDhrThreshold { dhr_const } => {
+            if dhr_const > 0 {
+                config.dhr_const = dhr_const;
+            }
+            dhr_const > 0
+        }
+        MacCommand::LinkAdrReq {
+            spreading_factor,
+            tx_power,
+        } => match adr::set_link(lora, config, spreading_factor, tx_power) {
+            Ok(()) => true,
+            Err(x) => {
+                println!("Refused the data rate of the AS: {}", x);
+                false
+            }
+        },
+        MacCommand::StatusReq => {
+            mac_state
+                .answers
+                .push(MacCommand::StatusAns(device_status(ed_ratchet)));
+            return;
+        }
+        MacCommand::Rejoin => {
+            mac_state.restart = Some(Restart::Rejoin);
+            true
+        }
+        MacCommand::Reboot => {
+            mac_state.restart = Some(Restart::Reboot);
+            true
+        }
+        MacCommand::DhrReq => {
+            mac_state.dhr_requested = true;
+            true
+        }
+        MacCommand::UplinkAck { fcnt } => {
+            mac_state.acknowledged = Some(fcnt);
+            return;
+        }
+        MacCommand::LinkCheckAns { margin } => {
+            println!("The AS hears us with a margin of {:?} dB", margin);
+            return;
+        }
+        _ => false,
+    };
+    mac_state.answers.push(MacCommand::Ack { cid, accepted });
+}
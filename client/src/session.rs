@@ -0,0 +1,72 @@
+use crate::filehandling::Config;
+
+/// Keeps track of whether our ratchet session with the AS still works. When it does not, we drop
+/// the `EDRatchet` and join again.
+pub struct SessionHealth {
+    decrypt_failures: u32,
+    missing_dhr_answers: u32,
+    unknown_session_notices: u32,
+    max_decrypt_failures: u32,
+    max_missing_dhr_answers: u32,
+}
+
+impl SessionHealth {
+    pub fn new(config: Config) -> Self {
+        SessionHealth {
+            decrypt_failures: 0,
+            missing_dhr_answers: 0,
+            unknown_session_notices: 0,
+            max_decrypt_failures: config.max_decrypt_failures,
+            max_missing_dhr_answers: config.max_missing_dhr_answers,
+        }
+    }
+
+    /// A downlink was decrypted.
+    pub fn decrypted(&mut self) {
+        self.decrypt_failures = 0;
+        self.unknown_session_notices = 0;
+    }
+
+    /// A downlink could not be decrypted.
+    pub fn decrypt_failed(&mut self) {
+        self.decrypt_failures += 1;
+    }
+
+    /// The AS answered our DHR request.
+    pub fn dhr_answered(&mut self) {
+        self.missing_dhr_answers = 0;
+    }
+
+    /// The AS did not answer our DHR request.
+    pub fn dhr_missing(&mut self) {
+        self.missing_dhr_answers += 1;
+    }
+
+    /// The AS told us it has no ratchet on our devaddr. Anyone can send this notice, so we only
+    /// believe it once `max_decrypt_failures` of them arrived without a downlink we could decrypt.
+    pub fn unknown_session(&mut self) {
+        self.unknown_session_notices += 1;
+    }
+
+    /// Returns why the session is desynchronized, or `None` while it still works.
+    pub fn desynchronized(&self) -> Option<String> {
+        if self.unknown_session_notices >= self.max_decrypt_failures {
+            Some(format!(
+                "the AS told us {:?} times in a row it has no ratchet on our devaddr",
+                self.unknown_session_notices
+            ))
+        } else if self.decrypt_failures >= self.max_decrypt_failures {
+            Some(format!(
+                "{:?} downlinks in a row could not be decrypted",
+                self.decrypt_failures
+            ))
+        } else if self.missing_dhr_answers >= self.max_missing_dhr_answers {
+            Some(format!(
+                "{:?} DHR requests in a row were not answered",
+                self.missing_dhr_answers
+            ))
+        } else {
+            None
+        }
+    }
+}
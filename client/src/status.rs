@@ -0,0 +1,73 @@
+use std::fs;
+
+use rand_core::OsRng;
+
+use twoRatchet::ED::EDRatchet;
+
+use crate::mac::DeviceStatus;
+
+/// Where Linux lists the power supplies of the device.
+const POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+
+/// Below this battery level, about 10%, we send an alarm.
+pub const LOW_BATTERY: u8 = 26;
+
+/// The battery level, 0 when there is no battery and we run on external power, otherwise from 1
+/// (empty) to 254 (full).
+pub fn battery() -> u8 {
+    let supplies = match fs::read_dir(POWER_SUPPLY_PATH) {
+        Ok(supplies) => supplies,
+        Err(_) => return 0,
+    };
+    for supply in supplies.flatten() {
+        let path = supply.path();
+        let is_battery = fs::read_to_string(path.join("type"))
+            .map(|x| x.trim() == "Battery")
+            .unwrap_or(false);
+        if !is_battery {
+            continue;
+        }
+        if let Ok(capacity) = fs::read_to_string(path.join("capacity")) {
+            if let Ok(percent) = capacity.trim().parse::<u32>() {
+                return (1 + percent.min(100) * 253 / 100) as u8;
+            }
+        }
+    }
+    0
+}
+
+/// Where Linux reports the temperature of the CPU, in millidegrees Celsius.
+const TEMPERATURE_PATH: &str = "/sys/class/thermal/thermal_zone0/temp";
+
+/// The temperature of the CPU in degrees Celsius, 0 if the device does not report it.
+pub fn temperature() -> f64 {
+    fs::read_to_string(TEMPERATURE_PATH)
+        .ok()
+        .and_then(|x| x.trim().parse::<f64>().ok())
+        .map(|x| x / 1000.0)
+        .unwrap_or(0.0)
+}
+
+/// The uptime of the device in seconds.
+pub fn uptime() -> u32 {
+    fs::read_to_string("/proc/uptime")
+        .ok()
+        .and_then(|x| x.split_whitespace().next()?.parse::<f64>().ok())
+        .map(|x| x as u32)
+        .unwrap_or(0)
+}
+
+/// Our status, which the AS can ask for with a `StatusReq`.
+///
+/// # Arguments
+///
+/// * `ed_ratchet` - The ratchet of our session, holding its counters
+pub fn device_status(ed_ratchet: &EDRatchet<OsRng>) -> DeviceStatus {
+    DeviceStatus {
+        battery: battery(),
+        uptime: uptime(),
+        fcnt_up: ed_ratchet.fcnt_up,
+        fcnt_down: ed_ratchet.fcnt_down,
+        dh_id: ed_ratchet.dh_id as u32,
+    }
+}
@@ -0,0 +1,202 @@
+use std::time::Duration;
+
+use crate::radio::RadioProfile;
+
+/// Above this symbol time the sx1276 has to use low data rate optimisation.
+const LOW_DATA_RATE_SYMBOL_TIME: f64 = 0.016;
+
+/// The largest frame the sx1276 can send, in bytes.
+pub const MAX_FRAME_LENGTH: usize = 255;
+
+/// The noise figure of the sx1276 receiver in dB.
+const NOISE_FIGURE: f64 = 6.0;
+
+/// The modulation settings deciding how long a frame is on air.
+#[derive(Debug, Clone, Copy)]
+pub struct Modulation {
+    pub spreading_factor: u8,
+    /// The signal bandwidth in Hz
+    pub bandwidth: i64,
+    /// The denominator of the coding rate 4/x
+    pub coding_rate: u8,
+    /// The preamble length in symbols
+    pub preamble_length: i64,
+    pub implicit_header: bool,
+    pub crc: bool,
+}
+
+impl Modulation {
+    /// The modulation of a radio profile.
+    pub fn from_profile(profile: &RadioProfile) -> Self {
+        Modulation {
+            spreading_factor: profile.spreading_factor,
+            bandwidth: profile.bandwidth,
+            coding_rate: profile.coding_rate,
+            preamble_length: profile.preamble_length,
+            implicit_header: profile.implicit_header,
+            crc: profile.crc,
+        }
+    }
+
+    /// The same modulation with another spreading factor and bandwidth, like the receive windows use.
+    pub fn with_data_rate(self, spreading_factor: u8, bandwidth: i64) -> Self {
+        Modulation {
+            spreading_factor,
+            bandwidth,
+            ..self
+        }
+    }
+
+    /// The duration of a single symbol in seconds.
+    pub fn symbol_time(&self) -> f64 {
+        (1u64 << self.spreading_factor) as f64 / self.bandwidth as f64
+    }
+
+    pub fn low_data_rate_optimize(&self) -> bool {
+        self.symbol_time() > LOW_DATA_RATE_SYMBOL_TIME
+    }
+
+    /// How long a frame of `payload_length` bytes is on air, following the sx1276 datasheet.
+    pub fn time_on_air(&self, payload_length: usize) -> Duration {
+        let symbol_time = self.symbol_time();
+        let preamble = (self.preamble_length as f64 + 4.25) * symbol_time;
+        let spreading_factor = self.spreading_factor as f64;
+        let bits = 8.0 * payload_length as f64 - 4.0 * spreading_factor
+            + 28.0
+            + if self.crc { 16.0 } else { 0.0 }
+            - if self.implicit_header { 20.0 } else { 0.0 };
+        let bits_per_symbol = 4.0
+            * (spreading_factor
+                - if self.low_data_rate_optimize() {
+                    2.0
+                } else {
+                    0.0
+                });
+        let symbols = 8.0 + ((bits / bits_per_symbol).ceil() * self.coding_rate as f64).max(0.0);
+        Duration::from_secs_f64(preamble + symbols * symbol_time)
+    }
+    /// The largest payload which is on air for at most `max_airtime`, `None` if not even an empty
+    /// frame fits.
+    pub fn max_payload(&self, max_airtime: Duration) -> Option<usize> {
+        (0..=MAX_FRAME_LENGTH)
+            .rev()
+            .find(|payload_length| self.time_on_air(*payload_length) <= max_airtime)
+    }
+
+    /// The SNR in dB the sx1276 needs to demodulate a frame at our spreading factor.
+    pub fn required_snr(&self) -> f64 {
+        required_snr(self.spreading_factor)
+    }
+
+    /// The weakest signal in dBm the sx1276 can still receive with this modulation.
+    pub fn sensitivity(&self) -> f64 {
+        -174.0 + 10.0 * (self.bandwidth as f64).log10() + NOISE_FIGURE + self.required_snr()
+    }
+
+    /// The largest path loss in dB a frame sent with `tx_power` dBm survives, without antenna gains.
+    pub fn link_budget(&self, tx_power: i32) -> f64 {
+        tx_power as f64 - self.sensitivity()
+    }
+}
+
+/// The SNR in dB the sx1276 needs to demodulate a frame at `spreading_factor`.
+pub fn required_snr(spreading_factor: u8) -> f64 {
+    match spreading_factor {
+        6 => -5.0,
+        7 => -7.5,
+        8 => -10.0,
+        9 => -12.5,
+        10 => -15.0,
+        11 => -17.5,
+        _ => -20.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The LoRaWAN defaults: coding rate 4/5, an 8 symbol preamble, explicit header and CRC.
+    fn modulation(spreading_factor: u8, bandwidth: i64) -> Modulation {
+        Modulation {
+            spreading_factor,
+            bandwidth,
+            coding_rate: 5,
+            preamble_length: 8,
+            implicit_header: false,
+            crc: true,
+        }
+    }
+
+    fn millis(duration: Duration) -> f64 {
+        duration.as_secs_f64() * 1000.0
+    }
+
+    fn assert_airtime(modulation: Modulation, payload_length: usize, expected: f64) {
+        let airtime = millis(modulation.time_on_air(payload_length));
+        assert!(
+            (airtime - expected).abs() < 0.001,
+            "{:?} with {:?} bytes is {:?} ms on air, not {:?} ms",
+            modulation,
+            payload_length,
+            airtime,
+            expected
+        );
+    }
+
+    #[test]
+    fn matches_the_sx1276_calculator() {
+        assert_airtime(modulation(7, 125000), 20, 56.576);
+        assert_airtime(modulation(7, 125000), 64, 118.016);
+        assert_airtime(modulation(7, 250000), 64, 59.008);
+        assert_airtime(modulation(9, 125000), 64, 390.144);
+        assert_airtime(modulation(12, 125000), 20, 1318.912);
+        assert_airtime(modulation(12, 125000), 64, 2793.472);
+    }
+
+    #[test]
+    fn header_crc_and_coding_rate() {
+        let implicit = Modulation {
+            implicit_header: true,
+            crc: false,
+            ..modulation(7, 125000)
+        };
+        assert_airtime(implicit, 20, 46.336);
+        let robust = Modulation {
+            coding_rate: 8,
+            ..modulation(7, 125000)
+        };
+        assert_airtime(robust, 20, 78.080);
+    }
+
+    #[test]
+    fn low_data_rate_optimization_above_16_ms_symbols() {
+        assert!(!modulation(10, 125000).low_data_rate_optimize());
+        assert!(modulation(11, 125000).low_data_rate_optimize());
+        assert!(modulation(12, 125000).low_data_rate_optimize());
+        assert!(!modulation(12, 500000).low_data_rate_optimize());
+    }
+
+    #[test]
+    fn max_payload_fits_the_dwell_time() {
+        let dwell_time = Duration::from_millis(400);
+        let modulation = modulation(10, 125000);
+        let max = modulation.max_payload(dwell_time).unwrap();
+        assert!(modulation.time_on_air(max) <= dwell_time);
+        assert!(modulation.time_on_air(max + 1) > dwell_time);
+        assert_eq!(modulation.max_payload(Duration::from_millis(1)), None);
+        assert_eq!(
+            modulation.max_payload(Duration::from_secs(10)),
+            Some(MAX_FRAME_LENGTH)
+        );
+    }
+
+    #[test]
+    fn sensitivity_and_link_budget() {
+        let modulation = modulation(7, 125000);
+        let sensitivity = -174.0 + 10.0 * 125000f64.log10() + NOISE_FIGURE - 7.5;
+        assert!((modulation.sensitivity() - sensitivity).abs() < 1e-9);
+        assert!((modulation.link_budget(14) - (14.0 - sensitivity)).abs() < 1e-9);
+        assert_eq!(required_snr(12), -20.0);
+    }
+}
@@ -0,0 +1,239 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// How the application data on a port is encoded, defined in a config file so a new sensor type
+/// only needs a new schema.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "format")]
+pub enum Schema {
+    /// Any JSON object, encoded as CBOR
+    Cbor,
+    /// Numbers packed into as few bits as they need, most significant bit first, in the order of
+    /// `fields`
+    BitPacked { fields: Vec<Field> },
+}
+
+/// A number in a bit-packed schema. The value sent is `(value - offset) / scale`, rounded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Field {
+    pub name: String,
+    /// How many bits the number takes, from 1 to 32
+    pub bits: u8,
+    /// Whether the number is sent in two's complement
+    #[serde(default)]
+    pub signed: bool,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub offset: f64,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+impl Field {
+    /// The smallest and largest number the field can hold, before scaling.
+    fn range(&self) -> Result<(i64, i64), String> {
+        if self.bits == 0 || self.bits > 32 {
+            return Err(format!(
+                "Field {} has {:?} bits, it needs 1 to 32",
+                self.name, self.bits
+            ));
+        }
+        Ok(if self.signed {
+            (-(1 << (self.bits - 1)), (1 << (self.bits - 1)) - 1)
+        } else {
+            (0, (1 << self.bits) - 1)
+        })
+    }
+}
+
+/// Encodes a JSON object following a schema.
+///
+/// # Arguments
+///
+/// * `schema` - The schema of the port the data is sent on
+/// * `value` - The object to encode, a bit-packed schema needs a number for each of its fields
+pub fn encode(schema: &Schema, value: &Value) -> Result<Vec<u8>, String> {
+    match schema {
+        Schema::Cbor => {
+            let mut buffer = Vec::new();
+            ciborium::ser::into_writer(value, &mut buffer).map_err(|x| format!("{:?}", x))?;
+            Ok(buffer)
+        }
+        Schema::BitPacked { fields } => {
+            let mut buffer = Vec::new();
+            let mut used = 0;
+            for field in fields {
+                let (min, max) = field.range()?;
+                let number = value
+                    .get(&field.name)
+                    .and_then(Value::as_f64)
+                    .ok_or(format!("Field {} is missing or not a number", field.name))?;
+                let raw = ((number - field.offset) / field.scale).round();
+                if !(min as f64..=max as f64).contains(&raw) {
+                    return Err(format!(
+                        "{:?} does not fit in field {} of {:?} bits",
+                        number, field.name, field.bits
+                    ));
+                }
+                let raw = raw as i64 as u64;
+                for bit in (0..field.bits).rev() {
+                    if used % 8 == 0 {
+                        buffer.push(0);
+                    }
+                    if raw >> bit & 1 == 1 {
+                        *buffer.last_mut().unwrap() |= 0x80 >> (used % 8);
+                    }
+                    used += 1;
+                }
+            }
+            Ok(buffer)
+        }
+    }
+}
+
+/// Decodes application data into a JSON object following a schema.
+///
+/// # Arguments
+///
+/// * `schema` - The schema of the port the data was sent on
+/// * `data` - The application data, without the port
+pub fn decode(schema: &Schema, data: &[u8]) -> Result<Value, String> {
+    match schema {
+        Schema::Cbor => ciborium::de::from_reader(data).map_err(|x| format!("{:?}", x)),
+        Schema::BitPacked { fields } => {
+            let mut object = Map::new();
+            let mut used = 0;
+            for field in fields {
+                field.range()?;
+                if used + field.bits as usize > data.len() * 8 {
+                    return Err(format!(
+                        "{:?} bytes are too short for field {}",
+                        data.len(),
+                        field.name
+                    ));
+                }
+                let mut raw: u64 = 0;
+                for _ in 0..field.bits {
+                    let bit = data[used / 8] >> (7 - used % 8) & 1;
+                    raw = raw << 1 | bit as u64;
+                    used += 1;
+                }
+                let raw = if field.signed && raw >> (field.bits - 1) & 1 == 1 {
+                    raw as i64 - (1 << field.bits)
+                } else {
+                    raw as i64
+                };
+                let number = if field.scale == 1.0 && field.offset == 0.0 {
+                    Value::from(raw)
+                } else {
+                    Value::from(raw as f64 * field.scale + field.offset)
+                };
+                object.insert(field.name.clone(), number);
+            }
+            Ok(Value::Object(object))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn field(name: &str, bits: u8, signed: bool) -> Field {
+        Field {
+            name: name.to_string(),
+            bits,
+            signed,
+            scale: 1.0,
+            offset: 0.0,
+        }
+    }
+
+    fn packed(fields: Vec<Field>) -> Schema {
+        Schema::BitPacked { fields }
+    }
+
+    #[test]
+    fn packs_fields_across_bytes() {
+        let schema = packed(vec![
+            field("a", 3, false),
+            field("b", 7, false),
+            field("c", 6, false),
+        ]);
+        let value = json!({"a": 5, "b": 0x41, "c": 0x3f});
+        let data = encode(&schema, &value).unwrap();
+        assert_eq!(data, vec![0b1011_0000, 0b0111_1111]);
+        assert_eq!(decode(&schema, &data).unwrap(), value);
+    }
+
+    #[test]
+    fn round_trips_signed_fields() {
+        let schema = packed(vec![field("x", 4, true)]);
+        for (number, byte) in [(-8, 0x80), (-1, 0xf0), (0, 0x00), (7, 0x70)] {
+            let value = json!({ "x": number });
+            let data = encode(&schema, &value).unwrap();
+            assert_eq!(data, vec![byte]);
+            assert_eq!(decode(&schema, &data).unwrap(), value);
+        }
+        assert!(encode(&schema, &json!({"x": 8})).is_err());
+        assert!(encode(&schema, &json!({"x": -9})).is_err());
+    }
+
+    #[test]
+    fn round_trips_32_bit_fields() {
+        let schema = packed(vec![field("u", 32, false), field("s", 32, true)]);
+        for (u, s) in [
+            (0, 0),
+            (u32::MAX as i64, i32::MIN as i64),
+            (1, i32::MAX as i64),
+        ] {
+            let value = json!({"u": u, "s": s});
+            let data = encode(&schema, &value).unwrap();
+            assert_eq!(data.len(), 8);
+            assert_eq!(decode(&schema, &data).unwrap(), value);
+        }
+        assert!(encode(&schema, &json!({"u": -1, "s": 0})).is_err());
+        assert!(encode(&schema, &json!({"u": u32::MAX as i64 + 1, "s": 0})).is_err());
+    }
+
+    #[test]
+    fn applies_scale_and_offset() {
+        let schema = packed(vec![Field {
+            scale: 0.5,
+            offset: -40.0,
+            ..field("temperature", 8, false)
+        }]);
+        let data = encode(&schema, &json!({"temperature": 21.5})).unwrap();
+        assert_eq!(data, vec![123]);
+        assert_eq!(
+            decode(&schema, &data).unwrap(),
+            json!({"temperature": 21.5})
+        );
+        // Rounded to the nearest step of the scale
+        assert_eq!(
+            encode(&schema, &json!({"temperature": 21.6})).unwrap(),
+            vec![123]
+        );
+        assert!(encode(&schema, &json!({"temperature": -40.5})).is_err());
+        assert!(encode(&schema, &json!({"temperature": 88.0})).is_err());
+    }
+
+    #[test]
+    fn refuses_invalid_fields_and_short_data() {
+        assert!(encode(&packed(vec![field("x", 0, false)]), &json!({"x": 0})).is_err());
+        assert!(encode(&packed(vec![field("x", 33, false)]), &json!({"x": 0})).is_err());
+        assert!(encode(&packed(vec![field("x", 8, false)]), &json!({"y": 0})).is_err());
+        assert!(decode(&packed(vec![field("x", 9, false)]), &[0xff]).is_err());
+    }
+
+    #[test]
+    fn round_trips_cbor() {
+        let value = json!({"version": 10, "name": "sensor", "levels": [1, 2.5]});
+        let data = encode(&Schema::Cbor, &value).unwrap();
+        assert_eq!(decode(&Schema::Cbor, &data).unwrap(), value);
+    }
+}
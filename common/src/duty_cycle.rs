@@ -0,0 +1,250 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::region::{Region, SubBand};
+
+/// The period a duty cycle is averaged over.
+pub const DUTY_CYCLE_PERIOD: Duration = Duration::from_secs(3600);
+
+/// Why a transmission is not allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GateError {
+    /// The frame is on air longer than the region allows for a single transmission.
+    DwellTime { airtime: Duration, max: Duration },
+    /// The sub-band has no budget left, it will have enough again after `wait`.
+    DutyCycle { wait: Duration },
+    /// The transmissions need more airtime than the sub-band allows in a whole duty-cycle period.
+    OverBudget { airtime: Duration, budget: Duration },
+}
+
+/// The airtime budget of a sub-band over the last hour.
+#[derive(Debug, Clone, Copy)]
+pub struct Budget {
+    pub sub_band: SubBand,
+    pub used: Duration,
+    pub remaining: Duration,
+}
+
+/// Keeps every transmission within the duty cycle of its sub-band and the dwell time of the region.
+/// Frequencies outside every sub-band are not limited.
+pub struct TxGate {
+    sub_bands: Vec<SubBand>,
+    max_dwell_time: Option<Duration>,
+    /// The transmissions of the last hour in each sub-band, oldest first.
+    history: Vec<VecDeque<(Instant, Duration)>>,
+}
+
+impl TxGate {
+    /// Creates a gate with the given limits.
+    ///
+    /// # Arguments
+    ///
+    /// * `sub_bands` - The sub-bands with a duty cycle
+    /// * `max_dwell_time` - The longest a single transmission may be on air
+    pub fn new(sub_bands: Vec<SubBand>, max_dwell_time: Option<Duration>) -> Self {
+        TxGate {
+            history: vec![VecDeque::new(); sub_bands.len()],
+            sub_bands,
+            max_dwell_time,
+        }
+    }
+
+    /// The gate for uplinks in a region, without a region nothing is limited.
+    pub fn uplink(region: Option<Region>) -> Self {
+        match region.map(Region::channel_plan) {
+            Some(plan) => TxGate::new(plan.sub_bands, plan.uplink_dwell_time),
+            None => TxGate::new(Vec::new(), None),
+        }
+    }
+
+    /// The gate for downlinks in a region, without a region nothing is limited.
+    pub fn downlink(region: Option<Region>) -> Self {
+        match region.map(Region::channel_plan) {
+            Some(plan) => TxGate::new(plan.sub_bands, None),
+            None => TxGate::new(Vec::new(), None),
+        }
+    }
+
+    fn sub_band(&self, frequency: i64) -> Option<usize> {
+        self.sub_bands
+            .iter()
+            .position(|sub_band| sub_band.contains(frequency))
+    }
+
+    /// Forgets the transmissions which fell out of the duty-cycle period.
+    fn expire(&mut self, now: Instant) {
+        for history in self.history.iter_mut() {
+            while let Some((sent, _)) = history.front() {
+                if now.duration_since(*sent) < DUTY_CYCLE_PERIOD {
+                    break;
+                }
+                history.pop_front();
+            }
+        }
+    }
+
+    /// Checks if a transmission, or a burst of them like the fragments of a frame, may be sent now.
+    /// Every transmission must fit the dwell time, and all of them together the duty cycle.
+    /// Only [`GateError::DutyCycle`] goes away by waiting.
+    ///
+    /// # Arguments
+    ///
+    /// * `frequency` - The frequency in Hz the transmissions are sent on
+    /// * `airtimes` - How long each transmission is on air
+    pub fn check(&mut self, frequency: i64, airtimes: &[Duration]) -> Result<(), GateError> {
+        if let Some(max) = self.max_dwell_time {
+            if let Some(airtime) = airtimes.iter().find(|airtime| **airtime > max) {
+                return Err(GateError::DwellTime {
+                    airtime: *airtime,
+                    max,
+                });
+            }
+        }
+        let airtime: Duration = airtimes.iter().sum();
+        let index = match self.sub_band(frequency) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        self.expire(now);
+        let budget = self.sub_bands[index].budget();
+        if airtime > budget {
+            return Err(GateError::OverBudget { airtime, budget });
+        }
+        let mut used: Duration = self.history[index]
+            .iter()
+            .map(|(_, airtime)| *airtime)
+            .sum();
+        if used + airtime <= budget {
+            return Ok(());
+        }
+        // Wait until enough of the oldest transmissions fell out of the period
+        let mut wait = DUTY_CYCLE_PERIOD;
+        for (sent, sent_airtime) in self.history[index].iter() {
+            used = used.saturating_sub(*sent_airtime);
+            wait = (*sent + DUTY_CYCLE_PERIOD).saturating_duration_since(now);
+            if used + airtime <= budget {
+                break;
+            }
+        }
+        Err(GateError::DutyCycle { wait })
+    }
+
+    /// Records a transmission which was sent, it counts against the budget of its sub-band.
+    ///
+    /// # Arguments
+    ///
+    /// * `frequency` - The frequency in Hz the transmission was sent on
+    /// * `airtime` - How long the transmission was on air
+    pub fn record(&mut self, frequency: i64, airtime: Duration) {
+        if let Some(index) = self.sub_band(frequency) {
+            self.history[index].push_back((Instant::now(), airtime));
+        }
+    }
+
+    /// The budget of the sub-band a frequency is in, `None` if the frequency is not limited.
+    pub fn budget(&mut self, frequency: i64) -> Option<Budget> {
+        let index = self.sub_band(frequency)?;
+        self.budgets().into_iter().nth(index)
+    }
+
+    /// The budget of every sub-band.
+    pub fn budgets(&mut self) -> Vec<Budget> {
+        self.expire(Instant::now());
+        self.sub_bands
+            .iter()
+            .zip(self.history.iter())
+            .map(|(sub_band, history)| {
+                let used: Duration = history.iter().map(|(_, airtime)| *airtime).sum();
+                Budget {
+                    sub_band: *sub_band,
+                    used,
+                    remaining: sub_band.budget().saturating_sub(used),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FREQUENCY: i64 = 868_100_000;
+
+    /// A gate with one 1% sub-band, a budget of 36 s an hour.
+    fn gate(max_dwell_time: Option<Duration>) -> TxGate {
+        TxGate::new(
+            vec![SubBand {
+                low: 868_000_000,
+                high: 868_600_000,
+                duty_cycle: 0.01,
+            }],
+            max_dwell_time,
+        )
+    }
+
+    #[test]
+    fn records_airtime_against_the_budget() {
+        let mut gate = gate(None);
+        let airtime = Duration::from_secs(10);
+        for used in 1..=3 {
+            assert_eq!(gate.check(FREQUENCY, &[airtime]), Ok(()));
+            gate.record(FREQUENCY, airtime);
+            let budget = gate.budget(FREQUENCY).unwrap();
+            assert_eq!(budget.used, airtime * used);
+            assert_eq!(budget.remaining, Duration::from_secs(36) - airtime * used);
+        }
+    }
+
+    #[test]
+    fn waits_for_the_oldest_transmissions_to_expire() {
+        let mut gate = gate(None);
+        gate.record(FREQUENCY, Duration::from_secs(20));
+        gate.record(FREQUENCY, Duration::from_secs(10));
+        assert_eq!(gate.check(FREQUENCY, &[Duration::from_secs(6)]), Ok(()));
+        match gate.check(FREQUENCY, &[Duration::from_secs(3), Duration::from_secs(4)]) {
+            Err(GateError::DutyCycle { wait }) => {
+                assert!(wait <= DUTY_CYCLE_PERIOD);
+                assert!(wait > DUTY_CYCLE_PERIOD - Duration::from_secs(60));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_more_than_the_whole_budget() {
+        let mut gate = gate(None);
+        assert_eq!(
+            gate.check(
+                FREQUENCY,
+                &[Duration::from_secs(20), Duration::from_secs(17)]
+            ),
+            Err(GateError::OverBudget {
+                airtime: Duration::from_secs(37),
+                budget: Duration::from_secs(36),
+            })
+        );
+    }
+
+    #[test]
+    fn refuses_transmissions_longer_than_the_dwell_time() {
+        let max = Duration::from_millis(400);
+        let mut gate = gate(Some(max));
+        assert_eq!(gate.check(FREQUENCY, &[max]), Ok(()));
+        let airtime = Duration::from_millis(401);
+        assert_eq!(
+            gate.check(FREQUENCY, &[max, airtime]),
+            Err(GateError::DwellTime { airtime, max })
+        );
+    }
+
+    #[test]
+    fn does_not_limit_frequencies_outside_the_sub_bands() {
+        let mut gate = gate(None);
+        gate.record(869_525_000, Duration::from_secs(3600));
+        assert_eq!(gate.check(869_525_000, &[Duration::from_secs(60)]), Ok(()));
+        assert!(gate.budget(869_525_000).is_none());
+        assert_eq!(gate.budget(FREQUENCY).unwrap().used, Duration::ZERO);
+    }
+}
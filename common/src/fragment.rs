@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// The mtype of a fragment of a frame too large to send at once.
+pub const FRAGMENT: u8 = 11;
+
+/// The header in front of every fragment: the mtype, a tag shared by the fragments of a frame, the
+/// index of the fragment, the amount of data fragments and the length of the whole frame.
+pub const FRAGMENT_HEADER: usize = 7;
+
+/// The most data fragments a frame is split into, so a receiver never waits for long bursts.
+pub const MAX_FRAGMENTS: usize = 16;
+
+/// How long we keep the fragments of a frame which is not complete yet.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a receiver waits for the next fragment on top of the time on air of the largest frame,
+/// in ms. Fragments are sent back to back, so a longer silence means the rest of the frame was lost.
+pub const FRAGMENT_GAP: i32 = 200;
+
+/// Checks if a frame is a fragment.
+pub fn is_fragment(frame: &[u8]) -> bool {
+    frame.first() == Some(&FRAGMENT)
+}
+
+/// The largest frame which can be split into fragments of at most `max_length` bytes.
+pub fn max_frame_length(max_length: usize) -> usize {
+    (max_length.saturating_sub(FRAGMENT_HEADER) * MAX_FRAGMENTS).min(u16::MAX as usize)
+}
+
+/// Splits a frame into fragments of at most `max_length` bytes, header included. With `fec` an
+/// extra parity fragment is added, the XOR of all data fragments, from which the receiver can
+/// recover any single lost fragment. Returns `None` if the frame is longer than `max_frame_length`.
+///
+/// # Arguments
+///
+/// * `frame` - The frame to split, e.g. an EDHOC message or ratchet frame
+/// * `max_length` - The largest frame the data rate allows
+/// * `fec` - Whether to add a parity fragment
+pub fn split(frame: &[u8], max_length: usize, fec: bool) -> Option<Vec<Vec<u8>>> {
+    if frame.is_empty() || frame.len() > max_frame_length(max_length) {
+        return None;
+    }
+    let count = frame.len().div_ceil(max_length - FRAGMENT_HEADER);
+    // All data fragments but the last have the same size, which the receiver derives from the header
+    let chunk = frame.len().div_ceil(count);
+    let tag: [u8; 2] = rand::random();
+    let header = |index: usize| {
+        let mut header = vec![FRAGMENT, tag[0], tag[1], index as u8, count as u8];
+        header.extend_from_slice(&(frame.len() as u16).to_be_bytes());
+        header
+    };
+    let mut parity = vec![0; chunk];
+    let mut fragments = Vec::new();
+    for (index, data) in frame.chunks(chunk).enumerate() {
+        for (parity, byte) in parity.iter_mut().zip(data) {
+            *parity ^= byte;
+        }
+        fragments.push([header(index), data.to_vec()].concat());
+    }
+    if fec {
+        fragments.push([header(count), parity].concat());
+    }
+    Some(fragments)
+}
+
+/// The fragments of a single frame we received so far.
+struct Partial {
+    length: usize,
+    fragments: Vec<Option<Vec<u8>>>,
+    parity: Option<Vec<u8>>,
+    started: Instant,
+    /// The frame was returned already, later fragments like the parity are ignored
+    done: bool,
+}
+
+impl Partial {
+    /// The frame, once every data fragment arrived or all but one and the parity did.
+    fn complete(&self) -> Option<Vec<u8>> {
+        let missing: Vec<usize> = (0..self.fragments.len())
+            .filter(|index| self.fragments[*index].is_none())
+            .collect();
+        match (missing.as_slice(), &self.parity) {
+            ([], _) => Some(self.fragments.iter().flatten().flatten().copied().collect()),
+            ([lost], Some(parity)) => {
+                let mut recovered = parity.clone();
+                for data in self.fragments.iter().flatten() {
+                    for (recovered, byte) in recovered.iter_mut().zip(data) {
+                        *recovered ^= byte;
+                    }
+                }
+                recovered.truncate(fragment_length(self.length, self.fragments.len(), *lost));
+                let mut fragments = self.fragments.clone();
+                fragments[*lost] = Some(recovered);
+                Some(fragments.into_iter().flatten().flatten().collect())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The length of the data in a fragment, the parity fragment has the length of the first.
+fn fragment_length(length: usize, count: usize, index: usize) -> usize {
+    let chunk = length.div_ceil(count);
+    if index == count {
+        chunk
+    } else {
+        chunk.min(length.saturating_sub(index * chunk))
+    }
+}
+
+/// Puts fragmented frames back together, based on their tag.
+pub struct Reassembler {
+    partials: HashMap<[u8; 2], Partial>,
+    timeout: Duration,
+}
+
+impl Reassembler {
+    /// Creates a reassembler without any fragments.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - How long the fragments of an incomplete frame are kept
+    pub fn new(timeout: Duration) -> Self {
+        Reassembler {
+            partials: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Whether we are waiting for the rest of a frame.
+    pub fn is_pending(&mut self) -> bool {
+        self.prune();
+        self.partials.values().any(|partial| !partial.done)
+    }
+
+    /// Adds a fragment, and returns the whole frame once enough of its fragments arrived.
+    ///
+    /// # Arguments
+    ///
+    /// * `fragment` - The fragment, a frame may be longer than its header says
+    pub fn add(&mut self, fragment: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.prune();
+        if fragment.len() < FRAGMENT_HEADER || !is_fragment(fragment) {
+            return Err(format!("Malformed fragment {:?}", fragment));
+        }
+        let tag = [fragment[1], fragment[2]];
+        let index = fragment[3] as usize;
+        let count = fragment[4] as usize;
+        let length = u16::from_be_bytes([fragment[5], fragment[6]]) as usize;
+        // The header comes off the air, so it is checked before we compute anything from it. Every
+        // data fragment carries data, and the index one past the last is the parity fragment
+        if count == 0
+            || count > MAX_FRAGMENTS
+            || index > count
+            || length == 0
+            || (count - 1) * length.div_ceil(count) >= length
+        {
+            return Err(format!("Malformed fragment {:?}", fragment));
+        }
+        let data_length = fragment_length(length, count, index);
+        if fragment.len() < FRAGMENT_HEADER + data_length {
+            return Err(format!(
+                "Fragment shorter than its header says {:?}",
+                fragment
+            ));
+        }
+        let data = fragment[FRAGMENT_HEADER..FRAGMENT_HEADER + data_length].to_vec();
+        let partial = self.partials.entry(tag).or_insert_with(|| Partial {
+            length,
+            fragments: vec![None; count],
+            parity: None,
+            started: Instant::now(),
+            done: false,
+        });
+        if partial.done {
+            return Ok(None);
+        }
+        if partial.length != length || partial.fragments.len() != count {
+            return Err(format!(
+                "Fragment does not match the earlier ones {:?}",
+                fragment
+            ));
+        }
+        if index == count {
+            partial.parity = Some(data);
+        } else {
+            partial.fragments[index] = Some(data);
+        }
+        let frame = partial.complete();
+        partial.done = frame.is_some();
+        Ok(frame)
+    }
+
+    fn prune(&mut self) {
+        let timeout = self.timeout;
+        self.partials
+            .retain(|_, partial| partial.started.elapsed() < timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(length: usize) -> Vec<u8> {
+        (0..length).map(|x| x as u8).collect()
+    }
+
+    #[test]
+    fn split_and_reassemble() {
+        let frame = frame(99);
+        let fragments = split(&frame, 40, false).unwrap();
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments.iter().all(|x| x.len() <= 40 && is_fragment(x)));
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT);
+        assert_eq!(reassembler.add(&fragments[2]), Ok(None));
+        assert!(reassembler.is_pending());
+        assert_eq!(reassembler.add(&fragments[0]), Ok(None));
+        assert_eq!(reassembler.add(&fragments[1]), Ok(Some(frame)));
+        assert!(!reassembler.is_pending());
+    }
+
+    #[test]
+    fn split_refuses_frames_too_large() {
+        assert_eq!(split(&[], 40, false), None);
+        assert_eq!(split(&frame(max_frame_length(40) + 1), 40, false), None);
+        assert!(split(&frame(max_frame_length(40)), 40, true).is_some());
+    }
+
+    #[test]
+    fn parity_recovers_any_lost_fragment() {
+        // With 101 bytes the last fragment is shorter than the parity
+        for length in [99, 101] {
+            let frame = frame(length);
+            let fragments = split(&frame, 40, true).unwrap();
+            for lost in 0..fragments.len() - 1 {
+                let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT);
+                let mut result = None;
+                for (index, fragment) in fragments.iter().enumerate() {
+                    if index != lost {
+                        result = reassembler.add(fragment).unwrap().or(result);
+                    }
+                }
+                assert_eq!(result, Some(frame.clone()));
+            }
+        }
+    }
+
+    #[test]
+    fn parity_after_the_frame_is_ignored() {
+        let fragments = split(&frame(60), 40, true).unwrap();
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT);
+        assert_eq!(reassembler.add(&fragments[0]), Ok(None));
+        assert_eq!(reassembler.add(&fragments[1]), Ok(Some(frame(60))));
+        assert_eq!(reassembler.add(&fragments[2]), Ok(None));
+    }
+
+    #[test]
+    fn refuses_zero_fragments() {
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT);
+        assert!(reassembler
+            .add(&[FRAGMENT, 1, 2, 0, 0, 0, 10, 1, 2])
+            .is_err());
+        assert!(reassembler
+            .add(&[FRAGMENT, 1, 2, 0, 17, 0, 10, 1, 2])
+            .is_err());
+    }
+
+    #[test]
+    fn refuses_index_past_the_parity() {
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT);
+        assert!(reassembler.add(&[FRAGMENT, 1, 2, 3, 2, 0, 2, 1]).is_err());
+        assert!(reassembler.add(&[FRAGMENT, 1, 2, 255, 2, 0, 2, 1]).is_err());
+    }
+
+    #[test]
+    fn refuses_zero_or_oversized_length() {
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT);
+        assert!(reassembler.add(&[FRAGMENT, 1, 2, 0, 1, 0, 0]).is_err());
+        // The header claims more data than the fragment carries
+        assert!(reassembler
+            .add(&[FRAGMENT, 1, 2, 0, 1, 255, 255, 1, 2])
+            .is_err());
+        // More fragments than the length leaves data for
+        assert!(reassembler.add(&[FRAGMENT, 1, 2, 0, 16, 0, 2, 1]).is_err());
+        assert!(reassembler.add(&[FRAGMENT, 1, 2]).is_err());
+    }
+}
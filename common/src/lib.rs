@@ -0,0 +1,13 @@
+//! The modules the LoRaRatchet client and server share: the radio profile, the regional
+//! parameters, the duty cycle, time on air, fragmentation, MAC commands, ports, payload schemas and
+//! the registry entries of peer keys. Both ends must agree on these, so they are kept in one place.
+
+pub mod airtime;
+pub mod codec;
+pub mod duty_cycle;
+pub mod fragment;
+pub mod mac;
+pub mod peer_key;
+pub mod port;
+pub mod radio;
+pub mod region;
@@ -0,0 +1,301 @@
+use crate::port::{self, MAC_PORT};
+
+/// The identifiers of the commands the AS sends. The ED answers with the same identifier, with
+/// `FROM_ED` set.
+pub const SET_UPLINK_INTERVAL: u8 = 0x01;
+pub const SET_DHR_THRESHOLD: u8 = 0x02;
+pub const LINK_ADR_REQ: u8 = 0x03;
+pub const STATUS_REQ: u8 = 0x04;
+pub const LINK_CHECK: u8 = 0x05;
+pub const REJOIN: u8 = 0x06;
+pub const REBOOT: u8 = 0x07;
+pub const DHR_REQ: u8 = 0x08;
+pub const UPLINK_ACK: u8 = 0x09;
+
+/// Set in the identifier of every command the ED sends.
+pub const FROM_ED: u8 = 0x80;
+
+/// What an ED reports about itself, when the AS asks for its status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceStatus {
+    /// 0 when running on external power, otherwise the battery level from 1 (empty) to 254 (full)
+    pub battery: u8,
+    /// The uptime of the ED in seconds
+    pub uptime: u32,
+    pub fcnt_up: u16,
+    pub fcnt_down: u16,
+    /// The amount of Diffie-Hellman ratchet steps in the session
+    pub dh_id: u32,
+}
+
+/// A MAC command, carried on `MAC_PORT` inside a ratchet-encrypted frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MacCommand {
+    /// Sleep `seconds` between uplinks.
+    SetUplinkInterval { seconds: u16 },
+    /// Request a DHR every `dhr_const` uplinks.
+    SetDhrThreshold { dhr_const: u16 },
+    /// Use `spreading_factor` and transmit with `tx_power` dBm from now on.
+    LinkAdrReq { spreading_factor: u8, tx_power: i32 },
+    /// Report a `DeviceStatus`.
+    StatusReq,
+    /// Join again, with a fresh EDHOC handshake.
+    Rejoin,
+    /// Restart the ED.
+    Reboot,
+    /// Perform a Diffie-Hellman ratchet step right away.
+    DhrReq,
+    /// The AS received the confirmed uplink with frame counter `fcnt`.
+    UplinkAck { fcnt: u16 },
+    /// Sent by the ED, asking how well the AS hears it.
+    LinkCheckReq,
+    /// The SNR in dB the uplink with the `LinkCheckReq` was received with, above what its data rate needs.
+    LinkCheckAns { margin: u8 },
+    /// Sent by the ED, answering the command with identifier `cid`.
+    Ack { cid: u8, accepted: bool },
+    /// Sent by the ED, answering a `StatusReq`.
+    StatusAns(DeviceStatus),
+}
+
+impl MacCommand {
+    /// The identifier of the command, answers carry the identifier of the command they answer.
+    pub fn cid(&self) -> u8 {
+        match self {
+            MacCommand::SetUplinkInterval { .. } => SET_UPLINK_INTERVAL,
+            MacCommand::SetDhrThreshold { .. } => SET_DHR_THRESHOLD,
+            MacCommand::LinkAdrReq { .. } => LINK_ADR_REQ,
+            MacCommand::StatusReq | MacCommand::StatusAns(_) => STATUS_REQ,
+            MacCommand::Rejoin => REJOIN,
+            MacCommand::Reboot => REBOOT,
+            MacCommand::DhrReq => DHR_REQ,
+            MacCommand::UplinkAck { .. } => UPLINK_ACK,
+            MacCommand::LinkCheckReq | MacCommand::LinkCheckAns { .. } => LINK_CHECK,
+            MacCommand::Ack { cid, .. } => *cid,
+        }
+    }
+
+    /// Appends the command to `buffer`, as its identifier followed by its arguments.
+    pub fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            MacCommand::SetUplinkInterval { seconds } => {
+                buffer.push(SET_UPLINK_INTERVAL);
+                buffer.extend_from_slice(&seconds.to_be_bytes());
+            }
+            MacCommand::SetDhrThreshold { dhr_const } => {
+                buffer.push(SET_DHR_THRESHOLD);
+                buffer.extend_from_slice(&dhr_const.to_be_bytes());
+            }
+            MacCommand::LinkAdrReq {
+                spreading_factor,
+                tx_power,
+            } => {
+                buffer.push(LINK_ADR_REQ);
+                buffer.push(*spreading_factor);
+                buffer.push(*tx_power as i8 as u8);
+            }
+            MacCommand::StatusReq => buffer.push(STATUS_REQ),
+            MacCommand::Rejoin => buffer.push(REJOIN),
+            MacCommand::Reboot => buffer.push(REBOOT),
+            MacCommand::DhrReq => buffer.push(DHR_REQ),
+            MacCommand::UplinkAck { fcnt } => {
+                buffer.push(UPLINK_ACK);
+                buffer.extend_from_slice(&fcnt.to_be_bytes());
+            }
+            MacCommand::LinkCheckReq => buffer.push(FROM_ED | LINK_CHECK),
+            MacCommand::LinkCheckAns { margin } => {
+                buffer.push(LINK_CHECK);
+                buffer.push(*margin);
+            }
+            MacCommand::Ack { cid, accepted } => {
+                buffer.push(FROM_ED | cid);
+                buffer.push(*accepted as u8);
+            }
+            MacCommand::StatusAns(status) => {
+                buffer.push(FROM_ED | STATUS_REQ);
+                buffer.push(status.battery);
+                buffer.extend_from_slice(&status.uptime.to_be_bytes());
+                buffer.extend_from_slice(&status.fcnt_up.to_be_bytes());
+                buffer.extend_from_slice(&status.fcnt_down.to_be_bytes());
+                buffer.extend_from_slice(&status.dh_id.to_be_bytes());
+            }
+        }
+    }
+}
+
+/// Encodes a list of MAC commands into a frame on `MAC_PORT`.
+///
+/// # Arguments
+///
+/// * `commands` - The commands, in the order they should be applied
+pub fn encode(commands: &[MacCommand]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    for command in commands {
+        command.encode(&mut buffer);
+    }
+    port::encode(MAC_PORT, false, &buffer)
+}
+
+/// Splits `length` bytes of arguments off the front of `rest`.
+fn take<'a>(rest: &mut &'a [u8], length: usize) -> Result<&'a [u8], String> {
+    if rest.len() < length {
+        return Err(format!("MAC command arguments too short {:?}", rest));
+    }
+    let (args, remaining) = rest.split_at(length);
+    *rest = remaining;
+    Ok(args)
+}
+
+/// Decodes the MAC commands of a frame on `MAC_PORT`, with the port already removed.
+///
+/// # Arguments
+///
+/// * `payload` - The decrypted payload
+pub fn decode(payload: &[u8]) -> Result<Vec<MacCommand>, String> {
+    let mut commands = Vec::new();
+    let mut rest = payload;
+    while let Some((cid, args)) = rest.split_first() {
+        rest = args;
+        let command = match *cid {
+            SET_UPLINK_INTERVAL => MacCommand::SetUplinkInterval {
+                seconds: u16::from_be_bytes(take(&mut rest, 2)?.try_into().unwrap()),
+            },
+            SET_DHR_THRESHOLD => MacCommand::SetDhrThreshold {
+                dhr_const: u16::from_be_bytes(take(&mut rest, 2)?.try_into().unwrap()),
+            },
+            LINK_ADR_REQ => {
+                let args = take(&mut rest, 2)?;
+                MacCommand::LinkAdrReq {
+                    spreading_factor: args[0],
+                    tx_power: args[1] as i8 as i32,
+                }
+            }
+            STATUS_REQ => MacCommand::StatusReq,
+            REJOIN => MacCommand::Rejoin,
+            REBOOT => MacCommand::Reboot,
+            DHR_REQ => MacCommand::DhrReq,
+            UPLINK_ACK => MacCommand::UplinkAck {
+                fcnt: u16::from_be_bytes(take(&mut rest, 2)?.try_into().unwrap()),
+            },
+            LINK_CHECK => MacCommand::LinkCheckAns {
+                margin: take(&mut rest, 1)?[0],
+            },
+            x if x == FROM_ED | LINK_CHECK => MacCommand::LinkCheckReq,
+            x if x == FROM_ED | STATUS_REQ => {
+                let args = take(&mut rest, 13)?;
+                MacCommand::StatusAns(DeviceStatus {
+                    battery: args[0],
+                    uptime: u32::from_be_bytes(args[1..5].try_into().unwrap()),
+                    fcnt_up: u16::from_be_bytes(args[5..7].try_into().unwrap()),
+                    fcnt_down: u16::from_be_bytes(args[7..9].try_into().unwrap()),
+                    dh_id: u32::from_be_bytes(args[9..13].try_into().unwrap()),
+                })
+            }
+            x if x & FROM_ED != 0 => MacCommand::Ack {
+                cid: x & !FROM_ED,
+                accepted: take(&mut rest, 1)?[0] != 0,
+            },
+            x => return Err(format!("Unknown MAC command {:?}", x)),
+        };
+        commands.push(command);
+    }
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes commands into a frame and decodes them back, like the AS and ED do.
+    fn round_trip(commands: &[MacCommand]) -> Vec<MacCommand> {
+        let frame = encode(commands);
+        let payload = port::decode(&frame).unwrap();
+        assert_eq!(payload.port, MAC_PORT);
+        assert!(!payload.confirmed);
+        decode(payload.data).unwrap()
+    }
+
+    #[test]
+    fn round_trips_the_commands_of_the_as() {
+        let commands = vec![
+            MacCommand::SetUplinkInterval { seconds: 0xbeef },
+            MacCommand::SetDhrThreshold { dhr_const: 300 },
+            MacCommand::LinkAdrReq {
+                spreading_factor: 9,
+                tx_power: -4,
+            },
+            MacCommand::StatusReq,
+            MacCommand::Rejoin,
+            MacCommand::Reboot,
+            MacCommand::DhrReq,
+            MacCommand::UplinkAck { fcnt: u16::MAX },
+            MacCommand::LinkCheckAns { margin: 20 },
+        ];
+        assert_eq!(round_trip(&commands), commands);
+    }
+
+    #[test]
+    fn round_trips_the_answers_of_the_ed() {
+        let commands = vec![
+            MacCommand::LinkCheckReq,
+            MacCommand::Ack {
+                cid: LINK_ADR_REQ,
+                accepted: false,
+            },
+            MacCommand::Ack {
+                cid: SET_UPLINK_INTERVAL,
+                accepted: true,
+            },
+            MacCommand::StatusAns(DeviceStatus {
+                battery: 254,
+                uptime: 0x01020304,
+                fcnt_up: 7,
+                fcnt_down: 0xfffe,
+                dh_id: u32::MAX,
+            }),
+        ];
+        assert_eq!(round_trip(&commands), commands);
+    }
+
+    #[test]
+    fn answers_carry_the_identifier_they_answer() {
+        let mut buffer = Vec::new();
+        MacCommand::Ack {
+            cid: REBOOT,
+            accepted: true,
+        }
+        .encode(&mut buffer);
+        assert_eq!(buffer, vec![FROM_ED | REBOOT, 1]);
+        assert_eq!(
+            MacCommand::StatusAns(DeviceStatus {
+                battery: 0,
+                uptime: 0,
+                fcnt_up: 0,
+                fcnt_down: 0,
+                dh_id: 0,
+            })
+            .cid(),
+            STATUS_REQ
+        );
+        assert_eq!(
+            MacCommand::LinkCheckReq.cid(),
+            MacCommand::LinkCheckAns { margin: 0 }.cid()
+        );
+    }
+
+    #[test]
+    fn empty_payload_has_no_commands() {
+        assert_eq!(decode(&[]), Ok(Vec::new()));
+        assert_eq!(round_trip(&[]), Vec::new());
+    }
+
+    #[test]
+    fn refuses_truncated_and_unknown_commands() {
+        assert!(decode(&[SET_UPLINK_INTERVAL, 1]).is_err());
+        assert!(decode(&[LINK_ADR_REQ, 7]).is_err());
+        assert!(decode(&[FROM_ED | STATUS_REQ, 0, 0, 0]).is_err());
+        assert!(decode(&[FROM_ED | REJOIN]).is_err());
+        assert!(decode(&[0x7f]).is_err());
+        // A valid command followed by garbage is refused as a whole
+        assert!(decode(&[REBOOT, 0x7f]).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek_ng::{PublicKey, StaticSecret};
+
+/// What kind of key an entry in a registry holds. Only public keys belong there, the secret type
+/// exists so an entry holding secret material is refused instead of misread.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    X25519Public,
+    X25519Secret,
+}
+
+/// The static public key of a peer, as written in a registry.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PeerKey {
+    /// The kid the peer identifies with, as hex
+    pub kid: String,
+    #[serde(rename = "type")]
+    pub key_type: KeyType,
+    /// The 32 bytes of the key, written as `hex:` or `base64:` followed by the encoded bytes
+    pub key: String,
+    /// The start of the SHA-256 of the key, see `fingerprint`
+    pub fingerprint: String,
+}
+
+/// The fingerprint of a key: the first 8 bytes of its SHA-256, as hex separated by colons. It lets
+/// the owners of both ends compare a key at a glance, and catches a mistyped key.
+///
+/// # Arguments
+///
+/// * `key` - The public key
+pub fn fingerprint(key: &[u8; 32]) -> String {
+    Sha256::digest(key)[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The public key belonging to `key` read as an X25519 secret. A registry entry whose derived
+/// public key is another key of the registry is the secret of that peer, labelled as public.
+///
+/// # Arguments
+///
+/// * `key` - The key of a registry entry
+pub fn derived_public_key(key: &[u8; 32]) -> [u8; 32] {
+    PublicKey::from(&StaticSecret::from(*key)).to_bytes()
+}
+
+impl PeerKey {
+    /// The registry entry of a public key, hex encoded.
+    ///
+    /// # Arguments
+    ///
+    /// * `kid` - The kid the peer identifies with
+    /// * `key` - The static public key of the peer
+    pub fn new(kid: &[u8], key: &[u8; 32]) -> Self {
+        PeerKey {
+            kid: hex::encode(kid),
+            key_type: KeyType::X25519Public,
+            key: format!("hex:{}", hex::encode(key)),
+            fingerprint: fingerprint(key),
+        }
+    }
+
+    /// Decodes the kid and key of the entry, refusing it if it holds secret material, is malformed
+    /// or does not match its fingerprint.
+    pub fn verify(&self) -> Result<(Vec<u8>, [u8; 32]), String> {
+        let kid = hex::decode(&self.kid)
+            .ok()
+            .filter(|kid| !kid.is_empty())
+            .ok_or(format!("Kid {:?} is not hex", self.kid))?;
+        if self.key_type != KeyType::X25519Public {
+            return Err(format!(
+                "Kid {} holds secret material, only public keys belong in the registry",
+                self.kid
+            ));
+        }
+        let bytes = if let Some(encoded) = self.key.strip_prefix("hex:") {
+            hex::decode(encoded).map_err(|x| x.to_string())
+        } else if let Some(encoded) = self.key.strip_prefix("base64:") {
+            STANDARD.decode(encoded).map_err(|x| x.to_string())
+        } else {
+            Err("it does not start with hex: or base64:".to_string())
+        };
+        let key: [u8; 32] = bytes
+            .map_err(|x| format!("The key of kid {} is malformed: {}", self.kid, x))?
+            .try_into()
+            .map_err(|_| format!("The key of kid {} is not 32 bytes", self.kid))?;
+        if key == [0; 32] {
+            return Err(format!("The key of kid {} is all zeros", self.kid));
+        }
+        if fingerprint(&key) != self.fingerprint.to_lowercase() {
+            return Err(format!(
+                "The key of kid {} does not match its fingerprint {}",
+                self.kid, self.fingerprint
+            ));
+        }
+        Ok((kid, key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    #[test]
+    fn verifies_the_entries_it_writes() {
+        let entry = PeerKey::new(&[0xa2], &KEY);
+        assert_eq!(entry.kid, "a2");
+        assert_eq!(entry.verify(), Ok((vec![0xa2], KEY)));
+    }
+
+    #[test]
+    fn reads_base64_keys() {
+        let entry = PeerKey {
+            key: format!("base64:{}", STANDARD.encode(KEY)),
+            ..PeerKey::new(&[0xa2], &KEY)
+        };
+        assert_eq!(entry.verify(), Ok((vec![0xa2], KEY)));
+    }
+
+    #[test]
+    fn refuses_secret_and_malformed_entries() {
+        let entry = PeerKey::new(&[0xa2], &KEY);
+        let refused = [
+            PeerKey {
+                key_type: KeyType::X25519Secret,
+                ..entry.clone()
+            },
+            PeerKey {
+                kid: String::new(),
+                ..entry.clone()
+            },
+            PeerKey {
+                key: hex::encode(KEY),
+                ..entry.clone()
+            },
+            PeerKey {
+                key: format!("hex:{}", hex::encode([7; 31])),
+                ..entry.clone()
+            },
+            PeerKey::new(&[0xa2], &[0; 32]),
+            PeerKey {
+                fingerprint: fingerprint(&[8; 32]),
+                ..entry.clone()
+            },
+        ];
+        for entry in refused {
+            assert!(entry.verify().is_err(), "{:?}", entry);
+        }
+    }
+
+    #[test]
+    fn fingerprints_are_case_insensitive() {
+        let entry = PeerKey::new(&[0xa2], &KEY);
+        let upper = PeerKey {
+            fingerprint: entry.fingerprint.to_uppercase(),
+            ..entry.clone()
+        };
+        assert!(upper.verify().is_ok());
+        assert_eq!(entry.fingerprint.len(), 8 * 3 - 1);
+    }
+}
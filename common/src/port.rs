@@ -0,0 +1,70 @@
+/// The port of frames carrying MAC commands, reserved for the network.
+pub const MAC_PORT: u8 = 0;
+/// The port of the periodic measurements of an ED.
+pub const TELEMETRY_PORT: u8 = 1;
+/// The port of events which need attention right away.
+pub const ALARM_PORT: u8 = 2;
+/// The port on which an ED acknowledges the configuration an application sent it.
+pub const CONFIG_PORT: u8 = 3;
+/// The highest port, the bit above it is `CONFIRMED`.
+pub const MAX_PORT: u8 = 0x7f;
+/// Set in the port of an uplink the ED wants the AS to acknowledge.
+pub const CONFIRMED: u8 = 0x80;
+
+/// The plaintext of a LoRaRatchet frame: a port followed by the data sent on that port. The ratchet
+/// encrypts both, so eavesdroppers do not learn the port either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Payload<'a> {
+    pub port: u8,
+    pub confirmed: bool,
+    pub data: &'a [u8],
+}
+
+/// Puts a port in front of the data, to be encrypted by the ratchet.
+///
+/// # Arguments
+///
+/// * `port` - The port the data is sent on, at most `MAX_PORT`
+/// * `confirmed` - Whether the AS should acknowledge the frame
+/// * `data` - The data sent on the port
+pub fn encode(port: u8, confirmed: bool, data: &[u8]) -> Vec<u8> {
+    let mut plaintext = Vec::with_capacity(data.len() + 1);
+    plaintext.push(if confirmed {
+        port & MAX_PORT | CONFIRMED
+    } else {
+        port & MAX_PORT
+    });
+    plaintext.extend_from_slice(data);
+    plaintext
+}
+
+/// Splits the plaintext the ratchet decrypted into its port and data.
+///
+/// # Arguments
+///
+/// * `plaintext` - The decrypted frame
+pub fn decode(plaintext: &[u8]) -> Result<Payload<'_>, String> {
+    match plaintext.split_first() {
+        Some((port, data)) => Ok(Payload {
+            port: port & MAX_PORT,
+            confirmed: port & CONFIRMED != 0,
+            data,
+        }),
+        None => Err("Frame without a port".to_string()),
+    }
+}
+
+/// A readable name of a port, for logging.
+///
+/// # Arguments
+///
+/// * `port` - The port, without `CONFIRMED`
+pub fn name(port: u8) -> &'static str {
+    match port {
+        MAC_PORT => "mac",
+        TELEMETRY_PORT => "telemetry",
+        ALARM_PORT => "alarm",
+        CONFIG_PORT => "config",
+        _ => "application",
+    }
+}
@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+
+use sx127x_lora::LoRa;
+
+use rppal::gpio::OutputPin;
+use rppal::spi::Spi;
+
+/// The signal bandwidths the sx1276 module supports, in Hz.
+pub const BANDWIDTHS: [i64; 10] = [
+    7800, 10400, 15600, 20800, 31250, 41700, 62500, 125000, 250000, 500000,
+];
+
+/// We transmit through the PA_BOOST pin of the sx1276 module.
+pub const PA_OUTPUT_PA_BOOST_PIN: u8 = 1;
+
+/// The physical layer settings of the radio. Both the ED and the AS must use the same profile,
+/// otherwise they will not hear each other.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct RadioProfile {
+    /// The frequency in MHz
+    pub frequency: i64,
+    /// The signal bandwidth in Hz
+    pub bandwidth: i64,
+    pub spreading_factor: u8,
+    /// The denominator of the coding rate 4/x
+    pub coding_rate: u8,
+    /// Radios only hear frames with their own sync word. A private one like `0x12` keeps our
+    /// network apart from public LoRaWAN networks, which use `0x34`
+    pub sync_word: u8,
+    /// The preamble length in symbols
+    pub preamble_length: i64,
+    pub crc: bool,
+    /// The transmit power in dBm
+    pub tx_power: i32,
+    pub implicit_header: bool,
+}
+
+impl RadioProfile {
+    /// Checks every setting against the ranges the sx1276 module supports, and returns all the
+    /// settings which are out of range.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        if !(137..=1020).contains(&self.frequency) {
+            errors.push(format!(
+                "frequency {:?} MHz is outside 137 to 1020 MHz",
+                self.frequency
+            ));
+        }
+        if !BANDWIDTHS.contains(&self.bandwidth) {
+            errors.push(format!(
+                "bandwidth {:?} Hz is not one of {:?}",
+                self.bandwidth, BANDWIDTHS
+            ));
+        }
+        if !(6..=12).contains(&self.spreading_factor) {
+            errors.push(format!(
+                "spreading factor {:?} is outside 6 to 12",
+                self.spreading_factor
+            ));
+        }
+        if self.spreading_factor == 6 && !self.implicit_header {
+            errors.push("spreading factor 6 requires implicit header mode".to_string());
+        }
+        if !(5..=8).contains(&self.coding_rate) {
+            errors.push(format!(
+                "coding rate 4/{:?} is outside 4/5 to 4/8",
+                self.coding_rate
+            ));
+        }
+        if !(6..=65535).contains(&self.preamble_length) {
+            errors.push(format!(
+                "preamble length {:?} is outside 6 to 65535 symbols",
+                self.preamble_length
+            ));
+        }
+        if !(2..=20).contains(&self.tx_power) {
+            errors.push(format!(
+                "tx power {:?} dBm is outside 2 to 20 dBm",
+                self.tx_power
+            ));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Sets every setting of the profile on the radio.
+    pub fn apply(&self, lora: &mut LoRa<Spi, OutputPin, OutputPin>) {
+        let results = [
+            ("frequency", lora.set_frequency(self.frequency)),
+            ("bandwidth", lora.set_signal_bandwidth(self.bandwidth)),
+            (
+                "spreading factor",
+                lora.set_spreading_factor(self.spreading_factor),
+            ),
+            ("coding rate", lora.set_coding_rate_4(self.coding_rate)),
+            ("sync word", lora.set_sync_word(self.sync_word)),
+            (
+                "preamble length",
+                lora.set_preamble_length(self.preamble_length),
+            ),
+            ("crc", lora.set_crc(self.crc)),
+            (
+                "tx power",
+                lora.set_tx_power(self.tx_power, PA_OUTPUT_PA_BOOST_PIN),
+            ),
+            (
+                "implicit header mode",
+                lora.set_implicit_header_mode(self.implicit_header),
+            ),
+        ];
+        for (setting, result) in results {
+            if result.is_err() {
+                println!("Setting {} failed", setting);
+            }
+        }
+    }
+}
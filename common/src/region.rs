@@ -0,0 +1,517 @@
+use serde::{Deserialize, Serialize};
+
+use std::time::Duration;
+
+use crate::duty_cycle::DUTY_CYCLE_PERIOD;
+
+/// The regional parameter profiles we support, following the LoRaWAN regional parameters.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    EU868,
+    US915,
+    AU915,
+    AS923,
+}
+
+/// A combination of spreading factor and bandwidth, and the largest frame we may send with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataRate {
+    pub spreading_factor: u8,
+    /// The signal bandwidth in Hz
+    pub bandwidth: i64,
+    /// The largest frame in bytes
+    pub max_payload: usize,
+}
+
+/// An uplink channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Channel {
+    /// The frequency in Hz
+    pub frequency: i64,
+    /// The signal bandwidth in Hz
+    pub bandwidth: i64,
+}
+
+/// A frequency range in which the time we may spend transmitting is limited.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SubBand {
+    /// The lowest frequency in Hz
+    pub low: i64,
+    /// The highest frequency in Hz
+    pub high: i64,
+    /// The fraction of time we may transmit, e.g. `0.01` for 1%
+    pub duty_cycle: f64,
+}
+
+impl SubBand {
+    pub fn contains(&self, frequency: i64) -> bool {
+        (self.low..=self.high).contains(&frequency)
+    }
+
+    /// The airtime we may use within a duty-cycle period.
+    pub fn budget(&self) -> Duration {
+        DUTY_CYCLE_PERIOD.mul_f64(self.duty_cycle)
+    }
+}
+
+/// Everything a region defines, which we need to transmit and listen legally in it.
+#[derive(Debug, Clone)]
+pub struct ChannelPlan {
+    pub region: Region,
+    pub uplink_channels: Vec<Channel>,
+    pub uplink_data_rates: Vec<DataRate>,
+    pub downlink_data_rates: Vec<DataRate>,
+    /// The frequency of the RX2 window in Hz
+    pub rx2_frequency: i64,
+    pub rx2_data_rate: DataRate,
+    /// The highest transmit power we may use in dBm
+    pub max_tx_power: i32,
+    pub sub_bands: Vec<SubBand>,
+    /// The longest a single uplink may be on air, downlinks are only limited by the duty cycle
+    pub uplink_dwell_time: Option<Duration>,
+}
+
+const fn data_rate(spreading_factor: u8, bandwidth: i64, max_payload: usize) -> DataRate {
+    DataRate {
+        spreading_factor,
+        bandwidth,
+        max_payload,
+    }
+}
+
+const fn sub_band(low: i64, high: i64, duty_cycle: f64) -> SubBand {
+    SubBand {
+        low,
+        high,
+        duty_cycle,
+    }
+}
+
+/// `count` channels starting at `first` Hz, spaced `step` Hz apart.
+fn channels(first: i64, step: i64, count: i64, bandwidth: i64) -> Vec<Channel> {
+    (0..count)
+        .map(|n| Channel {
+            frequency: first + n * step,
+            bandwidth,
+        })
+        .collect()
+}
+
+impl Region {
+    pub fn channel_plan(self) -> ChannelPlan {
+        match self {
+            Region::EU868 => ChannelPlan {
+                region: self,
+                uplink_channels: [
+                    868_100_000,
+                    868_300_000,
+                    868_500_000,
+                    867_100_000,
+                    867_300_000,
+                    867_500_000,
+                    867_700_000,
+                    867_900_000,
+                ]
+                .iter()
+                .map(|frequency| Channel {
+                    frequency: *frequency,
+                    bandwidth: 125000,
+                })
+                .collect(),
+                uplink_data_rates: vec![
+                    data_rate(12, 125000, 59),
+                    data_rate(11, 125000, 59),
+                    data_rate(10, 125000, 59),
+                    data_rate(9, 125000, 123),
+                    data_rate(8, 125000, 230),
+                    data_rate(7, 125000, 230),
+                    data_rate(7, 250000, 230),
+                ],
+                downlink_data_rates: vec![
+                    data_rate(12, 125000, 59),
+                    data_rate(11, 125000, 59),
+                    data_rate(10, 125000, 59),
+                    data_rate(9, 125000, 123),
+                    data_rate(8, 125000, 230),
+                    data_rate(7, 125000, 230),
+                    data_rate(7, 250000, 230),
+                ],
+                rx2_frequency: 869_525_000,
+                rx2_data_rate: data_rate(12, 125000, 59),
+                max_tx_power: 16,
+                // The ETSI sub-bands, g, g1, g2, g3 and g4
+                sub_bands: vec![
+                    sub_band(863_000_000, 867_999_999, 0.01),
+                    sub_band(868_000_000, 868_600_000, 0.01),
+                    sub_band(868_700_000, 869_200_000, 0.001),
+                    sub_band(869_400_000, 869_650_000, 0.1),
+                    sub_band(869_700_000, 870_000_000, 0.01),
+                ],
+                uplink_dwell_time: None,
+            },
+            Region::US915 | Region::AU915 => {
+                let (first_125, first_500, uplink_data_rates) = if self == Region::US915 {
+                    (
+                        902_300_000,
+                        903_000_000,
+                        vec![
+                            data_rate(10, 125000, 19),
+                            data_rate(9, 125000, 61),
+                            data_rate(8, 125000, 133),
+                            data_rate(7, 125000, 250),
+                            data_rate(8, 500000, 250),
+                        ],
+                    )
+                } else {
+                    (
+                        915_200_000,
+                        915_900_000,
+                        vec![
+                            data_rate(12, 125000, 59),
+                            data_rate(11, 125000, 59),
+                            data_rate(10, 125000, 59),
+                            data_rate(9, 125000, 123),
+                            data_rate(8, 125000, 230),
+                            data_rate(7, 125000, 230),
+                            data_rate(8, 500000, 230),
+                        ],
+                    )
+                };
+                let mut uplink_channels = channels(first_125, 200_000, 64, 125000);
+                uplink_channels.extend(channels(first_500, 1_600_000, 8, 500000));
+                ChannelPlan {
+                    region: self,
+                    uplink_channels,
+                    uplink_data_rates,
+                    downlink_data_rates: vec![
+                        data_rate(12, 500000, 61),
+                        data_rate(11, 500000, 137),
+                        data_rate(10, 500000, 250),
+                        data_rate(9, 500000, 250),
+                        data_rate(8, 500000, 250),
+                        data_rate(7, 500000, 250),
+                    ],
+                    rx2_frequency: 923_300_000,
+                    rx2_data_rate: data_rate(12, 500000, 61),
+                    max_tx_power: 30,
+                    sub_bands: Vec::new(),
+                    uplink_dwell_time: if self == Region::US915 {
+                        Some(Duration::from_millis(400))
+                    } else {
+                        None
+                    },
+                }
+            }
+            Region::AS923 => ChannelPlan {
+                region: self,
+                uplink_channels: channels(922_000_000, 200_000, 8, 125000),
+                uplink_data_rates: vec![
+                    data_rate(12, 125000, 59),
+                    data_rate(11, 125000, 59),
+                    data_rate(10, 125000, 123),
+                    data_rate(9, 125000, 123),
+                    data_rate(8, 125000, 250),
+                    data_rate(7, 125000, 250),
+                    data_rate(7, 250000, 250),
+                ],
+                downlink_data_rates: vec![
+                    data_rate(12, 125000, 59),
+                    data_rate(11, 125000, 59),
+                    data_rate(10, 125000, 123),
+                    data_rate(9, 125000, 123),
+                    data_rate(8, 125000, 250),
+                    data_rate(7, 125000, 250),
+                    data_rate(7, 250000, 250),
+                ],
+                rx2_frequency: 923_200_000,
+                rx2_data_rate: data_rate(10, 125000, 123),
+                max_tx_power: 16,
+                sub_bands: vec![sub_band(915_000_000, 928_000_000, 0.01)],
+                uplink_dwell_time: Some(Duration::from_millis(400)),
+            },
+        }
+    }
+}
+
+impl ChannelPlan {
+    /// The uplink channels we may use with the given bandwidth. In US915 and AU915 a sub-band
+    /// restricts these to the eight 125 kHz channels and the one 500 kHz channel of that sub-band,
+    /// which is what most gateways listen on.
+    ///
+    /// # Arguments
+    ///
+    /// * `bandwidth` - The signal bandwidth in Hz
+    /// * `sub_band` - The sub-band from 0 to 7, only used in US915 and AU915
+    pub fn enabled_channels(&self, bandwidth: i64, sub_band: Option<u8>) -> Vec<Channel> {
+        self.uplink_channels
+            .iter()
+            .enumerate()
+            .filter(|(_, channel)| channel.bandwidth == bandwidth)
+            .filter(|(index, _)| match (self.region, sub_band) {
+                (Region::US915 | Region::AU915, Some(sub_band)) => {
+                    let sub_band = sub_band as usize;
+                    index / 8 == sub_band || *index == 64 + sub_band
+                }
+                _ => true,
+            })
+            .map(|(_, channel)| *channel)
+            .collect()
+    }
+
+    /// The uplink data rate with the given spreading factor and bandwidth, if the region allows it.
+    pub fn uplink_data_rate(&self, spreading_factor: u8, bandwidth: i64) -> Option<DataRate> {
+        self.uplink_data_rates.iter().copied().find(|data_rate| {
+            data_rate.spreading_factor == spreading_factor && data_rate.bandwidth == bandwidth
+        })
+    }
+
+    /// The frequency in Hz the RX1 window listens on, after an uplink on `uplink_frequency`. In
+    /// US915 and AU915 this is one of the eight downlink channels, elsewhere it is the uplink channel.
+    pub fn rx1_frequency(&self, uplink_frequency: i64) -> i64 {
+        match self.region {
+            Region::US915 | Region::AU915 => {
+                let index = self
+                    .uplink_channels
+                    .iter()
+                    .position(|channel| channel.frequency == uplink_frequency)
+                    .unwrap_or(0);
+                923_300_000 + (index as i64 % 8) * 600_000
+            }
+            Region::EU868 | Region::AS923 => uplink_frequency,
+        }
+    }
+
+    /// The data rate the RX1 window listens with, after an uplink with the given data rate. In
+    /// US915 and AU915 downlinks use 500 kHz following the RX1 table of the regional parameters,
+    /// elsewhere the uplink data rate is used.
+    pub fn rx1_data_rate(&self, uplink: DataRate) -> DataRate {
+        // The downlink data rate of each uplink data rate, with an RX1 offset of 0, as an index
+        // into `downlink_data_rates`, which runs from DR8 to DR13
+        let table: &[usize] = match self.region {
+            // DR0 to DR4 answered on DR10 to DR13
+            Region::US915 => &[2, 3, 4, 5, 5],
+            // DR0 to DR6 answered on DR8 to DR13
+            Region::AU915 => &[0, 1, 2, 3, 4, 5, 5],
+            Region::EU868 | Region::AS923 => return uplink,
+        };
+        self.uplink_data_rates
+            .iter()
+            .position(|data_rate| {
+                data_rate.spreading_factor == uplink.spreading_factor
+                    && data_rate.bandwidth == uplink.bandwidth
+            })
+            .and_then(|index| table.get(index))
+            .map(|index| self.downlink_data_rates[*index])
+            .unwrap_or(self.rx2_data_rate)
+    }
+
+    /// Checks the radio settings against the region, and returns everything the region does not allow.
+    ///
+    /// # Arguments
+    ///
+    /// * `spreading_factor` - The spreading factor of our uplinks
+    /// * `bandwidth` - The signal bandwidth of our uplinks in Hz
+    /// * `tx_power` - The transmit power in dBm
+    /// * `sub_band` - The sub-band from 0 to 7, only used in US915 and AU915
+    pub fn validate(
+        &self,
+        spreading_factor: u8,
+        bandwidth: i64,
+        tx_power: i32,
+        sub_band: Option<u8>,
+    ) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        if self.uplink_data_rate(spreading_factor, bandwidth).is_none() {
+            errors.push(format!(
+                "SF{:?} with {:?} Hz is not an uplink data rate in {:?}",
+                spreading_factor, bandwidth, self.region
+            ));
+        }
+        if tx_power > self.max_tx_power {
+            errors.push(format!(
+                "tx power {:?} dBm is above the {:?} dBm allowed in {:?}",
+                tx_power, self.max_tx_power, self.region
+            ));
+        }
+        if sub_band.is_some_and(|sub_band| sub_band > 7) {
+            errors.push(format!("sub-band {:?} is outside 0 to 7", sub_band));
+        }
+        if self.enabled_channels(bandwidth, sub_band).is_empty() {
+            errors.push(format!(
+                "{:?} has no uplink channels with {:?} Hz",
+                self.region, bandwidth
+            ));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGIONS: [Region; 4] = [Region::EU868, Region::US915, Region::AU915, Region::AS923];
+
+    /// The spreading factor and bandwidth of the RX1 data rate after each uplink data rate.
+    fn rx1_table(region: Region) -> Vec<(u8, i64)> {
+        let plan = region.channel_plan();
+        plan.uplink_data_rates
+            .iter()
+            .map(|uplink| {
+                let downlink = plan.rx1_data_rate(*uplink);
+                (downlink.spreading_factor, downlink.bandwidth)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rx1_data_rates_follow_the_regional_parameters() {
+        let eu868: Vec<(u8, i64)> = Region::EU868
+            .channel_plan()
+            .uplink_data_rates
+            .iter()
+            .map(|x| (x.spreading_factor, x.bandwidth))
+            .collect();
+        assert_eq!(rx1_table(Region::EU868), eu868);
+        assert_eq!(
+            rx1_table(Region::US915),
+            vec![
+                (10, 500000),
+                (9, 500000),
+                (8, 500000),
+                (7, 500000),
+                (7, 500000)
+            ]
+        );
+        assert_eq!(
+            rx1_table(Region::AU915),
+            vec![
+                (12, 500000),
+                (11, 500000),
+                (10, 500000),
+                (9, 500000),
+                (8, 500000),
+                (7, 500000),
+                (7, 500000)
+            ]
+        );
+        let as923 = rx1_table(Region::AS923);
+        assert_eq!(as923.first(), Some(&(12, 125000)));
+        assert_eq!(as923.last(), Some(&(7, 250000)));
+    }
+
+    #[test]
+    fn rx1_of_an_unknown_data_rate_is_rx2() {
+        let plan = Region::US915.channel_plan();
+        assert_eq!(
+            plan.rx1_data_rate(data_rate(12, 125000, 51)),
+            plan.rx2_data_rate
+        );
+    }
+
+    #[test]
+    fn rx1_frequencies() {
+        let eu868 = Region::EU868.channel_plan();
+        assert_eq!(eu868.rx1_frequency(867_100_000), 867_100_000);
+        for region in [Region::US915, Region::AU915] {
+            let plan = region.channel_plan();
+            let first = plan.uplink_channels[0].frequency;
+            assert_eq!(plan.rx1_frequency(first), 923_300_000);
+            assert_eq!(
+                plan.rx1_frequency(plan.uplink_channels[9].frequency),
+                923_900_000
+            );
+            // The 500 kHz channels map onto the same eight downlink channels
+            assert_eq!(
+                plan.rx1_frequency(plan.uplink_channels[71].frequency),
+                927_500_000
+            );
+        }
+    }
+
+    #[test]
+    fn channel_counts() {
+        let counts: Vec<usize> = REGIONS
+            .iter()
+            .map(|region| region.channel_plan().uplink_channels.len())
+            .collect();
+        assert_eq!(counts, vec![8, 72, 72, 8]);
+        let us915 = Region::US915.channel_plan();
+        assert_eq!(us915.uplink_channels[0].frequency, 902_300_000);
+        assert_eq!(us915.uplink_channels[63].frequency, 914_900_000);
+        assert_eq!(us915.uplink_channels[64].frequency, 903_000_000);
+        let au915 = Region::AU915.channel_plan();
+        assert_eq!(au915.uplink_channels[0].frequency, 915_200_000);
+        assert_eq!(au915.uplink_channels[71].frequency, 927_100_000);
+    }
+
+    #[test]
+    fn sub_bands_enable_nine_channels() {
+        for region in [Region::US915, Region::AU915] {
+            let plan = region.channel_plan();
+            let narrow = plan.enabled_channels(125000, Some(1));
+            assert_eq!(narrow.len(), 8);
+            assert_eq!(narrow[0], plan.uplink_channels[8]);
+            assert_eq!(
+                plan.enabled_channels(500000, Some(1)),
+                vec![plan.uplink_channels[65]]
+            );
+            assert_eq!(plan.enabled_channels(125000, None).len(), 64);
+        }
+        // Sub-bands only apply in US915 and AU915
+        assert_eq!(
+            Region::EU868
+                .channel_plan()
+                .enabled_channels(125000, Some(1))
+                .len(),
+            8
+        );
+        assert!(Region::EU868
+            .channel_plan()
+            .enabled_channels(500000, None)
+            .is_empty());
+    }
+
+    #[test]
+    fn every_plan_accepts_its_own_data_rates() {
+        for region in REGIONS {
+            let plan = region.channel_plan();
+            for data_rate in plan.uplink_data_rates.iter() {
+                let channels = plan.enabled_channels(data_rate.bandwidth, Some(0));
+                if channels.is_empty() {
+                    continue;
+                }
+                assert_eq!(
+                    plan.validate(data_rate.spreading_factor, data_rate.bandwidth, 14, Some(0)),
+                    Ok(()),
+                    "{:?} {:?}",
+                    region,
+                    data_rate
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let plan = Region::EU868.channel_plan();
+        let errors = plan.validate(6, 500000, 20, Some(8)).unwrap_err();
+        assert_eq!(errors.len(), 4);
+    }
+
+    #[test]
+    fn duty_cycle_budgets() {
+        let eu868 = Region::EU868.channel_plan();
+        let budgets: Vec<Duration> = eu868.sub_bands.iter().map(SubBand::budget).collect();
+        assert_eq!(budgets[1], Duration::from_secs(36));
+        assert_eq!(budgets[2], Duration::from_millis(3600));
+        assert_eq!(budgets[3], Duration::from_secs(360));
+        assert!(eu868.sub_bands[1].contains(868_100_000));
+        assert!(!eu868.sub_bands[1].contains(869_525_000));
+        assert!(eu868.sub_bands[3].contains(eu868.rx2_frequency));
+    }
+}
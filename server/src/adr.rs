@@ -0,0 +1,212 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+use crate::{
+    airtime::Modulation,
+    radio::RadioProfile,
+    region::{ChannelPlan, Region},
+    transport::FrameMeta,
+};
+
+/// The amount of receptions at the current spreading factor we need, before we adapt the data rate.
+pub const MIN_RECEPTIONS: usize = 10;
+
+/// Every step of 3 dB SNR margin lets us lower the spreading factor or TX power by one step.
+const STEP: f64 = 3.0;
+
+/// The TX power change in dBm of a single step.
+const TX_POWER_STEP: i32 = 3;
+
+/// The limits within which the server adapts the data rate and TX power of the EDs.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct AdrConfig {
+    pub enabled: bool,
+    pub min_spreading_factor: u8,
+    pub max_spreading_factor: u8,
+    /// The lowest TX power in dBm we let an ED use
+    pub min_tx_power: i32,
+    /// The highest TX power in dBm we let an ED use, EDs are assumed to start with it
+    pub max_tx_power: i32,
+    /// The SNR in dB we keep in reserve for fading
+    pub margin: f64,
+}
+
+impl AdrConfig {
+    /// Checks that the limits are within what the sx1276 supports, and returns all which are not.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        if !(7..=12).contains(&self.min_spreading_factor)
+            || !(7..=12).contains(&self.max_spreading_factor)
+            || self.min_spreading_factor > self.max_spreading_factor
+        {
+            errors.push(format!(
+                "spreading factors {:?} to {:?} are not a range within 7 to 12",
+                self.min_spreading_factor, self.max_spreading_factor
+            ));
+        }
+        if !(2..=20).contains(&self.min_tx_power)
+            || !(2..=20).contains(&self.max_tx_power)
+            || self.min_tx_power > self.max_tx_power
+        {
+            errors.push(format!(
+                "tx powers {:?} to {:?} dBm are not a range within 2 to 20 dBm",
+                self.min_tx_power, self.max_tx_power
+            ));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The spreading factors EDs may use, which we have to listen on.
+    pub fn spreading_factors(&self) -> Vec<u8> {
+        (self.min_spreading_factor..=self.max_spreading_factor).collect()
+    }
+}
+
+/// The data rate and TX power of an ED.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkSettings {
+    pub spreading_factor: u8,
+    /// The TX power in dBm
+    pub tx_power: i32,
+}
+
+/// The ADR state of the server, holding the TX power we assigned to every ED.
+pub struct Adr {
+    config: AdrConfig,
+    profile: RadioProfile,
+    plan: Option<ChannelPlan>,
+    assigned: HashMap<[u8; 4], Assignment>,
+}
+
+/// The link settings we last assigned to an ED.
+#[derive(Debug, Clone, Copy)]
+struct Assignment {
+    tx_power: i32,
+    /// Only receptions after this are used for the next decision
+    since: Instant,
+}
+
+impl Adr {
+    /// Creates the ADR state, without any settings assigned.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The limits of the settings
+    /// * `profile` - The radio profile, whose bandwidth the EDs use
+    /// * `region` - The region, whose data rates limit the spreading factors
+    pub fn new(config: AdrConfig, profile: RadioProfile, region: Option<Region>) -> Self {
+        Adr {
+            config,
+            profile,
+            plan: region.map(Region::channel_plan),
+            assigned: HashMap::new(),
+        }
+    }
+
+    fn allowed(&self, spreading_factor: u8) -> bool {
+        match &self.plan {
+            Some(plan) => plan
+                .uplink_data_rate(spreading_factor, self.profile.bandwidth)
+                .is_some(),
+            None => true,
+        }
+    }
+
+    /// Computes the link settings an ED should use, from the SNR of its latest receptions. Like the
+    /// LoRaWAN ADR algorithm, every 3 dB of margin above what the spreading factor needs lowers the
+    /// spreading factor and then the TX power by one step, while a negative margin raises the TX
+    /// power and then the spreading factor. Returns `None` while there are too few receptions since
+    /// the last assignment, or when the settings should stay the same.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED
+    /// * `receptions` - The latest receptions from the ED, oldest first
+    pub fn decide(
+        &self,
+        devaddr: &[u8; 4],
+        receptions: &VecDeque<FrameMeta>,
+    ) -> Option<LinkSettings> {
+        // The spreading factor is seen in every uplink, the TX power only known from what we assigned
+        let latest = receptions.back()?;
+        let assigned = self.assigned.get(devaddr);
+        let current = LinkSettings {
+            spreading_factor: latest.spreading_factor,
+            tx_power: assigned.map_or(self.config.max_tx_power, |x| x.tx_power),
+        };
+        let snrs: Vec<f64> = receptions
+            .iter()
+            .filter(|meta| assigned.is_none_or(|x| meta.received > x.since))
+            .filter(|meta| meta.spreading_factor == current.spreading_factor)
+            .map(|meta| meta.snr)
+            .collect();
+        if snrs.len() < MIN_RECEPTIONS {
+            return None;
+        }
+        let max_snr = snrs.iter().copied().fold(f64::MIN, f64::max);
+        let required_snr = Modulation::from_profile(&self.profile)
+            .with_data_rate(current.spreading_factor, self.profile.bandwidth)
+            .required_snr();
+        let mut steps = ((max_snr - required_snr - self.config.margin) / STEP).floor() as i32;
+
+        let mut next = current;
+        while steps > 0
+            && next.spreading_factor > self.config.min_spreading_factor
+            && self.allowed(next.spreading_factor - 1)
+        {
+            next.spreading_factor -= 1;
+            steps -= 1;
+        }
+        while steps > 0 && next.tx_power - TX_POWER_STEP >= self.config.min_tx_power {
+            next.tx_power -= TX_POWER_STEP;
+            steps -= 1;
+        }
+        while steps < 0 && next.tx_power < self.config.max_tx_power {
+            next.tx_power = (next.tx_power + TX_POWER_STEP).min(self.config.max_tx_power);
+            steps += 1;
+        }
+        while steps < 0
+            && next.spreading_factor < self.config.max_spreading_factor
+            && self.allowed(next.spreading_factor + 1)
+        {
+            next.spreading_factor += 1;
+            steps += 1;
+        }
+        if next == current {
+            None
+        } else {
+            Some(next)
+        }
+    }
+
+    /// Remembers that we told an ED to use `settings`.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED
+    /// * `settings` - The settings we sent it
+    pub fn assign(&mut self, devaddr: [u8; 4], settings: LinkSettings) {
+        self.assigned.insert(
+            devaddr,
+            Assignment {
+                tx_power: settings.tx_power,
+                since: Instant::now(),
+            },
+        );
+    }
+
+    /// Forgets the settings of an ED, which starts over at the radio profile when it joins again.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED
+    pub fn forget(&mut self, devaddr: &[u8; 4]) {
+        self.assigned.remove(devaddr);
+    }
+}
@@ -0,0 +1,198 @@
+use clap::{Parser, Subcommand};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use serde_json::{json, Value};
+
+use x25519_dalek_ng::{PublicKey, StaticSecret};
+
+use rasp_lora_server::{filehandler, peer_key};
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Generates static keys, and provisions and revokes EDs in the key registry of the server.
+#[derive(Parser)]
+#[command(name = "lora-keytool", version)]
+struct Args {
+    /// The TOML config of the server, naming its keys file and AS kid
+    #[arg(long, env = "LORA_CONFIG", default_value = "./config.toml")]
+    config: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Generate a static keypair and print it, with the public key as a registry entry
+    Generate {
+        /// The kid of the registry entry, as hex
+        #[arg(long, default_value = "00")]
+        kid: String,
+    },
+    /// Generate the static key of a new ED, add its public key to the registry of the server, and
+    /// write the keys.json and config.json of the ED
+    Provision {
+        /// The directory to write the keys.json and config.json of the ED to
+        #[arg(long)]
+        out: String,
+        /// The client config.json the config of the ED is based on
+        #[arg(long, default_value = "../client/config.json")]
+        template: String,
+        /// The kid of the ED as hex, the lowest free kid when left out
+        #[arg(long)]
+        kid: Option<String>,
+        /// The AppEUI of the ED as 16 hex digits, that of the template when left out
+        #[arg(long)]
+        appeui: Option<String>,
+    },
+    /// Remove an ED from the registry of the server, so it can no longer join. A running server
+    /// reads the registry when it starts, and keeps the session of the ED until it is restarted
+    Revoke {
+        /// The kid of the ED, as hex
+        kid: String,
+    },
+}
+
+fn main() {
+    let args = Args::parse();
+    let result = match args.command {
+        Command::Generate { kid } => parse_hex(&kid).map(|kid| {
+            let (secret, public) = generate_keypair();
+            println!(
+                "{}",
+                json!({
+                    "static_material": secret,
+                    "public_key": peer_key::PeerKey::new(&kid, &public),
+                })
+            );
+        }),
+        Command::Provision {
+            out,
+            template,
+            kid,
+            appeui,
+        } => provision(&args.config, &out, &template, kid, appeui),
+        Command::Revoke { kid } => revoke(&args.config, &kid),
+    };
+    if let Err(x) = result {
+        println!("{}", x);
+        std::process::exit(1);
+    }
+}
+
+/// Generates a static X25519 keypair, returning the secret and the public key.
+fn generate_keypair() -> ([u8; 32], [u8; 32]) {
+    let mut r: StdRng = StdRng::from_entropy();
+    let secret = r.gen::<[u8; 32]>();
+    let public = PublicKey::from(&StaticSecret::from(secret));
+    (secret, *public.as_bytes())
+}
+
+/// Parses hex digits into bytes.
+fn parse_hex(hex: &str) -> Result<Vec<u8>, String> {
+    hex::decode(hex).map_err(|_| format!("{:?} is not hex, two digits per byte", hex))
+}
+
+/// Provisions a new ED: generates its static key and DevEUI, writes its keys.json and config.json
+/// to `out`, and adds its public key to the registry of the server.
+///
+/// # Arguments
+///
+/// * `config` - The path of the TOML config of the server
+/// * `out` - The directory to write the files of the ED to
+/// * `template` - The path of the client config the config of the ED is based on
+/// * `kid` - The kid of the ED as hex, the lowest free kid when `None`
+/// * `appeui` - The AppEUI of the ED as hex, that of the template when `None`
+fn provision(
+    config: &str,
+    out: &str,
+    template: &str,
+    kid: Option<String>,
+    appeui: Option<String>,
+) -> Result<(), String> {
+    let config = filehandler::load_config(config)?;
+    let mut keys = filehandler::load_static_keys(&config.keys)?;
+    let kid = match kid {
+        Some(kid) => parse_hex(&kid)?,
+        None => keys
+            .free_kid(&config.as_kid)
+            .ok_or("Every single-byte kid is taken, pick one with --kid")?,
+    };
+    let (secret, public) = generate_keypair();
+    keys.add_ed(kid.clone(), public, &config.as_kid)?;
+
+    let mut ed_config: Value = serde_json::from_str(
+        &fs::read_to_string(template).map_err(|x| format!("Unable to read {}: {}", template, x))?,
+    )
+    .map_err(|x| format!("{}: {}", template, x))?;
+    let deveui: [u8; 8] = rand::random();
+    ed_config["deveui"] = json!(deveui);
+    if let Some(appeui) = appeui {
+        let appeui: [u8; 8] = parse_hex(&appeui)?
+            .try_into()
+            .map_err(|_| "An AppEUI is 16 hex digits".to_string())?;
+        ed_config["appeui"] = json!(appeui);
+    }
+    let as_public = PublicKey::from(&StaticSecret::from(keys.as_static_material));
+    let ed_keys = json!({
+        "ed_kid": kid,
+        "ed_static_material": secret,
+        "as_keys": [peer_key::PeerKey::new(&config.as_kid, as_public.as_bytes())],
+    });
+
+    let out = Path::new(out);
+    if out.join("keys.json").exists() {
+        return Err(format!(
+            "{} already holds a keys.json",
+            out.to_string_lossy()
+        ));
+    }
+    fs::create_dir_all(out).map_err(|x| x.to_string())?;
+    // Only the owner may read the static secret of the ED
+    for (name, value, mode) in [
+        ("keys.json", &ed_keys, 0o600),
+        ("config.json", &ed_config, 0o644),
+    ] {
+        let path = out.join(name);
+        let data = serde_json::to_string_pretty(value).map_err(|x| x.to_string())?;
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(mode)
+            .open(&path)
+            .and_then(|mut file| file.write_all(data.as_bytes()))
+            .map_err(|x| format!("Unable to write {}: {}", path.to_string_lossy(), x))?;
+    }
+    filehandler::save_static_keys(&config.keys, &keys)?;
+    println!(
+        "Provisioned kid {:?} with DevEUI {} and key fingerprint {}, its files are in {}",
+        kid,
+        hex::encode(deveui),
+        peer_key::fingerprint(&public),
+        out.to_string_lossy()
+    );
+    Ok(())
+}
+
+/// Removes an ED from the registry of the server.
+///
+/// # Arguments
+///
+/// * `config` - The path of the TOML config of the server
+/// * `kid` - The kid of the ED, as hex
+fn revoke(config: &str, kid: &str) -> Result<(), String> {
+    let config = filehandler::load_config(config)?;
+    let mut keys = filehandler::load_static_keys(&config.keys)?;
+    let kid = parse_hex(kid)?;
+    keys.revoke(&kid)?;
+    filehandler::save_static_keys(&config.keys, &keys)?;
+    println!(
+        "Revoked kid {:?}, restart the server to also end the session of the ED",
+        kid
+    );
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::fs;
+
+use crate::{
+    codec::{self, Schema},
+    scripts::{ScriptLimits, Scripts},
+};
+
+/// The schemas and scripts which turn the application data of the EDs into JSON objects and back,
+/// and which device or application uses which schema on which port. EUIs are written as 16 hex
+/// digits. The schema of a device goes before the script of its application, which goes before
+/// the schema of its application.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Decoders {
+    /// The schemas, by name
+    pub schemas: HashMap<String, Schema>,
+    /// The schema name per port, per DevEUI
+    #[serde(default)]
+    pub devices: HashMap<String, HashMap<u8, String>>,
+    /// The schema name per port, per AppEUI, for devices without one of their own
+    #[serde(default)]
+    pub applications: HashMap<String, HashMap<u8, String>>,
+    /// The path of the decoder and encoder script, per AppEUI
+    #[serde(default)]
+    pub scripts: HashMap<String, String>,
+    #[serde(default)]
+    pub script_limits: ScriptLimits,
+    #[serde(skip)]
+    compiled: Scripts,
+}
+
+/// Writes an EUI as 16 hex digits.
+fn eui(eui: &[u8]) -> String {
+    eui.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl Decoders {
+    /// Loads the decoders from a JSON file and compiles their scripts, failing when the file is
+    /// missing. Without decoders, `Decoders::default()`, no uplink is decoded.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Where the file is located
+    pub fn load(path: &str) -> Result<Self, String> {
+        let data =
+            fs::read_to_string(path).map_err(|x| format!("Unable to read {}: {}", path, x))?;
+        let mut decoders: Decoders =
+            serde_json::from_str(&data).map_err(|x| format!("{}: {}", path, x))?;
+        for (name, ports) in decoders.devices.iter().chain(decoders.applications.iter()) {
+            for schema in ports.values() {
+                if !decoders.schemas.contains_key(schema) {
+                    return Err(format!(
+                        "{}: {} uses the unknown schema {}",
+                        path, name, schema
+                    ));
+                }
+            }
+        }
+        decoders.compiled = Scripts::new(decoders.script_limits);
+        for (appeui, script) in &decoders.scripts {
+            decoders.compiled.load(appeui, script)?;
+        }
+        Ok(decoders)
+    }
+
+    /// The schema a device has of its own on a port.
+    fn device_schema(&self, deveui: &[u8], port: u8) -> Option<&Schema> {
+        let name = self.devices.get(&eui(deveui))?.get(&port)?;
+        self.schemas.get(name)
+    }
+
+    /// The schema an application has on a port.
+    fn application_schema(&self, appeui: &[u8], port: u8) -> Option<&Schema> {
+        let name = self.applications.get(&eui(appeui))?.get(&port)?;
+        self.schemas.get(name)
+    }
+
+    /// Decodes an uplink into a JSON object, `None` if the device has no schema or script for
+    /// the port.
+    ///
+    /// # Arguments
+    ///
+    /// * `deveui` - The DevEUI of the device
+    /// * `appeui` - The AppEUI of the device
+    /// * `port` - The port of the uplink
+    /// * `data` - The application data
+    pub fn decode(
+        &self,
+        deveui: &[u8],
+        appeui: &[u8],
+        port: u8,
+        data: &[u8],
+    ) -> Option<Result<Value, String>> {
+        if let Some(schema) = self.device_schema(deveui, port) {
+            return Some(codec::decode(schema, data));
+        }
+        self.compiled.decode(&eui(appeui), port, data).or_else(|| {
+            self.application_schema(appeui, port)
+                .map(|schema| codec::decode(schema, data))
+        })
+    }
+
+    /// Encodes a JSON object into the application data of a downlink, `None` if the device has
+    /// no schema or script for the port.
+    ///
+    /// # Arguments
+    ///
+    /// * `deveui` - The DevEUI of the device
+    /// * `appeui` - The AppEUI of the device
+    /// * `port` - The port of the downlink
+    /// * `object` - The object to encode
+    pub fn encode(
+        &self,
+        deveui: &[u8],
+        appeui: &[u8],
+        port: u8,
+        object: &Value,
+    ) -> Option<Result<Vec<u8>, String>> {
+        if let Some(schema) = self.device_schema(deveui, port) {
+            return Some(codec::encode(schema, object));
+        }
+        self.compiled
+            .encode(&eui(appeui), port, object)
+            .or_else(|| {
+                self.application_schema(appeui, port)
+                    .map(|schema| codec::encode(schema, object))
+            })
+    }
+}
@@ -1,18 +1,177 @@
 use serde::{Deserialize, Serialize};
+use x25519_dalek_ng::{PublicKey, StaticSecret};
 
 use std::fs;
 
-
+use crate::{
+    adr::AdrConfig,
+    peer_key::{derived_public_key, PeerKey},
+    radio::RadioProfile,
+    region::Region,
+    rekey::RekeyPolicy,
+    scheduler::RxConfig,
+};
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StaticKeys {
     pub as_static_material: [u8; 32],
-    pub ed_keys: Vec<EdKeys>, //ed_static_pk_material: [u8; 32]
+    pub ed_keys: Vec<EdKeys>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The static public key of an ED. It is stored as a `PeerKey`, and an entry which is malformed or
+/// holds secret material is refused when the keys are loaded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "PeerKey", into = "PeerKey")]
 pub struct EdKeys {
     pub kid: Vec<u8>,
-    pub ed_static_material: [u8; 32],
+    pub ed_public_key: [u8; 32],
+}
+
+impl TryFrom<PeerKey> for EdKeys {
+    type Error = String;
+
+    fn try_from(entry: PeerKey) -> Result<Self, String> {
+        let (kid, ed_public_key) = entry.verify()?;
+        Ok(EdKeys { kid, ed_public_key })
+    }
+}
+
+impl From<EdKeys> for PeerKey {
+    fn from(ed: EdKeys) -> Self {
+        PeerKey::new(&ed.kid, &ed.ed_public_key)
+    }
+}
+
+impl StaticKeys {
+    /// Checks that every kid is set and identifies one key only, that no key is left at zero, that
+    /// no ED entry holds our own key and that none holds the secret key of another ED, and returns
+    /// all the problems found.
+    ///
+    /// # Arguments
+    ///
+    /// * `as_kid` - The kid the EDs know our static key by
+    pub fn validate(&self, as_kid: &[u8]) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        if as_kid.is_empty() {
+            errors.push("The AS kid is empty".to_string());
+        }
+        if self.as_static_material == [0; 32] {
+            errors.push("The AS static key is all zeros".to_string());
+        }
+        let as_public_key = PublicKey::from(&StaticSecret::from(self.as_static_material));
+        for (i, ed) in self.ed_keys.iter().enumerate() {
+            if ed.kid.is_empty() {
+                errors.push(format!("ED key {:?} has an empty kid", i));
+            } else if ed.kid == as_kid {
+                errors.push(format!("ED kid {:?} is the kid of the AS", ed.kid));
+            } else if self.ed_keys[..i].iter().any(|other| other.kid == ed.kid) {
+                errors.push(format!("ED kid {:?} is used more than once", ed.kid));
+            }
+            let derived = derived_public_key(&ed.ed_public_key);
+            if ed.ed_public_key == self.as_static_material {
+                errors.push(format!(
+                    "ED kid {:?} holds the static secret of the AS",
+                    ed.kid
+                ));
+            } else if &ed.ed_public_key == as_public_key.as_bytes() {
+                errors.push(format!(
+                    "ED kid {:?} holds the public key of the AS",
+                    ed.kid
+                ));
+            } else if let Some((_, other)) = self
+                .ed_keys
+                .iter()
+                .enumerate()
+                .find(|(j, other)| *j != i && other.ed_public_key == derived)
+            {
+                errors.push(format!(
+                    "ED kid {:?} holds the secret key of ED kid {:?}",
+                    ed.kid, other.kid
+                ));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The lowest single-byte kid used by neither an ED nor the AS, `None` when all are taken.
+    ///
+    /// # Arguments
+    ///
+    /// * `as_kid` - The kid the EDs know our static key by
+    pub fn free_kid(&self, as_kid: &[u8]) -> Option<Vec<u8>> {
+        (0..=u8::MAX)
+            .map(|kid| vec![kid])
+            .find(|kid| kid != as_kid && self.ed_keys.iter().all(|ed| &ed.kid != kid))
+    }
+
+    /// Adds the static public key of a new ED.
+    ///
+    /// # Arguments
+    ///
+    /// * `kid` - The kid the ED identifies with, which no other ED or the AS may use
+    /// * `ed_public_key` - The static public key of the ED
+    /// * `as_kid` - The kid the EDs know our static key by
+    pub fn add_ed(
+        &mut self,
+        kid: Vec<u8>,
+        ed_public_key: [u8; 32],
+        as_kid: &[u8],
+    ) -> Result<(), String> {
+        if kid.is_empty() || kid == as_kid || self.ed_keys.iter().any(|ed| ed.kid == kid) {
+            return Err(format!("Kid {:?} is empty or already taken", kid));
+        }
+        self.ed_keys.push(EdKeys { kid, ed_public_key });
+        Ok(())
+    }
+
+    /// Removes the key of an ED, so it can no longer join.
+    ///
+    /// # Arguments
+    ///
+    /// * `kid` - The kid of the ED
+    pub fn revoke(&mut self, kid: &[u8]) -> Result<(), String> {
+        let before = self.ed_keys.len();
+        self.ed_keys.retain(|ed| ed.kid != kid);
+        if self.ed_keys.len() == before {
+            return Err(format!("No ED has kid {:?}", kid));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    /// Where the static keys of the AS and the EDs are stored
+    pub keys: String,
+    /// Where the schemas and scripts which decode the uplinks are stored, without it no uplink is
+    /// decoded
+    #[serde(default)]
+    pub decoders: Option<String>,
+    /// The kid the EDs know our static key by
+    pub as_kid: Vec<u8>,
+    /// The GPIO pin wired to the chip select of the sx1276
+    pub cs_pin: u8,
+    /// The GPIO pin wired to the reset of the sx1276
+    pub reset_pin: u8,
+    pub radio: RadioProfile,
+    /// The regional parameters to follow, without one we only use the frequency of the radio profile
+    pub region: Option<Region>,
+    /// The sub-band from 0 to 7 we listen on, only used in US915 and AU915
+    pub sub_band: Option<u8>,
+    /// How many ms we listen on an uplink channel of the region before hopping to the next
+    pub channel_dwell: i32,
+    /// Whether downlinks split into fragments get a parity fragment, which can replace any lost fragment
+    pub fragment_fec: bool,
+    /// The limits of the data rates and TX powers we assign to the EDs
+    pub adr: AdrConfig,
+    /// When we make EDs replace their key material on our own
+    pub rekey: RekeyPolicy,
+    /// When the EDs listen for our answers, which has to match their config
+    #[serde(default)]
+    pub rx: RxConfig,
 }
 
 /// Get the content from a text file
@@ -20,8 +179,8 @@ pub struct EdKeys {
 /// # Arguments
 ///
 /// * `path` - A string for where the file are located
-fn load_file(path: String) -> String {
-    fs::read_to_string(path).expect("Unable to read file")
+fn load_file(path: &str) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|x| format!("Unable to read {}: {}", path, x))
 }
 
 /// Convert a files content to a StaticKeys struct
@@ -29,8 +188,76 @@ fn load_file(path: String) -> String {
 /// # Arguments
 ///
 /// *  `path` - A string for where the file are located
-pub fn load_static_keys(path: String) -> StaticKeys {
-    let static_data = load_file(path);
-    let static_keys: StaticKeys = serde_json::from_str(&static_data).unwrap();
-    static_keys
+pub fn load_static_keys(path: &str) -> Result<StaticKeys, String> {
+    let static_data = load_file(path)?;
+    serde_json::from_str(&static_data).map_err(|x| format!("{}: {}", path, x))
+}
+
+/// Write a StaticKeys struct to a file, the way `load_static_keys` reads it
+///
+/// # Arguments
+///
+/// *  `path` - A string for where the file are located
+/// *  `static_keys` - The keys to store
+pub fn save_static_keys(path: &str, static_keys: &StaticKeys) -> Result<(), String> {
+    let static_data = serde_json::to_string_pretty(static_keys).map_err(|x| x.to_string())?;
+    fs::write(path, static_data).map_err(|x| format!("Unable to write {}: {}", path, x))
+}
+
+/// Convert the content of a TOML file to a Config struct
+///
+/// # Arguments
+///
+/// *  `path` - A string for where the file are located
+pub fn load_config(path: &str) -> Result<Config, String> {
+    let config_data = load_file(path)?;
+    toml::from_str(&config_data).map_err(|x| format!("{}: {}", path, x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer_key::derived_public_key;
+
+    const AS_KID: [u8; 1] = [0xa3];
+
+    fn keys(ed_keys: Vec<[u8; 32]>) -> StaticKeys {
+        StaticKeys {
+            as_static_material: [1; 32],
+            ed_keys: ed_keys
+                .into_iter()
+                .enumerate()
+                .map(|(i, ed_public_key)| EdKeys {
+                    kid: vec![i as u8],
+                    ed_public_key,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn accepts_distinct_public_keys() {
+        let ed_keys = vec![derived_public_key(&[2; 32]), derived_public_key(&[3; 32])];
+        assert_eq!(keys(ed_keys).validate(&AS_KID), Ok(()));
+    }
+
+    #[test]
+    fn refuses_the_secret_key_of_another_ed() {
+        let secret = [2; 32];
+        let errors = keys(vec![derived_public_key(&secret), secret])
+            .validate(&AS_KID)
+            .unwrap_err();
+        assert!(errors.contains(&"ED kid [1] holds the secret key of ED kid [0]".to_string()));
+    }
+
+    #[test]
+    fn refuses_our_own_keys() {
+        let as_public = derived_public_key(&[1; 32]);
+        let errors = keys(vec![[1; 32], as_public])
+            .validate(&AS_KID)
+            .unwrap_err();
+        assert_eq!(errors[0], "ED kid [0] holds the static secret of the AS");
+        assert_eq!(errors.len(), 2);
+        assert!(errors[1].starts_with("ED kid [1] holds the"));
+    }
 }
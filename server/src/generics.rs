@@ -1,12 +1,55 @@
-/// Converts a Vector to an array of a fixed size and length of the given Vector.
+/// Converts a Vector to an array of a fixed size and length of the given Vector. Anything beyond
+/// 255 bytes does not fit in a single frame and is cut off, larger frames have to be split with
+/// `fragment::split` first.
 ///
 /// # Arguments
 ///
 /// * `message` - The messages which we need to convert and get length
 pub fn get_message_lenght(message: Vec<u8>) -> ([u8; 255], usize) {
     let mut buffer = [0; 255];
-    for (i, byte) in message.iter().enumerate() {
-        buffer[i] = *byte;
+    for (slot, byte) in buffer.iter_mut().zip(message.iter()) {
+        *slot = *byte;
     }
-    (buffer, message.len())
+    (buffer, message.len().min(buffer.len()))
+}
+
+/// Returns the devaddr of an EDHOC message, which comes after the mtype and frame counter.
+///
+/// # Arguments
+///
+/// * `message` - The EDHOC message
+pub fn get_edhoc_devaddr(message: &[u8]) -> [u8; 4] {
+    message[3..7].try_into().unwrap()
+}
+
+/// Returns the devaddr of a LoRaRatchet message.
+///
+/// # Arguments
+///
+/// * `message` - The LoRaRatchet message
+pub fn get_ratchet_devaddr(message: &[u8]) -> [u8; 4] {
+    message[14..18].try_into().unwrap()
+}
+
+/// Returns the frame counter of a LoRaRatchet message, which is the first two bytes after the mtype.
+///
+/// # Arguments
+///
+/// * `message` - The LoRaRatchet message
+pub fn get_ratchet_fcnt(message: &[u8]) -> u16 {
+    u16::from_be_bytes([message[1], message[2]])
+}
+
+/// The mtype of the notification telling an ED we have no ratchet on its devaddr.
+pub const UNKNOWN_SESSION: u8 = 9;
+
+/// Creates the notification telling an ED we have no ratchet on its devaddr, so it should join again.
+///
+/// # Arguments
+///
+/// * `devaddr` - The devaddr we have no ratchet on
+pub fn prepare_unknown_session(devaddr: [u8; 4]) -> Vec<u8> {
+    let mut buffer = vec![UNKNOWN_SESSION];
+    buffer.extend_from_slice(&devaddr);
+    buffer
 }
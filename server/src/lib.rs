@@ -0,0 +1,28 @@
+//! The LoRaRatchet network server.
+//!
+//! The server logic lives in [`NetworkServer`], which consumes frames from a [`Transport`] and
+//! reports what happens through [`Event`]s. The `rasp_lora_server` binary is a thin wrapper around
+//! this, running it on a sx1276 module, but it can be embedded in custom gateways as well.
+//!
+//! The modules shared with the client, such as the regions, the duty cycle and the fragmentation,
+//! live in `rasp_lora_common` and are re-exported here.
+
+pub mod adr;
+pub mod decoders;
+pub mod edhoc;
+pub mod filehandler;
+pub mod generics;
+pub mod network_server;
+pub mod rekey;
+pub mod response_cache;
+pub mod scheduler;
+pub mod scripts;
+pub mod transport;
+
+pub use rasp_lora_common::{
+    airtime, codec, duty_cycle, fragment, mac, peer_key, port, radio, region,
+};
+
+pub use network_server::{Event, NetworkServer};
+pub use scheduler::{DownlinkTiming, Window};
+pub use transport::{Frame, FrameMeta, LoraTransport, Transport, TransportError};
@@ -1,31 +1,330 @@
 extern crate linux_embedded_hal as hal;
 extern crate sx127x_lora;
 
-use rand_core::OsRng;
-
-use oscore::edhoc::{api::Msg3Receiver, PartyR};
-
-use twoRatchet::AS::ASRatchet;
-
 use sx127x_lora::LoRa;
 
 use rppal::gpio::{Gpio, OutputPin};
 use rppal::hal::Delay;
 use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
 
-use std::collections::HashMap;
+use rasp_lora_server::{
+    adr::Adr,
+    decoders::Decoders,
+    filehandler::{self, Config, StaticKeys},
+    port::{self, ALARM_PORT},
+    rekey::Rekey,
+    Event, LoraTransport, NetworkServer, Window,
+};
+
+use clap::Parser;
+
+use serde_json::Value;
+
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
 
-mod edhoc;
-mod filehandler;
-mod generics;
+const ADMIN_USAGE: &str = "Usage: <dhr|rejoin> <devaddr as 8 hex digits|all>, or send <devaddr> <port> <data as hex|JSON object>";
 
-const LORA_CS_PIN: u8 = 8;
-const LORA_RESET_PIN: u8 = 22;
-const FREQUENCY: i64 = 915;
+/// A command typed into the server by an admin.
+enum AdminCommand {
+    /// Make an ED, or every ED when `None`, replace its key material
+    Rekey(Option<[u8; 4]>, Rekey),
+    /// Queue application data for an ED
+    Downlink {
+        devaddr: [u8; 4],
+        port: u8,
+        data: Vec<u8>,
+    },
+    /// Queue an object for an ED, encoded by its schema or script
+    DownlinkObject {
+        devaddr: [u8; 4],
+        port: u8,
+        object: Value,
+    },
+}
+
+/// The LoRaRatchet network server, running on a sx1276 module. Every setting of the config file
+/// given here can also be set by an environment variable, and an option overrides both.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// The TOML config file
+    #[arg(long, env = "LORA_CONFIG", default_value = "./config.toml")]
+    config: String,
+    /// The JSON file with the static keys of the AS and the EDs
+    #[arg(long, env = "LORA_KEYS")]
+    keys: Option<String>,
+    /// The JSON file with the schemas and scripts which decode the uplinks
+    #[arg(long, env = "LORA_DECODERS")]
+    decoders: Option<String>,
+    /// The kid the EDs know our static key by, as hex
+    #[arg(long, env = "LORA_AS_KID")]
+    as_kid: Option<String>,
+    /// The GPIO pin wired to the chip select of the sx1276
+    #[arg(long, env = "LORA_CS_PIN")]
+    cs_pin: Option<u8>,
+    /// The GPIO pin wired to the reset of the sx1276
+    #[arg(long, env = "LORA_RESET_PIN")]
+    reset_pin: Option<u8>,
+    /// The frequency in MHz
+    #[arg(long, env = "LORA_FREQUENCY")]
+    frequency: Option<i64>,
+    /// The signal bandwidth in Hz
+    #[arg(long, env = "LORA_BANDWIDTH")]
+    bandwidth: Option<i64>,
+    /// The spreading factor, from 6 to 12
+    #[arg(long, env = "LORA_SPREADING_FACTOR")]
+    spreading_factor: Option<u8>,
+    /// Validate the config, keys and decoders, and exit without touching the radio
+    #[arg(long)]
+    check_config: bool,
+}
 
 fn main() {
-    let lora = setup_sx127x(125000, 7);
-    main_loop(lora);
+    let args = Args::parse();
+    let (config, enc_keys, decoders) = match load(&args) {
+        Ok(loaded) => loaded,
+        Err(errors) => {
+            for error in errors {
+                println!("{}", error);
+            }
+            std::process::exit(1);
+        }
+    };
+    if args.check_config {
+        if config.decoders.is_none() {
+            println!("No decoders are set, uplinks will not be decoded");
+        }
+        println!("{} and the files it points to are valid", args.config);
+        return;
+    }
+    let spreading_factors = if config.adr.enabled {
+        config.adr.spreading_factors()
+    } else {
+        vec![config.radio.spreading_factor]
+    };
+    let lora = setup_sx127x(&config);
+    let mut transport = LoraTransport::new(
+        lora,
+        config.radio,
+        config.region,
+        config.sub_band,
+        spreading_factors,
+        config.channel_dwell,
+        config.fragment_fec,
+    );
+    transport.set_receive_windows(config.rx);
+    let mut server = NetworkServer::new(transport, enc_keys);
+    server.set_as_kid(config.as_kid);
+    if config.adr.enabled {
+        server.enable_adr(Adr::new(config.adr, config.radio, config.region));
+    }
+    server.set_rekey_policy(config.rekey);
+    server.set_default_downlink_timing(config.rx.into());
+    server.set_decoders(decoders);
+    server.on_event(print_event);
+    server.on_port(ALARM_PORT, print_alarm).unwrap();
+    let admin = read_admin_commands();
+    loop {
+        for command in admin.try_iter() {
+            match command {
+                AdminCommand::Rekey(Some(devaddr), rekey) => server.request_rekey(devaddr, rekey),
+                AdminCommand::Rekey(None, rekey) => server.request_rekey_all(rekey),
+                AdminCommand::Downlink {
+                    devaddr,
+                    port,
+                    data,
+                } => {
+                    if let Err(x) = server.queue_downlink(devaddr, port, data) {
+                        println!("{}", x);
+                    }
+                }
+                AdminCommand::DownlinkObject {
+                    devaddr,
+                    port,
+                    object,
+                } => {
+                    if let Err(x) = server.queue_downlink_object(devaddr, port, &object) {
+                        println!("{}", x);
+                    }
+                }
+            }
+        }
+        server.poll();
+    }
+}
+
+/// Loads the config file with the options and environment variables on top, and the keys and
+/// decoders it points to, and validates all of them. Returns every problem found.
+///
+/// # Arguments
+///
+/// * `args` - The options the server was started with
+fn load(args: &Args) -> Result<(Config, StaticKeys, Decoders), Vec<String>> {
+    let mut config = filehandler::load_config(&args.config).map_err(|x| vec![x])?;
+    let mut errors = Vec::new();
+    if let Some(keys) = &args.keys {
+        config.keys = keys.clone();
+    }
+    if let Some(decoders) = &args.decoders {
+        config.decoders = Some(decoders.clone());
+    }
+    if let Some(as_kid) = &args.as_kid {
+        match parse_hex(as_kid) {
+            Ok(as_kid) => config.as_kid = as_kid,
+            Err(x) => errors.push(format!("Invalid AS kid: {}", x)),
+        }
+    }
+    config.cs_pin = args.cs_pin.unwrap_or(config.cs_pin);
+    config.reset_pin = args.reset_pin.unwrap_or(config.reset_pin);
+    config.radio.frequency = args.frequency.unwrap_or(config.radio.frequency);
+    config.radio.bandwidth = args.bandwidth.unwrap_or(config.radio.bandwidth);
+    config.radio.spreading_factor = args
+        .spreading_factor
+        .unwrap_or(config.radio.spreading_factor);
+    if config.cs_pin == config.reset_pin {
+        errors.push(format!(
+            "The chip select and reset both use GPIO pin {:?}",
+            config.cs_pin
+        ));
+    }
+    if let Err(x) = config.radio.validate() {
+        errors.extend(x.iter().map(|x| format!("Invalid radio profile: {}", x)));
+    }
+    if let Some(region) = config.region {
+        if let Err(x) = region.channel_plan().validate(
+            config.radio.spreading_factor,
+            config.radio.bandwidth,
+            config.radio.tx_power,
+            config.sub_band,
+        ) {
+            errors.extend(
+                x.iter()
+                    .map(|x| format!("Radio profile not allowed: {}", x)),
+            );
+        }
+    }
+    if config.region.is_none() {
+        for window in [Window::Rx1, Window::Rx2] {
+            if let Err(x) = config.rx.window_profile(window, config.radio).validate() {
+                errors.extend(
+                    x.iter()
+                        .map(|x| format!("Invalid {:?} window: {}", window, x)),
+                );
+            }
+        }
+    }
+    if config.adr.enabled {
+        if let Err(x) = config.adr.validate() {
+            errors.extend(x.iter().map(|x| format!("Invalid ADR limits: {}", x)));
+        }
+    }
+    let keys = match filehandler::load_static_keys(&config.keys) {
+        Ok(keys) => {
+            if let Err(x) = keys.validate(&config.as_kid) {
+                errors.extend(x.iter().map(|x| format!("Invalid keys: {}", x)));
+            }
+            Some(keys)
+        }
+        Err(x) => {
+            errors.push(x);
+            None
+        }
+    };
+    let decoders = match config.decoders.as_deref().map(Decoders::load) {
+        Some(Ok(decoders)) => Some(decoders),
+        Some(Err(x)) => {
+            errors.push(format!("Invalid decoders: {}", x));
+            None
+        }
+        None => Some(Decoders::default()),
+    };
+    match (keys, decoders) {
+        (Some(keys), Some(decoders)) if errors.is_empty() => Ok((config, keys, decoders)),
+        _ => Err(errors),
+    }
+}
+
+/// Reads admin commands from stdin on a thread of their own, so they can be handled between frames.
+fn read_admin_commands() -> Receiver<AdminCommand> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            match parse_admin_command(&line) {
+                Ok(command) => {
+                    if sender.send(command).is_err() {
+                        return;
+                    }
+                }
+                Err(x) => println!("{}", x),
+            }
+        }
+    });
+    receiver
+}
+
+/// Parses hex digits into bytes.
+fn parse_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(format!("{:?} is not hex, two digits per byte", hex));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| format!("{:?} is not hex, two digits per byte", hex))
+        })
+        .collect()
+}
+
+/// Parses a devaddr written as 8 hex digits.
+fn parse_devaddr(hex: Option<&str>) -> Result<[u8; 4], String> {
+    parse_hex(hex.unwrap_or_default())
+        .ok()
+        .and_then(|devaddr| devaddr.try_into().ok())
+        .ok_or(ADMIN_USAGE.to_string())
+}
+
+/// Parses an admin command, like `dhr 01020304`, `rejoin all`, `send 01020304 3 0a` or
+/// `send 01020304 3 {"version": 10}`.
+///
+/// # Arguments
+///
+/// * `line` - The command
+fn parse_admin_command(line: &str) -> Result<AdminCommand, String> {
+    let mut words = line.split_whitespace();
+    let rekey = match words.next() {
+        Some("dhr") => Rekey::Dhr,
+        Some("rejoin") => Rekey::Rejoin,
+        Some("send") => {
+            let devaddr = parse_devaddr(words.next())?;
+            let port = words
+                .next()
+                .and_then(|port| port.parse().ok())
+                .ok_or(ADMIN_USAGE.to_string())?;
+            let data = words.collect::<Vec<_>>().join(" ");
+            if data.starts_with('{') {
+                let object = serde_json::from_str(&data).map_err(|x| x.to_string())?;
+                return Ok(AdminCommand::DownlinkObject {
+                    devaddr,
+                    port,
+                    object,
+                });
+            }
+            let data = parse_hex(&data).map_err(|_| ADMIN_USAGE.to_string())?;
+            return Ok(AdminCommand::Downlink {
+                devaddr,
+                port,
+                data,
+            });
+        }
+        _ => return Err(ADMIN_USAGE.to_string()),
+    };
+    match words.next() {
+        Some("all") => Ok(AdminCommand::Rekey(None, rekey)),
+        hex => Ok(AdminCommand::Rekey(Some(parse_devaddr(hex)?), rekey)),
+    }
 }
 
 /// This function creates a sx127x object, which enables us to send and recieve messages by
@@ -33,170 +332,132 @@ fn main() {
 ///
 /// # Arguments
 ///
-/// * `bandwith` - Sets the signal bandwith of the module. supported values are `800` Hz, `10400` Hz, `15600` Hz, `20800` Hz, `31250` Hz, `41700` Hz, `62500` Hz, `125000` Hz and `250000` Hz
-/// * `spreadfactor` - Sets the spreading factor of the radio. Supported values are between 6 and 12. If a spreading factor of 6 is set, implicit header mode must be used to transmit and receive packets.
-fn setup_sx127x(bandwidth: i64, spreadfactor: u8) -> LoRa<Spi, OutputPin, OutputPin> {
+/// * `config` - The pins the module is wired to, and the radio settings in `radio`. The supported signal bandwiths are `7800` Hz, `10400` Hz, `15600` Hz, `20800` Hz, `31250` Hz, `41700` Hz, `62500` Hz, `125000` Hz, `250000` Hz and `500000` Hz.
+///   The spreading factor must be between 6 and 12. If a spreading factor of 6 is set, implicit header mode must be used to transmit and receive packets.
+fn setup_sx127x(config: &Config) -> LoRa<Spi, OutputPin, OutputPin> {
     let spi = Spi::new(Bus::Spi0, SlaveSelect::Ss0, 8_000_000, Mode::Mode0).unwrap();
 
     let gpio = Gpio::new().unwrap();
 
-    let cs = gpio.get(LORA_CS_PIN).unwrap().into_output();
-    let reset = gpio.get(LORA_RESET_PIN).unwrap().into_output();
+    let cs = gpio.get(config.cs_pin).unwrap().into_output();
+    let reset = gpio.get(config.reset_pin).unwrap().into_output();
 
-    let mut lora = sx127x_lora::LoRa::new(spi, cs, reset, FREQUENCY, &mut Delay).unwrap();
-    let _ = lora.set_signal_bandwidth(bandwidth);
-    let _ = lora.set_spreading_factor(spreadfactor);
+    let mut lora =
+        sx127x_lora::LoRa::new(spi, cs, reset, config.radio.frequency, &mut Delay).unwrap();
+    config.radio.apply(&mut lora);
     lora
 }
 
-/// Starting the server application.
-/// This function handles all the logic behind listening & recieving messages.
+/// Prints the alarms of the EDs, which are routed here on top of `print_event`.
 ///
 /// # Arguments
 ///
-/// * `lora` - Takes a sx127x lora module object
-fn main_loop(mut lora: LoRa<Spi, OutputPin, OutputPin>) {
-    // load keys
-    let enc_keys: filehandler::StaticKeys =
-        filehandler::load_static_keys("./keys.json".to_string());
-
-    // Creating two hashmaps, outside the loop to ensure they are no overwritten on each iteration
-    // We do this to make the server function more advanced such it can handle multiple clients at a time
-    // and access the correct data based on the clients devaddr.
-    let mut msg3_receivers: HashMap<[u8; 4], PartyR<Msg3Receiver>> = HashMap::new();
-    let mut lora_ratchets: HashMap<[u8; 4], ASRatchet<OsRng>> = HashMap::new();
-    let mut ratchet_recieved: HashMap<[u8; 4], u16> = HashMap::new();
-    loop {
-        let poll = lora.poll_irq(None, &mut Delay); //30 Second timeout
-        match poll {
-            Ok(size) => {
-                println!("Recieved packet with size: {:?}", size);
-                let buffer = lora.read_packet().unwrap(); // Received buffer. NOTE: 255 bytes are always returned
-                match buffer[0] {
-                    0 => {
-                        println!("Recieved m type 0");
-                        let rtn = edhoc::handle_m_type_zero(
-                            buffer,
-                            msg3_receivers,
-                            lora,
-                            enc_keys.as_static_material,
-                        );
-                        msg3_receivers = rtn.msg3_receivers;
-                        lora = rtn.lora;
-                    }
-                    2 => {
-                        println!("Recieved m type 2");
-                        let rtn = edhoc::handle_m_type_two(
-                            buffer,
-                            msg3_receivers,
-                            lora_ratchets,
-                            lora,
-                            ratchet_recieved,
-                        );
-                        msg3_receivers = rtn.msg3_receivers;
-                        lora_ratchets = rtn.lora_ratchets;
-                        lora = rtn.lora;
-                        ratchet_recieved = rtn.ratchet_recieved;
-                    }
-                    5 => {
-                        println!("Recieved m type 5");
-                        let incoming = &buffer;
-                        let rtn = handle_ratchet_message(
-                            incoming.to_vec(),
-                            lora,
-                            lora_ratchets,
-                            ratchet_recieved,
-                        );
-                        lora = rtn.lora;
-                        lora_ratchets = rtn.lora_ratchets;
-                        ratchet_recieved = rtn.ratchet_recieved;
-                    }
-                    7 => {
-                        println!("Recieved m type 7");
-                        let incoming = &buffer;
-                        let rtn = handle_ratchet_message(
-                            incoming.to_vec(),
-                            lora,
-                            lora_ratchets,
-                            ratchet_recieved,
-                        );
-                        lora = rtn.lora;
-                        lora_ratchets = rtn.lora_ratchets;
-                        ratchet_recieved = rtn.ratchet_recieved;
-                    }
-                    _ => {
-                        println!("Recieved m type _");
-                    }
-                }
-            }
-            Err(_) => println!("Timeout"),
+/// * `event` - The uplink on the alarm port
+fn print_alarm(event: &Event) {
+    if let Event::Uplink {
+        devaddr,
+        payload,
+        object,
+        ..
+    } = event
+    {
+        match object {
+            Some(object) => println!("ALARM from devaddr {:?}: {}", devaddr, object),
+            None => println!("ALARM from devaddr {:?}: {:?}", devaddr, payload),
         }
     }
 }
 
-struct RatchetMessage {
-    lora: LoRa<Spi, OutputPin, OutputPin>,
-    lora_ratchets: HashMap<[u8; 4], ASRatchet<OsRng>>,
-    ratchet_recieved: HashMap<[u8; 4], u16>,
-}
-
-/// This function handles the incomming ratchet messages, this includes decrypting, and checking if
-/// we would need to perform a DHR, to update our keys.
+/// Prints the events from the network server.
 ///
 /// # Arguments
 ///
-/// * `buffer` - The recieved LoRaRatchet message.
-/// * `lora` - Takes a sx127x lora module object.
-/// * `lora_ratchet` - A hashmap containing all the ASRatchets.
-/// * `ratchet_recieved` - This is a debug hashmap. It contains the amount of messages recieved based on the devaddr
-fn handle_ratchet_message(
-    buffer: Vec<u8>,
-    mut lora: LoRa<Spi, OutputPin, OutputPin>,
-    mut lora_ratchets: HashMap<[u8; 4], ASRatchet<OsRng>>,
-    mut ratchet_recieved: HashMap<[u8; 4], u16>,
-) -> RatchetMessage {
-    let incoming = &buffer;
-    let devaddr: [u8; 4] = buffer[14..18].try_into().unwrap();
-    let ratchet = lora_ratchets.remove(&devaddr);
-    match ratchet {
-        Some(mut lora_ratchet) => {
-            let mut message_recieved = ratchet_recieved.remove(&devaddr).unwrap();
-            message_recieved += 1;
-            println!(
-                "Recieved #{:?} messages on the following devaddr {:?}",
-                message_recieved, devaddr
-            );
-            ratchet_recieved.insert(devaddr, message_recieved);
-            let (newout, sendnew) = match lora_ratchet.receive(incoming.to_vec()) {
-                Ok((x, b)) => (x, b),
-                Err(x) => {
-                    println!("error has happened {:?}", incoming);
-                    println!("Error message {:?}", x);
-                    lora_ratchets.insert(devaddr, lora_ratchet);
-                    return RatchetMessage {
-                        lora,
-                        lora_ratchets,
-                        ratchet_recieved,
-                    };
-                }
-            };
-            if sendnew {
-                let (msg_buffer, len) = generics::get_message_lenght(newout);
-                let transmit = lora.transmit_payload_busy(msg_buffer, len);
-                match transmit {
-                    Ok(packet_size) => {
-                        println!("Sent packet with size: {:?}", packet_size)
-                    }
-                    Err(_) => println!("Error"),
-                }
-            }
-            lora_ratchets.insert(devaddr, lora_ratchet);
+/// * `event` - The event which happened
+fn print_event(event: &Event) {
+    match event {
+        Event::JoinRequested {
+            devaddr,
+            deveui,
+            appeui,
+        } => println!(
+            "Join requested by DevEUI {:?} of AppEUI {:?}, assigned devaddr {:?}",
+            deveui, appeui, devaddr
+        ),
+        Event::Joined { devaddr, kid } => {
+            println!("Joined devaddr {:?} with kid {:?}", devaddr, kid)
         }
-        None => println!("No ratchet on this devaddr"),
-    }
-    RatchetMessage {
-        lora,
-        lora_ratchets,
-        ratchet_recieved,
+        Event::Uplink {
+            devaddr,
+            port,
+            payload,
+            fcnt,
+            confirmed,
+            object,
+            meta,
+        } => match object {
+            Some(object) => println!(
+                "Uplink #{:?} from {:?} on the {} port {:?}, confirmed {:?}: {}, RSSI {:?} dBm, SNR {:?} dB",
+                fcnt,
+                devaddr,
+                port::name(*port),
+                port,
+                confirmed,
+                object,
+                meta.rssi,
+                meta.snr
+            ),
+            None => println!(
+                "Uplink #{:?} from {:?} on the {} port {:?}, confirmed {:?}: {:?}, RSSI {:?} dBm, SNR {:?} dB",
+                fcnt,
+                devaddr,
+                port::name(*port),
+                port,
+                confirmed,
+                payload,
+                meta.rssi,
+                meta.snr
+            ),
+        },
+        Event::DataRateChanged {
+            devaddr,
+            spreading_factor,
+            tx_power,
+        } => println!(
+            "Asked devaddr {:?} to use SF{:?} at {:?} dBm",
+            devaddr, spreading_factor, tx_power
+        ),
+        Event::MacAnswered {
+            devaddr,
+            cid,
+            accepted,
+        } => println!(
+            "Devaddr {:?} answered MAC command {:?}, accepted: {:?}",
+            devaddr, cid, accepted
+        ),
+        Event::Status { devaddr, status } => {
+            println!("Status of devaddr {:?}: {:?}", devaddr, status)
+        }
+        Event::RekeyRequested { devaddr, rekey } => {
+            println!("Asked devaddr {:?} for a {:?}", devaddr, rekey)
+        }
+        Event::DhrCompleted { devaddr } => println!("DHR completed on devaddr {:?}", devaddr),
+        Event::DecodeFailed {
+            devaddr,
+            port,
+            reason,
+        } => println!(
+            "Unable to decode the uplink of devaddr {:?} on port {:?}: {}",
+            devaddr, port, reason
+        ),
+        Event::DecryptFailed { devaddr, reason } => {
+            println!("Decryption failed on devaddr {:?}: {}", devaddr, reason)
+        }
+        Event::DownlinkDropped { devaddr } => {
+            println!("Dropped a late downlink to devaddr {:?}", devaddr)
+        }
+        Event::SessionDropped { devaddr } => {
+            println!("Dropped the session on devaddr {:?}", devaddr)
+        }
+        Event::Error(x) => println!("Error {}", x),
     }
-}
\ No newline at end of file
+}
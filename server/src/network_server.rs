@@ -0,0 +1,1167 @@
+use rand_core::OsRng;
+
+use oscore::edhoc::{api::Msg3Receiver, PartyR};
+
+use twoRatchet::AS::ASRatchet;
+
+use std::collections::{hash_map::DefaultHasher, HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use crate::{
+    adr::Adr,
+    airtime::required_snr,
+    decoders::Decoders,
+    edhoc::{self, EdhocError},
+    filehandler::StaticKeys,
+    generics::{get_edhoc_devaddr, get_ratchet_devaddr, get_ratchet_fcnt, prepare_unknown_session},
+    mac::{self, DeviceStatus, MacCommand, LINK_ADR_REQ, STATUS_REQ},
+    port::{self, MAC_PORT, MAX_PORT},
+    rekey::{KeyAge, Rekey, RekeyPolicy},
+    response_cache::ResponseCache,
+    scheduler::{DownlinkScheduler, DownlinkTiming},
+    transport::{Frame, FrameMeta, Transport, TransportError},
+};
+
+/// Everything which can happen on the network, reported to the callbacks registered with
+/// `NetworkServer::on_event`.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// An ED sent the first EDHOC message with its DevEUI and AppEUI, and got assigned `devaddr`.
+    JoinRequested {
+        devaddr: [u8; 4],
+        deveui: Vec<u8>,
+        appeui: Vec<u8>,
+    },
+    /// An ED finished the EDHOC handshake, and now has a ratchet on `devaddr`.
+    Joined { devaddr: [u8; 4], kid: Vec<u8> },
+    /// A decrypted uplink with application data from an ED, and how we received it. A `confirmed`
+    /// uplink is acknowledged in the receive window of the ED. Besides every callback, the callbacks
+    /// registered with `NetworkServer::on_port` for its port are called.
+    Uplink {
+        devaddr: [u8; 4],
+        port: u8,
+        payload: Vec<u8>,
+        fcnt: u16,
+        confirmed: bool,
+        /// The payload as a JSON object, if the ED has a decoder on the port
+        object: Option<Value>,
+        meta: FrameMeta,
+    },
+    /// We asked an ED to change its data rate and TX power.
+    DataRateChanged {
+        devaddr: [u8; 4],
+        spreading_factor: u8,
+        tx_power: i32,
+    },
+    /// An ED answered the MAC command with identifier `cid`, and whether it applied it.
+    MacAnswered {
+        devaddr: [u8; 4],
+        cid: u8,
+        accepted: bool,
+    },
+    /// An ED reported its status, after we queued a `MacCommand::StatusReq`.
+    Status {
+        devaddr: [u8; 4],
+        status: DeviceStatus,
+    },
+    /// We asked an ED to replace its key material, by policy or through `NetworkServer::request_rekey`.
+    RekeyRequested { devaddr: [u8; 4], rekey: Rekey },
+    /// An ED performed a Diffie-Hellman ratchet step with us.
+    DhrCompleted { devaddr: [u8; 4] },
+    /// The decoder of the ED could not decode its uplink on `port`, the uplink is emitted without an object.
+    DecodeFailed {
+        devaddr: [u8; 4],
+        port: u8,
+        reason: String,
+    },
+    /// A LoRaRatchet message could not be decrypted.
+    DecryptFailed { devaddr: [u8; 4], reason: String },
+    /// Our answer to an ED was dropped, because both its receive windows had closed before it was ready,
+    /// or the duty cycle or dwell time of the region did not allow sending it.
+    DownlinkDropped { devaddr: [u8; 4] },
+    /// We lost sync with the ratchet of an ED, and told it to join again.
+    SessionDropped { devaddr: [u8; 4] },
+    /// Something went wrong, which is not tied to a specific ratchet message.
+    Error(String),
+}
+
+/// How long we keep answering retransmitted EDHOC messages, DHR requests and confirmed uplinks with
+/// our cached response.
+const RESPONSE_CACHE_LIFETIME: Duration = Duration::from_secs(60);
+
+/// After this many LoRaRatchet messages in a row we could not decrypt, within
+/// `DECRYPT_FAILURE_WINDOW`, we consider the session desynchronized and drop it.
+const MAX_DECRYPT_FAILURES: u32 = 5;
+
+/// The window the decrypt failures of a session are counted in. Anyone can send frames with the
+/// devaddr of an ED, so failures spread out over a longer time do not end a session.
+const DECRYPT_FAILURE_WINDOW: Duration = Duration::from_secs(600);
+
+/// How many of the latest receptions we keep per devaddr.
+const RECEPTION_HISTORY: usize = 20;
+
+/// The kid the EDs know our static key by, unless `set_as_kid` says otherwise.
+pub const DEFAULT_AS_KID: [u8; 1] = [0xA3];
+
+/// An answer waiting for the receive window of its ED.
+struct PendingDownlink {
+    at: Instant,
+    devaddr: Option<[u8; 4]>,
+    msg: Vec<u8>,
+    meta: FrameMeta,
+}
+
+/// A callback registered with `NetworkServer::on_event`.
+pub type EventCallback = Box<dyn FnMut(&Event)>;
+
+/// The network server, it keeps track of the EDHOC handshakes and ratchets of all the EDs, based
+/// on their devaddr.
+pub struct NetworkServer<T: Transport> {
+    transport: T,
+    enc_keys: StaticKeys,
+    as_kid: Vec<u8>,
+    msg3_receivers: HashMap<[u8; 4], PartyR<Msg3Receiver>>,
+    /// When we answered the first EDHOC message of each handshake which has not completed yet
+    pending_joins: HashMap<[u8; 4], Instant>,
+    lora_ratchets: HashMap<[u8; 4], ASRatchet<OsRng>>,
+    ratchet_recieved: HashMap<[u8; 4], u16>,
+    /// The decrypt failures of each session, and when the first of them happened
+    decrypt_failures: HashMap<[u8; 4], (u32, Instant)>,
+    /// The frame counter of the latest uplink we decrypted of each session
+    uplink_fcnts: HashMap<[u8; 4], u16>,
+    receptions: HashMap<[u8; 4], VecDeque<FrameMeta>>,
+    adr: Option<Adr>,
+    mac_queues: HashMap<[u8; 4], VecDeque<MacCommand>>,
+    key_ages: HashMap<[u8; 4], KeyAge>,
+    rekey_policy: RekeyPolicy,
+    msg2_cache: ResponseCache<u64>,
+    msg4_cache: ResponseCache<[u8; 4]>,
+    dhr_cache: ResponseCache<[u8; 4]>,
+    confirmed_cache: ResponseCache<[u8; 4]>,
+    downlink_queues: HashMap<[u8; 4], VecDeque<(u8, Vec<u8>)>>,
+    euis: HashMap<[u8; 4], (Vec<u8>, Vec<u8>)>,
+    decoders: Decoders,
+    scheduler: DownlinkScheduler,
+    /// The answers waiting for their receive window, the first one due first
+    pending_downlinks: VecDeque<PendingDownlink>,
+    callbacks: Vec<EventCallback>,
+    routes: HashMap<u8, Vec<EventCallback>>,
+}
+
+impl<T: Transport> NetworkServer<T> {
+    /// Creates a network server without any EDs joined.
+    ///
+    /// # Arguments
+    ///
+    /// * `transport` - Where frames are received from and transmitted with
+    /// * `enc_keys` - Our static key material, and the static keys of the EDs we know
+    pub fn new(transport: T, enc_keys: StaticKeys) -> Self {
+        NetworkServer {
+            transport,
+            enc_keys,
+            as_kid: DEFAULT_AS_KID.to_vec(),
+            msg3_receivers: HashMap::new(),
+            pending_joins: HashMap::new(),
+            lora_ratchets: HashMap::new(),
+            ratchet_recieved: HashMap::new(),
+            decrypt_failures: HashMap::new(),
+            uplink_fcnts: HashMap::new(),
+            receptions: HashMap::new(),
+            adr: None,
+            mac_queues: HashMap::new(),
+            key_ages: HashMap::new(),
+            rekey_policy: RekeyPolicy::default(),
+            msg2_cache: ResponseCache::new(RESPONSE_CACHE_LIFETIME),
+            msg4_cache: ResponseCache::new(RESPONSE_CACHE_LIFETIME),
+            dhr_cache: ResponseCache::new(RESPONSE_CACHE_LIFETIME),
+            confirmed_cache: ResponseCache::new(RESPONSE_CACHE_LIFETIME),
+            downlink_queues: HashMap::new(),
+            euis: HashMap::new(),
+            decoders: Decoders::default(),
+            scheduler: DownlinkScheduler::new(DownlinkTiming::default()),
+            pending_downlinks: VecDeque::new(),
+            callbacks: Vec::new(),
+            routes: HashMap::new(),
+        }
+    }
+
+    /// Registers a callback, which is called with every event the server emits.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - The function to call
+    pub fn on_event<F: FnMut(&Event) + 'static>(&mut self, callback: F) {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    /// Registers a callback, which is called with the uplinks of every ED on `port`, e.g. to hand
+    /// alarms to a different integration than telemetry. The MAC port is reserved for the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The port to route, from 1 up to `MAX_PORT`
+    /// * `callback` - The function to call with the `Event::Uplink`s on the port
+    pub fn on_port<F: FnMut(&Event) + 'static>(
+        &mut self,
+        port: u8,
+        callback: F,
+    ) -> Result<(), String> {
+        if port == MAC_PORT || port > MAX_PORT {
+            return Err(format!("Port {:?} can not be routed", port));
+        }
+        self.routes
+            .entry(port)
+            .or_default()
+            .push(Box::new(callback));
+        Ok(())
+    }
+
+    /// Sets the schemas and scripts which decode the uplinks of the EDs into JSON objects and encode
+    /// the objects queued for them, replacing the ones set before. Without them no uplink is decoded.
+    ///
+    /// # Arguments
+    ///
+    /// * `decoders` - The schemas and scripts, and which devices and applications use them
+    pub fn set_decoders(&mut self, decoders: Decoders) {
+        self.decoders = decoders;
+    }
+
+    /// Queues application data for an ED. Queued data is sent after an uplink of the ED, when there
+    /// are no MAC commands to send, one frame per uplink.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED
+    /// * `port` - The port to send the data on, from 1 up to `MAX_PORT`
+    /// * `data` - The application data
+    pub fn queue_downlink(
+        &mut self,
+        devaddr: [u8; 4],
+        port: u8,
+        data: Vec<u8>,
+    ) -> Result<(), String> {
+        if port == MAC_PORT || port > MAX_PORT {
+            return Err(format!("Port {:?} can not carry application data", port));
+        }
+        self.downlink_queues
+            .entry(devaddr)
+            .or_default()
+            .push_back((port, data));
+        Ok(())
+    }
+
+    /// Encodes a JSON object with the schema or script of the ED on `port`, and queues it like
+    /// `queue_downlink`.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED
+    /// * `port` - The port to send the object on, from 1 up to `MAX_PORT`
+    /// * `object` - The object to encode
+    pub fn queue_downlink_object(
+        &mut self,
+        devaddr: [u8; 4],
+        port: u8,
+        object: &Value,
+    ) -> Result<(), String> {
+        let (deveui, appeui) = self
+            .euis
+            .get(&devaddr)
+            .ok_or(format!("Devaddr {:?} has not joined", devaddr))?;
+        let data = self
+            .decoders
+            .encode(deveui, appeui, port, object)
+            .ok_or(format!(
+                "Devaddr {:?} has no schema or script on port {:?}",
+                devaddr, port
+            ))??;
+        self.queue_downlink(devaddr, port, data)
+    }
+
+    /// Sets when the EDs without a timing of their own listen for our answers, by default the timing
+    /// of the config shipped with the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `timing` - When the EDs listen
+    pub fn set_default_downlink_timing(&mut self, timing: DownlinkTiming) {
+        self.scheduler.set_default_timing(timing);
+    }
+
+    /// Sets when an ED listens for our answers, EDs without a timing of their own use the default.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED
+    /// * `timing` - When the ED listens
+    pub fn set_downlink_timing(&mut self, devaddr: [u8; 4], timing: DownlinkTiming) {
+        self.scheduler.set_timing(devaddr, timing);
+    }
+
+    /// Gives access to the downlink scheduler, e.g. for reading how many downlinks were late.
+    pub fn scheduler(&self) -> &DownlinkScheduler {
+        &self.scheduler
+    }
+
+    /// The latest receptions from an ED, oldest first, e.g. for coverage analysis or adapting its
+    /// data rate.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED
+    pub fn receptions(&self, devaddr: &[u8; 4]) -> Option<&VecDeque<FrameMeta>> {
+        self.receptions.get(devaddr)
+    }
+
+    /// Lets the server adapt the data rate and TX power of the EDs, based on how well we receive them.
+    /// The transport has to listen on every spreading factor `adr` may assign.
+    ///
+    /// # Arguments
+    ///
+    /// * `adr` - The ADR state, holding its limits
+    pub fn enable_adr(&mut self, adr: Adr) {
+        self.adr = Some(adr);
+    }
+
+    /// Queues a MAC command for an ED. Queued commands are sent after every uplink of the ED, until
+    /// it answers them. A queued command with the same identifier is replaced.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED
+    /// * `command` - The command to send
+    pub fn queue_mac_command(&mut self, devaddr: [u8; 4], command: MacCommand) {
+        let queue = self.mac_queues.entry(devaddr).or_default();
+        match queue
+            .iter_mut()
+            .find(|queued| queued.cid() == command.cid())
+        {
+            Some(queued) => *queued = command,
+            None => queue.push_back(command),
+        }
+    }
+
+    /// The MAC commands waiting for an answer from an ED, oldest first.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED
+    pub fn mac_queue(&self, devaddr: &[u8; 4]) -> Option<&VecDeque<MacCommand>> {
+        self.mac_queues.get(devaddr)
+    }
+
+    /// Sets the kid we identify our static key with in the EDHOC handshake, which the EDs look
+    /// our key up by.
+    ///
+    /// # Arguments
+    ///
+    /// * `kid` - Our kid
+    pub fn set_as_kid(&mut self, kid: Vec<u8>) {
+        self.as_kid = kid;
+    }
+
+    /// Sets when we make EDs replace their key material on our own, by default we never do.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The maximum ages of the key material
+    pub fn set_rekey_policy(&mut self, policy: RekeyPolicy) {
+        self.rekey_policy = policy;
+    }
+
+    /// When an ED joined and last completed a DH step, `None` if it has no session.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED
+    pub fn key_age(&self, devaddr: &[u8; 4]) -> Option<&KeyAge> {
+        self.key_ages.get(devaddr)
+    }
+
+    /// Asks an ED to replace its key material, with a DH step or a new handshake. The request is
+    /// queued as a MAC command, and sent after the next uplink of the ED.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED
+    /// * `rekey` - How the key material should be replaced
+    pub fn request_rekey(&mut self, devaddr: [u8; 4], rekey: Rekey) {
+        let command = match rekey {
+            Rekey::Dhr => MacCommand::DhrReq,
+            Rekey::Rejoin => MacCommand::Rejoin,
+        };
+        self.queue_mac_command(devaddr, command);
+        self.emit(Event::RekeyRequested { devaddr, rekey });
+    }
+
+    /// Asks every ED with a session to replace its key material, e.g. after a key was compromised.
+    ///
+    /// # Arguments
+    ///
+    /// * `rekey` - How the key material should be replaced
+    pub fn request_rekey_all(&mut self, rekey: Rekey) {
+        let devaddrs: Vec<[u8; 4]> = self.lora_ratchets.keys().copied().collect();
+        for devaddr in devaddrs {
+            self.request_rekey(devaddr, rekey);
+        }
+    }
+
+    /// Gives access to the transport, e.g. for reconfiguring the radio.
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Receives and handles frames forever.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.poll();
+        }
+    }
+
+    /// Waits for a single frame from the transport and handles it. The wait ends early when one of
+    /// our answers is due, and the answers which are due are sent before and after the frame.
+    pub fn poll(&mut self) {
+        self.transmit_due_downlinks();
+        let deadline = self.pending_downlinks.front().map(|pending| pending.at);
+        match self.transport.receive(deadline) {
+            Ok(frame) => self.handle_frame(frame),
+            // A transport hopping over channels times out on every hop, which is nothing to report
+            Err(TransportError::Timeout) => (),
+            Err(TransportError::Radio(x)) => self.emit(Event::Error(x)),
+            Err(x) => self.emit(Event::Error(format!("Receive failed {:?}", x))),
+        }
+        self.transmit_due_downlinks();
+    }
+
+    /// Handles a single frame, based on its mtype.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - The recieved frame
+    pub fn handle_frame(&mut self, frame: Frame) {
+        let Frame {
+            payload: buffer,
+            meta,
+        } = frame;
+        if buffer.is_empty() {
+            self.emit(Event::Error("Recieved an empty frame".to_string()));
+            return;
+        }
+        match buffer[0] {
+            0 => {
+                println!("Recieved m type 0");
+                self.handle_m_type_zero(buffer, meta);
+            }
+            2 => {
+                println!("Recieved m type 2");
+                self.handle_m_type_two(buffer, meta);
+            }
+            5 | 7 => {
+                println!("Recieved m type {:?}", buffer[0]);
+                self.handle_ratchet_message(buffer, meta);
+            }
+            _ => {
+                println!("Recieved m type _");
+            }
+        }
+    }
+
+    /// Stores how we received a frame from an ED, keeping only the latest receptions.
+    fn record_reception(&mut self, devaddr: [u8; 4], meta: FrameMeta) {
+        let receptions = self.receptions.entry(devaddr).or_default();
+        if receptions.len() == RECEPTION_HISTORY {
+            receptions.pop_front();
+        }
+        receptions.push_back(meta);
+    }
+
+    fn emit(&mut self, event: Event) {
+        for callback in self.callbacks.iter_mut() {
+            callback(&event);
+        }
+        if let Event::Uplink { port, .. } = event {
+            if let Some(routes) = self.routes.get_mut(&port) {
+                for callback in routes.iter_mut() {
+                    callback(&event);
+                }
+            }
+        }
+    }
+
+    /// Queues our answer to an uplink for the start of the first receive window of the ED which is
+    /// still open, `poll` transmits it once it is due. If both windows have closed the answer is
+    /// dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED, `None` if it has not been assigned one yet
+    /// * `msg` - Our answer
+    /// * `meta` - When and on which frequency we received the uplink we are answering
+    fn send(&mut self, devaddr: Option<[u8; 4]>, msg: Vec<u8>, meta: FrameMeta) {
+        match self.scheduler.schedule(devaddr, meta.received) {
+            Some((_, at)) => self.queue_pending(PendingDownlink {
+                at,
+                devaddr,
+                msg,
+                meta,
+            }),
+            None => self.late_downlink(devaddr),
+        }
+    }
+
+    fn queue_pending(&mut self, pending: PendingDownlink) {
+        let index = self
+            .pending_downlinks
+            .iter()
+            .position(|queued| queued.at > pending.at)
+            .unwrap_or(self.pending_downlinks.len());
+        self.pending_downlinks.insert(index, pending);
+    }
+
+    fn late_downlink(&mut self, devaddr: Option<[u8; 4]>) {
+        println!("Too late for both receive windows, dropping downlink");
+        if let Some(devaddr) = devaddr {
+            self.emit(Event::DownlinkDropped { devaddr });
+        }
+    }
+
+    /// Transmits the queued answers which are due. An answer which became due while we were
+    /// receiving is scheduled again, since the window it was meant for may have closed.
+    fn transmit_due_downlinks(&mut self) {
+        while let Some(pending) = self.pending_downlinks.front() {
+            if pending.at > Instant::now() {
+                return;
+            }
+            let PendingDownlink {
+                devaddr, msg, meta, ..
+            } = self.pending_downlinks.pop_front().unwrap();
+            let window = match self.scheduler.schedule(devaddr, meta.received) {
+                Some((window, at)) if at <= Instant::now() => window,
+                Some((_, at)) => {
+                    self.queue_pending(PendingDownlink {
+                        at,
+                        devaddr,
+                        msg,
+                        meta,
+                    });
+                    continue;
+                }
+                None => {
+                    self.late_downlink(devaddr);
+                    continue;
+                }
+            };
+            println!("Sending downlink in {:?}", window);
+            match self.transport.transmit(msg, window, &meta) {
+                Ok(packet_size) => {
+                    println!("Sent packet with size: {:?}", packet_size)
+                }
+                Err(TransportError::Gate(x)) => {
+                    println!("Not allowed to transmit, dropping downlink: {:?}", x);
+                    if let Some(devaddr) = devaddr {
+                        self.emit(Event::DownlinkDropped { devaddr });
+                    }
+                }
+                Err(x) => self.emit(Event::Error(format!("Transmit failed {:?}", x))),
+            }
+        }
+    }
+
+    /// Reports an EDHOC error, and sends our own errors back to the ED.
+    fn edhoc_error(
+        &mut self,
+        mtype: u8,
+        devaddr: Option<[u8; 4]>,
+        error: EdhocError,
+        meta: FrameMeta,
+    ) {
+        match error {
+            EdhocError::Own(x) => {
+                self.send(devaddr, x, meta);
+                self.emit(Event::Error(format!("Own error in m_type {:?}", mtype)));
+            }
+            EdhocError::Peer(x) => {
+                self.emit(Event::Error(format!("Error in m_type {:?} {:?}", mtype, x)))
+            }
+            EdhocError::UnknownDevaddr(devaddr) => self.emit(Event::Error(format!(
+                "No handshake in progress on the devaddr {:?}",
+                devaddr
+            ))),
+            EdhocError::UnknownKid(kid) => {
+                self.emit(Event::Error(format!("Missing kid value {:?}", kid)))
+            }
+            EdhocError::TooShort(buffer) => self.emit(Event::Error(format!(
+                "EDHOC message too short {:?}",
+                buffer
+            ))),
+        }
+    }
+
+    /// Handles the first EDHOC message. If it is a retransmission of a message we already answered,
+    /// the cached second message is sent again instead of starting a new handshake.
+    fn handle_m_type_zero(&mut self, buffer: Vec<u8>, meta: FrameMeta) {
+        let mut hasher = DefaultHasher::new();
+        buffer.hash(&mut hasher);
+        let key = hasher.finish();
+        if let Some(msg2) = self.msg2_cache.lookup(&key, &buffer) {
+            println!("Recieved duplicate m type 0, resending m type 1");
+            // The devaddr we assigned comes right after the mtype of the second message
+            let devaddr = msg2[1..5].try_into().unwrap();
+            self.record_reception(devaddr, meta);
+            self.send(Some(devaddr), msg2, meta);
+            return;
+        }
+        self.prune_pending_joins();
+        let as_static_material = self.enc_keys.as_static_material;
+        match edhoc::handle_m_type_zero(
+            buffer.clone(),
+            &mut self.msg3_receivers,
+            as_static_material,
+            &self.as_kid,
+        ) {
+            Ok(rtn) => {
+                self.msg2_cache.insert(key, buffer, rtn.msg2.clone());
+                self.record_reception(rtn.devaddr, meta);
+                self.send(Some(rtn.devaddr), rtn.msg2, meta);
+                self.pending_joins.insert(rtn.devaddr, Instant::now());
+                self.euis
+                    .insert(rtn.devaddr, (rtn.deveui.clone(), rtn.appeui.clone()));
+                self.emit(Event::JoinRequested {
+                    devaddr: rtn.devaddr,
+                    deveui: rtn.deveui,
+                    appeui: rtn.appeui,
+                });
+            }
+            Err(error) => self.edhoc_error(0, None, error, meta),
+        }
+    }
+
+    /// Forgets the handshakes which did not get their third EDHOC message within
+    /// `RESPONSE_CACHE_LIFETIME`, since anyone can send a first message. The EUIs are kept for the
+    /// devaddrs which have a session.
+    fn prune_pending_joins(&mut self) {
+        let expired: Vec<[u8; 4]> = self
+            .pending_joins
+            .iter()
+            .filter(|(_, started)| started.elapsed() >= RESPONSE_CACHE_LIFETIME)
+            .map(|(devaddr, _)| *devaddr)
+            .collect();
+        for devaddr in expired {
+            self.pending_joins.remove(&devaddr);
+            self.msg3_receivers.remove(&devaddr);
+            if !self.lora_ratchets.contains_key(&devaddr) {
+                self.euis.remove(&devaddr);
+            }
+        }
+    }
+
+    /// Handles the third EDHOC message. If it is a retransmission of a message we already answered,
+    /// the cached fourth message is sent again and the ratchet we created is kept.
+    fn handle_m_type_two(&mut self, buffer: Vec<u8>, meta: FrameMeta) {
+        if buffer.len() < 7 {
+            self.emit(Event::Error(format!(
+                "EDHOC message too short {:?}",
+                buffer
+            )));
+            return;
+        }
+        let devaddr = get_edhoc_devaddr(&buffer);
+        if let Some(msg4) = self.msg4_cache.lookup(&devaddr, &buffer) {
+            println!("Recieved duplicate m type 2, resending m type 3");
+            self.record_reception(devaddr, meta);
+            self.send(Some(devaddr), msg4, meta);
+            return;
+        }
+        match edhoc::handle_m_type_two(
+            buffer.clone(),
+            &mut self.msg3_receivers,
+            &self.enc_keys.ed_keys,
+        ) {
+            Ok(rtn) => {
+                self.msg4_cache
+                    .insert(rtn.devaddr, buffer, rtn.msg4.clone());
+                self.record_reception(rtn.devaddr, meta);
+                self.send(Some(rtn.devaddr), rtn.msg4, meta);
+                self.pending_joins.remove(&rtn.devaddr);
+                self.lora_ratchets.insert(rtn.devaddr, rtn.as_ratchet);
+                self.key_ages.insert(rtn.devaddr, KeyAge::joined());
+                self.ratchet_recieved.insert(rtn.devaddr, 2);
+                self.emit(Event::Joined {
+                    devaddr: rtn.devaddr,
+                    kid: rtn.kid,
+                });
+            }
+            Err(error) => self.edhoc_error(2, Some(devaddr), error, meta),
+        }
+    }
+
+    /// This function handles the incomming ratchet messages, this includes decrypting, and checking if
+    /// we would need to perform a DHR, to update our keys.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - The recieved LoRaRatchet message.
+    /// * `meta` - When and on which frequency we received the message
+    fn handle_ratchet_message(&mut self, buffer: Vec<u8>, meta: FrameMeta) {
+        if buffer.len() < 18 {
+            self.emit(Event::Error(format!(
+                "LoRaRatchet message too short {:?}",
+                buffer
+            )));
+            return;
+        }
+        let devaddr = get_ratchet_devaddr(&buffer);
+        let fcnt = get_ratchet_fcnt(&buffer);
+        if let Some(answer) = self.dhr_cache.lookup(&devaddr, &buffer) {
+            println!("Recieved duplicate DHR request, resending the answer");
+            self.record_reception(devaddr, meta);
+            self.send(Some(devaddr), answer, meta);
+            return;
+        }
+        // The ratchet would refuse a frame counter it already used, so a confirmed uplink the ED
+        // retransmits because our acknowledgement got lost is answered from the cache
+        if let Some(answer) = self.confirmed_cache.lookup(&devaddr, &buffer) {
+            println!("Recieved retransmitted confirmed uplink, resending the acknowledgement");
+            self.record_reception(devaddr, meta);
+            self.send(Some(devaddr), answer, meta);
+            return;
+        }
+        let mut lora_ratchet = match self.lora_ratchets.remove(&devaddr) {
+            Some(lora_ratchet) => lora_ratchet,
+            None => {
+                println!("No ratchet on this devaddr");
+                self.send(Some(devaddr), prepare_unknown_session(devaddr), meta);
+                self.emit(Event::Error(format!(
+                    "No ratchet on the devaddr {:?}",
+                    devaddr
+                )));
+                return;
+            }
+        };
+        self.record_reception(devaddr, meta);
+        // This is a debug counter, the amount of messages recieved on the devaddr
+        let message_recieved = self.ratchet_recieved.entry(devaddr).or_insert(0);
+        *message_recieved += 1;
+        println!(
+            "Recieved #{:?} messages on the following devaddr {:?}",
+            message_recieved, devaddr
+        );
+        let result = lora_ratchet.receive(buffer.clone());
+        self.lora_ratchets.insert(devaddr, lora_ratchet);
+        match result {
+            Ok((newout, true)) => {
+                self.decrypt_failures.remove(&devaddr);
+                self.uplink_fcnts.insert(devaddr, fcnt);
+                self.dhr_cache.insert(devaddr, buffer, newout.clone());
+                self.send(Some(devaddr), newout, meta);
+                if let Some(age) = self.key_ages.get_mut(&devaddr) {
+                    age.dh_step();
+                }
+                self.emit(Event::DhrCompleted { devaddr });
+            }
+            Ok((payload, false)) => {
+                self.decrypt_failures.remove(&devaddr);
+                self.uplink_fcnts.insert(devaddr, fcnt);
+                let confirmed = port::decode(&payload).is_ok_and(|payload| payload.confirmed);
+                let downlink = self.handle_uplink(devaddr, payload, fcnt, meta);
+                if let (true, Some(downlink)) = (confirmed, downlink) {
+                    self.confirmed_cache.insert(devaddr, buffer, downlink);
+                }
+            }
+            Err(x) => {
+                self.emit(Event::DecryptFailed {
+                    devaddr,
+                    reason: format!("{:?}", x),
+                });
+                // A replayed or stale frame says nothing about whether we are in sync
+                let fresh = self
+                    .uplink_fcnts
+                    .get(&devaddr)
+                    .is_none_or(|last| fcnt.wrapping_sub(*last).wrapping_sub(1) < u16::MAX / 2);
+                if !fresh {
+                    return;
+                }
+                let (failures, first) = self
+                    .decrypt_failures
+                    .entry(devaddr)
+                    .or_insert((0, Instant::now()));
+                if first.elapsed() > DECRYPT_FAILURE_WINDOW {
+                    *failures = 0;
+                    *first = Instant::now();
+                }
+                *failures += 1;
+                if *failures >= MAX_DECRYPT_FAILURES {
+                    self.drop_session(devaddr, meta);
+                }
+            }
+        }
+    }
+
+    /// Handles a decrypted uplink, based on its port. Application data is emitted as an event, while
+    /// MAC commands are handled. Afterwards the queued MAC commands of the ED, and our answers to its
+    /// own commands or the acknowledgement of a confirmed uplink, are sent in its receive window.
+    /// Without those, the application data queued for the ED is sent. Returns the downlink we sent,
+    /// if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED
+    /// * `payload` - The decrypted payload, starting with the port
+    /// * `fcnt` - The frame counter of the uplink
+    /// * `meta` - When and on which frequency we received the uplink
+    fn handle_uplink(
+        &mut self,
+        devaddr: [u8; 4],
+        payload: Vec<u8>,
+        fcnt: u16,
+        meta: FrameMeta,
+    ) -> Option<Vec<u8>> {
+        let (port, confirmed, payload) = match port::decode(&payload) {
+            Ok(payload) => (payload.port, payload.confirmed, payload.data.to_vec()),
+            Err(x) => {
+                self.emit(Event::Error(format!("{} from {:?}", x, devaddr)));
+                return None;
+            }
+        };
+        let mut answers = Vec::new();
+        if confirmed {
+            answers.push(MacCommand::UplinkAck { fcnt });
+        }
+        if port == MAC_PORT {
+            match mac::decode(&payload) {
+                Ok(commands) => {
+                    for command in commands {
+                        if let Some(answer) = self.handle_mac_command(devaddr, command, meta) {
+                            answers.push(answer);
+                        }
+                    }
+                }
+                Err(x) => self.emit(Event::Error(x)),
+            }
+        } else {
+            let object = self
+                .euis
+                .get(&devaddr)
+                .and_then(|(deveui, appeui)| self.decoders.decode(deveui, appeui, port, &payload));
+            let object = match object {
+                Some(Ok(object)) => Some(object),
+                Some(Err(reason)) => {
+                    self.emit(Event::DecodeFailed {
+                        devaddr,
+                        port,
+                        reason,
+                    });
+                    None
+                }
+                None => None,
+            };
+            self.emit(Event::Uplink {
+                devaddr,
+                port,
+                payload,
+                fcnt,
+                confirmed,
+                object,
+                meta,
+            });
+        }
+        self.apply_rekey_policy(devaddr);
+        self.adapt_data_rate(devaddr);
+        self.send_mac_commands(devaddr, answers, meta)
+            .or_else(|| self.send_application_data(devaddr, meta))
+    }
+
+    /// Handles a MAC command from an ED, returning our answer if it needs one.
+    fn handle_mac_command(
+        &mut self,
+        devaddr: [u8; 4],
+        command: MacCommand,
+        meta: FrameMeta,
+    ) -> Option<MacCommand> {
+        match command {
+            MacCommand::Ack { cid, accepted } => {
+                self.answered(devaddr, cid);
+                if cid == LINK_ADR_REQ && !accepted {
+                    if let Some(adr) = &mut self.adr {
+                        adr.forget(&devaddr);
+                    }
+                }
+                self.emit(Event::MacAnswered {
+                    devaddr,
+                    cid,
+                    accepted,
+                });
+                None
+            }
+            MacCommand::StatusAns(status) => {
+                self.answered(devaddr, STATUS_REQ);
+                self.emit(Event::Status { devaddr, status });
+                None
+            }
+            MacCommand::LinkCheckReq => Some(MacCommand::LinkCheckAns {
+                margin: (meta.snr - required_snr(meta.spreading_factor)).clamp(0.0, 255.0) as u8,
+            }),
+            command => {
+                self.emit(Event::Error(format!(
+                    "Unexpected MAC command {:?} from {:?}",
+                    command, devaddr
+                )));
+                None
+            }
+        }
+    }
+
+    /// Removes the queued command an ED answered.
+    fn answered(&mut self, devaddr: [u8; 4], cid: u8) {
+        if let Some(queue) = self.mac_queues.get_mut(&devaddr) {
+            if let Some(position) = queue.iter().position(|queued| queued.cid() == cid) {
+                queue.remove(position);
+            }
+        }
+    }
+
+    /// Sends the queued MAC commands of an ED together with `answers`, if there are any. Returns the
+    /// downlink, even if it had to be dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED
+    /// * `answers` - Our answers to the commands in its uplink
+    /// * `meta` - When and on which frequency we received the uplink we are answering
+    fn send_mac_commands(
+        &mut self,
+        devaddr: [u8; 4],
+        answers: Vec<MacCommand>,
+        meta: FrameMeta,
+    ) -> Option<Vec<u8>> {
+        let mut commands: Vec<MacCommand> = self
+            .mac_queues
+            .get(&devaddr)
+            .map(|queue| queue.iter().cloned().collect())
+            .unwrap_or_default();
+        commands.extend(answers);
+        if commands.is_empty() {
+            return None;
+        }
+        let lora_ratchet = self.lora_ratchets.get_mut(&devaddr)?;
+        let downlink = lora_ratchet.ratchet_encrypt_payload(&mac::encode(&commands), &devaddr);
+        self.send(Some(devaddr), downlink.clone(), meta);
+        Some(downlink)
+    }
+
+    /// Sends the oldest application data queued for an ED, if there is any. Returns the downlink.
+    fn send_application_data(&mut self, devaddr: [u8; 4], meta: FrameMeta) -> Option<Vec<u8>> {
+        let (port, data) = self.downlink_queues.get_mut(&devaddr)?.pop_front()?;
+        let lora_ratchet = self.lora_ratchets.get_mut(&devaddr)?;
+        let downlink =
+            lora_ratchet.ratchet_encrypt_payload(&port::encode(port, false, &data), &devaddr);
+        println!(
+            "Sending {:?} bytes on the {} port to {:?}",
+            data.len(),
+            port::name(port),
+            devaddr
+        );
+        self.send(Some(devaddr), downlink.clone(), meta);
+        Some(downlink)
+    }
+
+    /// Asks an ED to replace its key material, if the rekey policy says it is too old and we have not
+    /// asked already.
+    fn apply_rekey_policy(&mut self, devaddr: [u8; 4]) {
+        let rekey = match self.key_ages.get(&devaddr) {
+            Some(age) => self.rekey_policy.check(age),
+            None => None,
+        };
+        let queued =
+            |command: &MacCommand| matches!(command, MacCommand::DhrReq | MacCommand::Rejoin);
+        let pending = self
+            .mac_queues
+            .get(&devaddr)
+            .is_some_and(|queue| queue.iter().any(queued));
+        if let Some(rekey) = rekey {
+            if !pending {
+                self.request_rekey(devaddr, rekey);
+            }
+        }
+    }
+
+    /// Asks an ED to change its data rate and TX power, if ADR is enabled and its latest receptions
+    /// show it should.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED
+    fn adapt_data_rate(&mut self, devaddr: [u8; 4]) {
+        let (adr, receptions) = match (&mut self.adr, self.receptions.get(&devaddr)) {
+            (Some(adr), Some(receptions)) => (adr, receptions),
+            _ => return,
+        };
+        let settings = match adr.decide(&devaddr, receptions) {
+            Some(settings) => settings,
+            None => return,
+        };
+        adr.assign(devaddr, settings);
+        self.queue_mac_command(
+            devaddr,
+            MacCommand::LinkAdrReq {
+                spreading_factor: settings.spreading_factor,
+                tx_power: settings.tx_power,
+            },
+        );
+        self.emit(Event::DataRateChanged {
+            devaddr,
+            spreading_factor: settings.spreading_factor,
+            tx_power: settings.tx_power,
+        });
+    }
+
+    /// Forgets the ratchet of an ED we are out of sync with, and tells the ED to join again.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the session
+    /// * `meta` - When and on which frequency we received the message which made us drop the session
+    fn drop_session(&mut self, devaddr: [u8; 4], meta: FrameMeta) {
+        self.lora_ratchets.remove(&devaddr);
+        self.ratchet_recieved.remove(&devaddr);
+        self.decrypt_failures.remove(&devaddr);
+        self.uplink_fcnts.remove(&devaddr);
+        self.receptions.remove(&devaddr);
+        if let Some(adr) = &mut self.adr {
+            adr.forget(&devaddr);
+        }
+        self.mac_queues.remove(&devaddr);
+        self.key_ages.remove(&devaddr);
+        self.msg4_cache.remove(&devaddr);
+        self.dhr_cache.remove(&devaddr);
+        self.confirmed_cache.remove(&devaddr);
+        self.euis.remove(&devaddr);
+        self.send(Some(devaddr), prepare_unknown_session(devaddr), meta);
+        self.emit(Event::SessionDropped { devaddr });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fragment::{self, Reassembler, FRAGMENT_HEADER, REASSEMBLY_TIMEOUT};
+    use crate::scheduler::Window;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// What a test records, shared with the callbacks and the transport.
+    type Recorded<T> = Rc<RefCell<Vec<T>>>;
+
+    /// A transport which receives nothing and records what is transmitted.
+    struct TestTransport {
+        transmitted: Recorded<Vec<u8>>,
+    }
+
+    impl Transport for TestTransport {
+        fn receive(&mut self, _deadline: Option<Instant>) -> Result<Frame, TransportError> {
+            Err(TransportError::Timeout)
+        }
+
+        fn transmit(
+            &mut self,
+            payload: Vec<u8>,
+            _window: Window,
+            _uplink: &FrameMeta,
+        ) -> Result<usize, TransportError> {
+            let size = payload.len();
+            self.transmitted.borrow_mut().push(payload);
+            Ok(size)
+        }
+    }
+
+    /// A server on a `TestTransport`, the events it emits and the frames it transmits.
+    fn server() -> (
+        NetworkServer<TestTransport>,
+        Recorded<Event>,
+        Recorded<Vec<u8>>,
+    ) {
+        let transmitted = Rc::new(RefCell::new(Vec::new()));
+        let keys = StaticKeys {
+            as_static_material: [1; 32],
+            ed_keys: Vec::new(),
+        };
+        let mut server = NetworkServer::new(
+            TestTransport {
+                transmitted: transmitted.clone(),
+            },
+            keys,
+        );
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let emitted = events.clone();
+        server.on_event(move |event| emitted.borrow_mut().push(event.clone()));
+        (server, events, transmitted)
+    }
+
+    fn frame(payload: Vec<u8>) -> Frame {
+        Frame {
+            payload,
+            meta: FrameMeta {
+                received: Instant::now(),
+                frequency: 915_000_000,
+                spreading_factor: 7,
+                bandwidth: 125000,
+                rssi: -60,
+                snr: 9.0,
+            },
+        }
+    }
+
+    #[test]
+    fn refuses_short_edhoc_messages() {
+        let (mut server, events, transmitted) = server();
+        for payload in [vec![0], vec![0, 0], vec![2, 0, 0, 1, 2, 3]] {
+            server.handle_frame(frame(payload.clone()));
+            match events.borrow().last() {
+                Some(Event::Error(x)) => assert!(x.contains("too short"), "{}", x),
+                other => panic!("{:?} for {:?}", other, payload),
+            }
+        }
+        server.poll();
+        assert!(transmitted.borrow().is_empty());
+    }
+
+    #[test]
+    fn refuses_short_edhoc_messages_after_reassembly() {
+        let (mut server, events, transmitted) = server();
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT);
+        for payload in [vec![0], vec![0, 0]] {
+            // One byte per fragment, so the short frame arrives in pieces
+            let mut reassembled = None;
+            for piece in fragment::split(&payload, FRAGMENT_HEADER + 1, false).unwrap() {
+                reassembled = reassembler.add(&piece).unwrap();
+            }
+            let reassembled = reassembled.unwrap();
+            assert_eq!(reassembled, payload);
+            server.handle_frame(frame(reassembled));
+            match events.borrow().last() {
+                Some(Event::Error(x)) => assert!(x.contains("too short"), "{}", x),
+                other => panic!("{:?} for {:?}", other, payload),
+            }
+        }
+        assert!(transmitted.borrow().is_empty());
+    }
+
+    #[test]
+    fn forgets_expired_handshakes() {
+        let (mut server, _, _) = server();
+        let started = Instant::now() - RESPONSE_CACHE_LIFETIME;
+        let eui = (vec![1; 8], vec![2; 8]);
+        server.pending_joins.insert([1, 1, 1, 1], started);
+        server.euis.insert([1, 1, 1, 1], eui.clone());
+        server.pending_joins.insert([2, 2, 2, 2], Instant::now());
+        server.euis.insert([2, 2, 2, 2], eui);
+        server.prune_pending_joins();
+        assert!(!server.euis.contains_key(&[1, 1, 1, 1]));
+        assert!(!server.pending_joins.contains_key(&[1, 1, 1, 1]));
+        assert!(server.euis.contains_key(&[2, 2, 2, 2]));
+        assert!(server.pending_joins.contains_key(&[2, 2, 2, 2]));
+    }
+}
@@ -0,0 +1,71 @@
+use serde::{Deserialize, Serialize};
+
+use std::time::{Duration, Instant};
+
+/// How the AS can make an ED replace its key material.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rekey {
+    /// A Diffie-Hellman ratchet step, on the session the ED already has
+    Dhr,
+    /// A full EDHOC handshake, starting a new session
+    Rejoin,
+}
+
+/// When the AS makes EDs replace their key material on its own. A limit of `None` is never reached.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct RekeyPolicy {
+    /// After this many seconds without a DH step, we ask the ED for one
+    pub max_dhr_age: Option<u64>,
+    /// After this many seconds since the handshake, we ask the ED to join again
+    pub max_session_age: Option<u64>,
+}
+
+/// The age of the key material of an ED.
+#[derive(Debug, Clone, Copy)]
+pub struct KeyAge {
+    /// When the ED finished its EDHOC handshake
+    pub joined: Instant,
+    /// When the ED last completed a DH step, or joined if it has not yet
+    pub last_dh_step: Instant,
+    /// The amount of DH steps since the ED joined
+    pub dh_steps: u32,
+}
+
+impl KeyAge {
+    /// The key material of an ED which just joined.
+    pub fn joined() -> Self {
+        let now = Instant::now();
+        KeyAge {
+            joined: now,
+            last_dh_step: now,
+            dh_steps: 0,
+        }
+    }
+
+    /// Records a DH step the ED completed.
+    pub fn dh_step(&mut self) {
+        self.last_dh_step = Instant::now();
+        self.dh_steps += 1;
+    }
+}
+
+impl RekeyPolicy {
+    /// The rekey the policy asks for, if the key material of an ED is too old. A rejoin goes before a
+    /// DH step, since it replaces all key material.
+    ///
+    /// # Arguments
+    ///
+    /// * `age` - The age of the key material of the ED
+    pub fn check(&self, age: &KeyAge) -> Option<Rekey> {
+        let expired = |since: Instant, limit: Option<u64>| {
+            limit.is_some_and(|limit| since.elapsed() >= Duration::from_secs(limit))
+        };
+        if expired(age.joined, self.max_session_age) {
+            Some(Rekey::Rejoin)
+        } else if expired(age.last_dh_step, self.max_dhr_age) {
+            Some(Rekey::Dhr)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+struct CachedResponse {
+    request: Vec<u8>,
+    response: Vec<u8>,
+    created: Instant,
+}
+
+/// Remembers the responses we sent to EDHOC messages, DHR requests and confirmed uplinks for a while.
+/// When the ED did not recieve our response it retransmits the exact same message, and we can answer
+/// it again without creating new state.
+pub struct ResponseCache<K> {
+    entries: HashMap<K, CachedResponse>,
+    lifetime: Duration,
+}
+
+impl<K: Eq + Hash> ResponseCache<K> {
+    /// Creates an empty cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `lifetime` - How long a response is remembered
+    pub fn new(lifetime: Duration) -> Self {
+        ResponseCache {
+            entries: HashMap::new(),
+            lifetime,
+        }
+    }
+
+    /// Remembers the response to a request, replacing any earlier response stored on the key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - What the request is looked up by
+    /// * `request` - The frame we recieved
+    /// * `response` - The frame we answered with
+    pub fn insert(&mut self, key: K, request: Vec<u8>, response: Vec<u8>) {
+        self.prune();
+        self.entries.insert(
+            key,
+            CachedResponse {
+                request,
+                response,
+                created: Instant::now(),
+            },
+        );
+    }
+
+    /// Returns the response we sent, if `request` is a duplicate of the request stored on the key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - What the request is looked up by
+    /// * `request` - The frame we recieved
+    pub fn lookup(&mut self, key: &K, request: &[u8]) -> Option<Vec<u8>> {
+        self.prune();
+        self.entries
+            .get(key)
+            .filter(|cached| cached.request == request)
+            .map(|cached| cached.response.clone())
+    }
+
+    /// Forgets the response stored on the key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - What the request is looked up by
+    pub fn remove(&mut self, key: &K) {
+        self.entries.remove(key);
+    }
+
+    fn prune(&mut self) {
+        let lifetime = self.lifetime;
+        self.entries
+            .retain(|_, cached| cached.created.elapsed() < lifetime);
+    }
+}
@@ -0,0 +1,276 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::radio::RadioProfile;
+
+/// When an ED listens for our answer, counted from the moment its uplink ended. These have to
+/// match the `rx1_delay`, `rx1_duration`, `rx2_delay` and `rx2_duration` in the config of the ED.
+#[derive(Debug, Clone, Copy)]
+pub struct DownlinkTiming {
+    pub rx1_delay: Duration,
+    pub rx1_duration: Duration,
+    pub rx2_delay: Duration,
+    pub rx2_duration: Duration,
+}
+
+impl Default for DownlinkTiming {
+    /// The timing of the config shipped with the client.
+    fn default() -> Self {
+        RxConfig::default().into()
+    }
+}
+
+/// The receive windows of the EDs as set in the config file. These have the names and values of
+/// the config shipped with the client. Settings missing from the file keep their default.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct RxConfig {
+    /// The delays and durations in ms
+    pub rx1_delay: u64,
+    pub rx1_duration: u64,
+    pub rx2_delay: u64,
+    pub rx2_duration: u64,
+    /// The frequencies in MHz and bandwidths in Hz, only used without a region
+    pub rx1_frequency: i64,
+    pub rx1_spreading_factor: u8,
+    pub rx1_bandwidth: i64,
+    pub rx2_frequency: i64,
+    pub rx2_spreading_factor: u8,
+    pub rx2_bandwidth: i64,
+}
+
+impl RxConfig {
+    /// The radio profile a downlink in `window` is sent with when there is no region, which is
+    /// `profile` with the frequency and data rate of the window.
+    ///
+    /// # Arguments
+    ///
+    /// * `window` - The receive window of the ED
+    /// * `profile` - The radio profile of the server
+    pub fn window_profile(&self, window: Window, profile: RadioProfile) -> RadioProfile {
+        let (frequency, spreading_factor, bandwidth) = match window {
+            Window::Rx1 => (
+                self.rx1_frequency,
+                self.rx1_spreading_factor,
+                self.rx1_bandwidth,
+            ),
+            Window::Rx2 => (
+                self.rx2_frequency,
+                self.rx2_spreading_factor,
+                self.rx2_bandwidth,
+            ),
+        };
+        RadioProfile {
+            frequency,
+            spreading_factor,
+            bandwidth,
+            ..profile
+        }
+    }
+}
+
+impl Default for RxConfig {
+    fn default() -> Self {
+        RxConfig {
+            rx1_delay: 0,
+            rx1_duration: 5000,
+            rx2_delay: 7000,
+            rx2_duration: 5000,
+            rx1_frequency: 915,
+            rx1_spreading_factor: 7,
+            rx1_bandwidth: 125000,
+            rx2_frequency: 915,
+            rx2_spreading_factor: 7,
+            rx2_bandwidth: 125000,
+        }
+    }
+}
+
+impl From<RxConfig> for DownlinkTiming {
+    fn from(rx: RxConfig) -> Self {
+        DownlinkTiming {
+            rx1_delay: Duration::from_millis(rx.rx1_delay),
+            rx1_duration: Duration::from_millis(rx.rx1_duration),
+            rx2_delay: Duration::from_millis(rx.rx2_delay),
+            rx2_duration: Duration::from_millis(rx.rx2_duration),
+        }
+    }
+}
+
+/// The receive window of the ED a downlink is scheduled in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Window {
+    Rx1,
+    Rx2,
+}
+
+/// Decides when our answer to an uplink has to be transmitted, such that it arrives while the ED
+/// is listening in its RX1 window, or in its RX2 window if we are too late for RX1.
+pub struct DownlinkScheduler {
+    default_timing: DownlinkTiming,
+    timings: HashMap<[u8; 4], DownlinkTiming>,
+    late: HashMap<[u8; 4], u32>,
+    late_total: u64,
+}
+
+impl DownlinkScheduler {
+    /// Creates a scheduler using the same timing for all EDs.
+    ///
+    /// # Arguments
+    ///
+    /// * `default_timing` - The timing of EDs without a timing of their own
+    pub fn new(default_timing: DownlinkTiming) -> Self {
+        DownlinkScheduler {
+            default_timing,
+            timings: HashMap::new(),
+            late: HashMap::new(),
+            late_total: 0,
+        }
+    }
+
+    /// Sets the timing of the EDs without a timing of their own.
+    ///
+    /// # Arguments
+    ///
+    /// * `timing` - When the EDs listen
+    pub fn set_default_timing(&mut self, timing: DownlinkTiming) {
+        self.default_timing = timing;
+    }
+
+    /// Sets the timing of a single ED.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED
+    /// * `timing` - When the ED listens
+    pub fn set_timing(&mut self, devaddr: [u8; 4], timing: DownlinkTiming) {
+        self.timings.insert(devaddr, timing);
+    }
+
+    /// Returns the timing of an ED, or the default timing if it has none of its own.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED, `None` if it has not been assigned one yet
+    pub fn timing(&self, devaddr: Option<[u8; 4]>) -> DownlinkTiming {
+        devaddr
+            .and_then(|devaddr| self.timings.get(&devaddr).copied())
+            .unwrap_or(self.default_timing)
+    }
+
+    /// Returns the window and the instant our answer should be transmitted at. A window can be
+    /// used as long as it is still open, so a window which already opened is used right away. If
+    /// both windows have closed the downlink is counted as late and `None` is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED, `None` if it has not been assigned one yet
+    /// * `received` - When we were done receiving the uplink we are answering
+    pub fn schedule(
+        &mut self,
+        devaddr: Option<[u8; 4]>,
+        received: Instant,
+    ) -> Option<(Window, Instant)> {
+        let timing = self.timing(devaddr);
+        let now = Instant::now();
+        let windows = [
+            (Window::Rx1, timing.rx1_delay, timing.rx1_duration),
+            (Window::Rx2, timing.rx2_delay, timing.rx2_duration),
+        ];
+        for (window, delay, duration) in windows {
+            let opens = received + delay;
+            if now < opens + duration {
+                return Some((window, opens.max(now)));
+            }
+        }
+        self.late_total += 1;
+        if let Some(devaddr) = devaddr {
+            *self.late.entry(devaddr).or_insert(0) += 1;
+        }
+        None
+    }
+
+    /// The amount of downlinks to an ED we dropped, because both its windows had closed.
+    ///
+    /// # Arguments
+    ///
+    /// * `devaddr` - The devaddr of the ED
+    pub fn late_downlinks(&self, devaddr: &[u8; 4]) -> u32 {
+        self.late.get(devaddr).copied().unwrap_or(0)
+    }
+
+    /// The amount of downlinks we dropped in total, because both windows had closed.
+    pub fn late_total(&self) -> u64 {
+        self.late_total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVADDR: [u8; 4] = [1, 2, 3, 4];
+
+    fn scheduler() -> DownlinkScheduler {
+        DownlinkScheduler::new(
+            RxConfig {
+                rx1_delay: 1000,
+                rx1_duration: 1000,
+                rx2_delay: 3000,
+                rx2_duration: 1000,
+                ..RxConfig::default()
+            }
+            .into(),
+        )
+    }
+
+    #[test]
+    fn waits_for_rx1_to_open() {
+        let received = Instant::now();
+        let (window, at) = scheduler().schedule(Some(DEVADDR), received).unwrap();
+        assert_eq!(window, Window::Rx1);
+        assert_eq!(at, received + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn falls_back_to_rx2_after_rx1_closed() {
+        let received = Instant::now() - Duration::from_millis(2500);
+        let (window, at) = scheduler().schedule(Some(DEVADDR), received).unwrap();
+        assert_eq!(window, Window::Rx2);
+        assert_eq!(at, received + Duration::from_secs(3));
+    }
+
+    #[test]
+    fn sends_right_away_in_an_open_window() {
+        let received = Instant::now() - Duration::from_millis(1500);
+        let (window, at) = scheduler().schedule(Some(DEVADDR), received).unwrap();
+        assert_eq!(window, Window::Rx1);
+        assert!(at > received + Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn counts_downlinks_after_both_windows_as_late() {
+        let mut scheduler = scheduler();
+        let received = Instant::now() - Duration::from_secs(5);
+        assert!(scheduler.schedule(Some(DEVADDR), received).is_none());
+        assert!(scheduler.schedule(None, received).is_none());
+        assert_eq!(scheduler.late_downlinks(&DEVADDR), 1);
+        assert_eq!(scheduler.late_total(), 2);
+    }
+
+    #[test]
+    fn uses_the_timing_of_the_ed() {
+        let mut scheduler = scheduler();
+        scheduler.set_timing(DEVADDR, DownlinkTiming::default());
+        let received = Instant::now();
+        let (window, _) = scheduler.schedule(Some(DEVADDR), received).unwrap();
+        assert_eq!(window, Window::Rx1);
+        assert_eq!(
+            scheduler.timing(Some(DEVADDR)).rx2_delay,
+            Duration::from_secs(7)
+        );
+        assert_eq!(scheduler.timing(None).rx2_delay, Duration::from_secs(3));
+    }
+}
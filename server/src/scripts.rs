@@ -0,0 +1,234 @@
+use rhai::{
+    module_resolvers::DummyModuleResolver, Blob, Dynamic, Engine, EvalAltResult, Scope, AST,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// The longest string, array or object map a script may build.
+const MAX_SIZE: usize = 4096;
+/// How deep a script may nest expressions and function calls.
+const MAX_DEPTH: usize = 32;
+
+/// How much a script may do for a single message, before it is stopped.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ScriptLimits {
+    /// The most operations a script may run
+    pub max_operations: u64,
+    /// The most ms a script may run
+    pub max_time: u64,
+}
+
+impl Default for ScriptLimits {
+    fn default() -> Self {
+        ScriptLimits {
+            max_operations: 100_000,
+            max_time: 50,
+        }
+    }
+}
+
+/// The decoder and encoder scripts of the applications, in a sandboxed Rhai engine. A script
+/// defines `decode(port, bytes)`, returning an object map, and `encode(port, object)`, returning a
+/// blob. Either may return `()` for ports it leaves to the schemas.
+pub struct Scripts {
+    engine: Engine,
+    /// When the running script started, for the time limit
+    started: Rc<Cell<Instant>>,
+    /// The compiled script per AppEUI
+    applications: HashMap<String, AST>,
+}
+
+impl fmt::Debug for Scripts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Scripts")
+            .field("applications", &self.applications.keys())
+            .finish()
+    }
+}
+
+impl Default for Scripts {
+    fn default() -> Self {
+        Scripts::new(ScriptLimits::default())
+    }
+}
+
+impl Scripts {
+    /// Creates an engine without scripts. Scripts can not reach files, the network or the
+    /// server, `import` is refused, and they are stopped once they pass the limits.
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - How much a script may do for a single message
+    pub fn new(limits: ScriptLimits) -> Self {
+        let started = Rc::new(Cell::new(Instant::now()));
+        let mut engine = Engine::new();
+        // The default module resolver reads scripts from disk
+        engine
+            .set_module_resolver(DummyModuleResolver::new())
+            .set_max_modules(0)
+            .set_max_operations(limits.max_operations)
+            .set_max_string_size(MAX_SIZE)
+            .set_max_array_size(MAX_SIZE)
+            .set_max_map_size(MAX_SIZE)
+            .set_max_call_levels(MAX_DEPTH)
+            .set_max_expr_depths(MAX_DEPTH, MAX_DEPTH);
+        let max_time = Duration::from_millis(limits.max_time);
+        let start = started.clone();
+        engine.on_progress(move |_| {
+            if start.get().elapsed() > max_time {
+                Some(Dynamic::from(format!("ran longer than {:?}", max_time)))
+            } else {
+                None
+            }
+        });
+        engine.on_print(|x| println!("Script: {}", x));
+        Scripts {
+            engine,
+            started,
+            applications: HashMap::new(),
+        }
+    }
+
+    /// Compiles the script of an application.
+    ///
+    /// # Arguments
+    ///
+    /// * `appeui` - The AppEUI of the application, as 16 hex digits
+    /// * `path` - Where the script is located
+    pub fn load(&mut self, appeui: &str, path: &str) -> Result<(), String> {
+        let source = fs::read_to_string(path).map_err(|x| format!("{}: {}", path, x))?;
+        let ast = self
+            .engine
+            .compile(source)
+            .map_err(|x| format!("{}: {}", path, x))?;
+        self.applications.insert(appeui.to_string(), ast);
+        Ok(())
+    }
+
+    /// Calls a function of the script of an application, `None` if the application has no script
+    /// with that function or the script returned `()`.
+    fn call(
+        &self,
+        appeui: &str,
+        function: &str,
+        port: u8,
+        argument: Dynamic,
+    ) -> Option<Result<Dynamic, String>> {
+        let ast = self.applications.get(appeui)?;
+        if !ast.iter_functions().any(|x| x.name == function) {
+            return None;
+        }
+        self.started.set(Instant::now());
+        let result: Result<Dynamic, Box<EvalAltResult>> =
+            self.engine
+                .call_fn(&mut Scope::new(), ast, function, (port as i64, argument));
+        match result {
+            Ok(x) if x.is_unit() => None,
+            Ok(x) => Some(Ok(x)),
+            Err(x) => Some(Err(format!("{} of {} failed: {}", function, appeui, x))),
+        }
+    }
+
+    /// Decodes an uplink into a JSON object with the script of its application.
+    ///
+    /// # Arguments
+    ///
+    /// * `appeui` - The AppEUI of the device, as 16 hex digits
+    /// * `port` - The port of the uplink
+    /// * `data` - The application data
+    pub fn decode(&self, appeui: &str, port: u8, data: &[u8]) -> Option<Result<Value, String>> {
+        let object = self.call(appeui, "decode", port, Dynamic::from_blob(data.to_vec()))?;
+        Some(object.and_then(|x| rhai::serde::from_dynamic(&x).map_err(|x| x.to_string())))
+    }
+
+    /// Encodes a JSON object for a downlink with the script of its application.
+    ///
+    /// # Arguments
+    ///
+    /// * `appeui` - The AppEUI of the device, as 16 hex digits
+    /// * `port` - The port of the downlink
+    /// * `object` - The object to encode
+    pub fn encode(
+        &self,
+        appeui: &str,
+        port: u8,
+        object: &Value,
+    ) -> Option<Result<Vec<u8>, String>> {
+        let object = match rhai::serde::to_dynamic(object) {
+            Ok(object) => object,
+            Err(x) => return Some(Err(x.to_string())),
+        };
+        let data = self.call(appeui, "encode", port, object)?;
+        Some(data.and_then(|x| {
+            x.try_cast::<Blob>()
+                .ok_or(format!("encode of {} did not return a blob", appeui))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use std::env;
+    use std::path::PathBuf;
+
+    /// Writes a script to a file of its own, returning its path.
+    fn script(name: &str, source: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("{}-{}.rhai", name, std::process::id()));
+        fs::write(&path, source).unwrap();
+        path
+    }
+
+    #[test]
+    fn decodes_and_encodes() {
+        let path = script(
+            "codec",
+            "fn decode(port, bytes) { #{ port: port, first: bytes[0] } }
+             fn encode(port, object) { let blob = blob(); blob.push(object.value); blob }",
+        );
+        let mut scripts = Scripts::default();
+        scripts.load("0001", path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            scripts.decode("0001", 2, &[7, 8]),
+            Some(Ok(json!({ "port": 2, "first": 7 })))
+        );
+        assert_eq!(
+            scripts.encode("0001", 2, &json!({ "value": 9 })),
+            Some(Ok(vec![9]))
+        );
+        assert_eq!(scripts.decode("0002", 2, &[7]), None);
+    }
+
+    #[test]
+    fn refuses_imports() {
+        let module = script("module", "fn secret() { 42 }");
+        let path = script(
+            "import",
+            &format!(
+                "fn decode(port, bytes) {{ import {:?} as helpers; #{{ secret: helpers::secret() }} }}",
+                module.to_str().unwrap().trim_end_matches(".rhai")
+            ),
+        );
+        let mut scripts = Scripts::default();
+        scripts.load("0001", path.to_str().unwrap()).unwrap();
+        assert!(matches!(scripts.decode("0001", 1, &[1]), Some(Err(_))));
+    }
+
+    #[test]
+    fn stops_endless_scripts() {
+        let path = script("loop", "fn decode(port, bytes) { loop {} }");
+        let mut scripts = Scripts::default();
+        scripts.load("0001", path.to_str().unwrap()).unwrap();
+        assert!(matches!(scripts.decode("0001", 1, &[1]), Some(Err(_))));
+    }
+}
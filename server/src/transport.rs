@@ -0,0 +1,359 @@
+use rppal::gpio::OutputPin;
+use rppal::hal::Delay;
+use rppal::spi::Spi;
+
+use sx127x_lora::LoRa;
+
+use std::time::{Duration, Instant};
+
+use crate::{
+    airtime::{Modulation, MAX_FRAME_LENGTH},
+    duty_cycle::{GateError, TxGate},
+    fragment::{self, Reassembler, FRAGMENT_GAP, REASSEMBLY_TIMEOUT},
+    generics::get_message_lenght,
+    radio::RadioProfile,
+    region::{Channel, ChannelPlan, Region},
+    scheduler::{RxConfig, Window},
+};
+
+/// The ways a transport can fail to deliver or receive a frame.
+#[derive(Debug)]
+pub enum TransportError {
+    /// Nothing was received within the time the transport waits for a frame.
+    Timeout,
+    /// The frame is larger than can be split into fragments at the data rate it would be sent with.
+    PayloadTooLarge { size: usize, max: usize },
+    /// Sending the frame now would exceed the duty cycle or dwell time of the region.
+    Gate(GateError),
+    /// The radio, or whatever else is carrying the frames, reported an error.
+    Radio(String),
+}
+
+/// When and where a frame was received, and how well.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameMeta {
+    /// When the transport was done receiving the frame, the receive windows of the ED are timed from this.
+    pub received: Instant,
+    /// The frequency in Hz the frame was received on, the RX1 window of the ED depends on it.
+    pub frequency: i64,
+    pub spreading_factor: u8,
+    /// The signal bandwidth in Hz
+    pub bandwidth: i64,
+    /// The signal strength of the frame in dBm
+    pub rssi: i32,
+    /// The signal to noise ratio of the frame in dB
+    pub snr: f64,
+}
+
+/// A frame recieved by a transport.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub payload: Vec<u8>,
+    pub meta: FrameMeta,
+}
+
+/// Anything the `NetworkServer` can receive frames from and transmit frames with.
+///
+/// The sx1276 module implements this through [`LoraTransport`], but a custom gateway can implement
+/// it on top of e.g. a packet forwarder or a simulated radio.
+pub trait Transport {
+    /// Waits for the next frame and returns it.
+    ///
+    /// # Arguments
+    ///
+    /// * `deadline` - When to stop waiting with a `TransportError::Timeout`, since a downlink is
+    ///   due. Without one the transport waits as long as it likes.
+    fn receive(&mut self, deadline: Option<Instant>) -> Result<Frame, TransportError>;
+
+    /// Transmits a frame, returning the amount of bytes sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `payload` - The frame which should be sent
+    /// * `window` - The receive window of the ED the frame is sent in
+    /// * `uplink` - The uplink the frame answers, which decides the frequency of the RX1 window
+    fn transmit(
+        &mut self,
+        payload: Vec<u8>,
+        window: Window,
+        uplink: &FrameMeta,
+    ) -> Result<usize, TransportError>;
+}
+
+/// A sx1276 module following a radio profile and, optionally, a region.
+///
+/// A single sx1276 only hears one channel and spreading factor at a time, so with a region, or with
+/// several spreading factors for ADR, we hop over every combination, listening `dwell` ms on each.
+/// EDs retransmit what we miss. Downlinks are sent on the frequency and data rate of the receive
+/// window they are scheduled in, and only if the TX gate allows it. A downlink can not wait for
+/// budget, since the window of the ED would close.
+///
+/// Frames larger than the data rate allows are split into fragments, sent back to back. When we
+/// receive a fragment, we stay on the channel until the frame is complete or the rest stops coming.
+pub struct LoraTransport {
+    lora: LoRa<Spi, OutputPin, OutputPin>,
+    profile: RadioProfile,
+    plan: Option<ChannelPlan>,
+    rx: RxConfig,
+    gate: TxGate,
+    channels: Vec<Channel>,
+    spreading_factors: Vec<u8>,
+    channel: usize,
+    dwell: i32,
+    reassembler: Reassembler,
+    fec: bool,
+}
+
+impl LoraTransport {
+    /// Creates the transport, and tunes the radio to the first channel we listen on.
+    ///
+    /// # Arguments
+    ///
+    /// * `lora` - The radio, set up with `profile`
+    /// * `profile` - The radio settings, with a region these are only used for the data rate
+    /// * `region` - The regional parameters to follow, without one we only use the frequency of the profile
+    /// * `sub_band` - The sub-band from 0 to 7 we listen on, only used in US915 and AU915
+    /// * `spreading_factors` - The spreading factors EDs may use, the one of the profile when empty
+    /// * `dwell` - How many ms we listen on a channel before hopping to the next
+    /// * `fec` - Whether downlinks split into fragments get a parity fragment
+    pub fn new(
+        lora: LoRa<Spi, OutputPin, OutputPin>,
+        profile: RadioProfile,
+        region: Option<Region>,
+        sub_band: Option<u8>,
+        mut spreading_factors: Vec<u8>,
+        dwell: i32,
+        fec: bool,
+    ) -> Self {
+        if spreading_factors.is_empty() {
+            spreading_factors.push(profile.spreading_factor);
+        }
+        let plan = region.map(Region::channel_plan);
+        let channels = match &plan {
+            Some(plan) => plan.enabled_channels(profile.bandwidth, sub_band),
+            None => vec![Channel {
+                frequency: profile.frequency * 1_000_000,
+                bandwidth: profile.bandwidth,
+            }],
+        };
+        let mut transport = LoraTransport {
+            lora,
+            profile,
+            plan,
+            rx: RxConfig::default(),
+            gate: TxGate::downlink(region),
+            channels,
+            spreading_factors,
+            channel: 0,
+            dwell,
+            reassembler: Reassembler::new(REASSEMBLY_TIMEOUT),
+            fec,
+        };
+        transport.listen_on_channel();
+        transport
+    }
+
+    /// Sets the frequency and data rate of the receive windows of the EDs, by default those of the
+    /// config shipped with the client. These are only used without a region.
+    ///
+    /// # Arguments
+    ///
+    /// * `rx` - The receive windows of the EDs
+    pub fn set_receive_windows(&mut self, rx: RxConfig) {
+        self.rx = rx;
+    }
+
+    /// The radio the transport is running on.
+    pub fn lora(&mut self) -> &mut LoRa<Spi, OutputPin, OutputPin> {
+        &mut self.lora
+    }
+
+    /// The TX gate, holding the duty-cycle budget left in each sub-band.
+    pub fn gate(&mut self) -> &mut TxGate {
+        &mut self.gate
+    }
+
+    fn frequency(&self) -> i64 {
+        self.channels[self.channel / self.spreading_factors.len()].frequency
+    }
+
+    fn spreading_factor(&self) -> u8 {
+        self.spreading_factors[self.channel % self.spreading_factors.len()]
+    }
+
+    /// How long we wait for the next fragment of a frame on the current channel, in ms.
+    fn fragment_gap(&self) -> i32 {
+        Modulation::from_profile(&self.profile)
+            .with_data_rate(self.spreading_factor(), self.profile.bandwidth)
+            .time_on_air(MAX_FRAME_LENGTH)
+            .as_millis() as i32
+            + FRAGMENT_GAP
+    }
+
+    fn listen_on_channel(&mut self) {
+        let frequency = self.frequency();
+        self.tune(frequency, self.spreading_factor(), self.profile.bandwidth);
+    }
+
+    fn tune(&mut self, frequency: i64, spreading_factor: u8, bandwidth: i64) {
+        if self.lora.set_frequency_hz(frequency).is_err() {
+            println!("Setting frequency {:?} Hz failed", frequency);
+        }
+        if self.lora.set_spreading_factor(spreading_factor).is_err() {
+            println!("Setting spreading factor {:?} failed", spreading_factor);
+        }
+        if self.lora.set_signal_bandwidth(bandwidth).is_err() {
+            println!("Setting bandwidth {:?} failed", bandwidth);
+        }
+    }
+}
+
+impl Transport for LoraTransport {
+    fn receive(&mut self, deadline: Option<Instant>) -> Result<Frame, TransportError> {
+        let combinations = self.channels.len() * self.spreading_factors.len();
+        loop {
+            let pending = self.reassembler.is_pending();
+            let timeout = if pending {
+                Some(self.fragment_gap())
+            } else if combinations > 1 {
+                Some(self.dwell)
+            } else {
+                None
+            };
+            // Stop listening when a downlink is due, without hopping or giving up on fragments
+            let until_deadline = deadline.map(|deadline| {
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_millis() as i32
+            });
+            let cut = match (timeout, until_deadline) {
+                (_, Some(0)) => return Err(TransportError::Timeout),
+                (Some(timeout), Some(until)) => until < timeout,
+                (None, Some(_)) => true,
+                (_, None) => false,
+            };
+            let timeout = if cut { until_deadline } else { timeout };
+            let size = match self.lora.poll_irq(timeout, &mut Delay) {
+                Ok(size) => size,
+                Err(_) if cut => return Err(TransportError::Timeout),
+                Err(_) => {
+                    if pending {
+                        println!("The rest of a fragmented frame did not arrive, dropping it");
+                        self.reassembler = Reassembler::new(REASSEMBLY_TIMEOUT);
+                    }
+                    self.channel = (self.channel + 1) % combinations;
+                    self.listen_on_channel();
+                    return Err(TransportError::Timeout);
+                }
+            };
+            let received = Instant::now();
+            println!("Recieved packet with size: {:?}", size);
+            // NOTE: 255 bytes are always returned
+            let payload = self
+                .lora
+                .read_packet()
+                .map_err(|x| TransportError::Radio(format!("{:?}", x)))?;
+            let rssi = self
+                .lora
+                .get_packet_rssi()
+                .map_err(|x| TransportError::Radio(format!("{:?}", x)))?;
+            let snr = self
+                .lora
+                .get_packet_snr()
+                .map_err(|x| TransportError::Radio(format!("{:?}", x)))?;
+            let meta = FrameMeta {
+                received,
+                frequency: self.frequency(),
+                spreading_factor: self.spreading_factor(),
+                bandwidth: self.profile.bandwidth,
+                rssi,
+                snr,
+            };
+            if !fragment::is_fragment(&payload) {
+                return Ok(Frame { payload, meta });
+            }
+            match self.reassembler.add(&payload) {
+                Ok(Some(payload)) => return Ok(Frame { payload, meta }),
+                Ok(None) => (),
+                Err(x) => println!("{}", x),
+            }
+        }
+    }
+
+    fn transmit(
+        &mut self,
+        payload: Vec<u8>,
+        window: Window,
+        uplink: &FrameMeta,
+    ) -> Result<usize, TransportError> {
+        let (modulation, frequency, max_length) = match &self.plan {
+            Some(plan) => {
+                let data_rate = match window {
+                    Window::Rx1 => plan.rx1_data_rate(
+                        plan.uplink_data_rate(uplink.spreading_factor, uplink.bandwidth)
+                            .unwrap_or(plan.rx2_data_rate),
+                    ),
+                    Window::Rx2 => plan.rx2_data_rate,
+                };
+                let frequency = match window {
+                    Window::Rx1 => plan.rx1_frequency(uplink.frequency),
+                    Window::Rx2 => plan.rx2_frequency,
+                };
+                (
+                    Modulation::from_profile(&self.profile)
+                        .with_data_rate(data_rate.spreading_factor, data_rate.bandwidth),
+                    frequency,
+                    data_rate.max_payload,
+                )
+            }
+            None => {
+                let profile = self.rx.window_profile(window, self.profile);
+                (
+                    Modulation::from_profile(&profile),
+                    profile.frequency * 1_000_000,
+                    MAX_FRAME_LENGTH,
+                )
+            }
+        };
+        let frames = if payload.len() > max_length {
+            fragment::split(&payload, max_length, self.fec).ok_or(
+                TransportError::PayloadTooLarge {
+                    size: payload.len(),
+                    max: fragment::max_frame_length(max_length),
+                },
+            )?
+        } else {
+            vec![payload]
+        };
+        let airtimes: Vec<Duration> = frames
+            .iter()
+            .map(|frame| modulation.time_on_air(frame.len()))
+            .collect();
+        self.gate
+            .check(frequency, &airtimes)
+            .map_err(TransportError::Gate)?;
+        self.tune(frequency, modulation.spreading_factor, modulation.bandwidth);
+        let mut transmit = Ok(0);
+        for (frame, airtime) in frames.into_iter().zip(airtimes) {
+            let (msg_buffer, len) = get_message_lenght(frame);
+            match self.lora.transmit_payload_busy(msg_buffer, len) {
+                Ok(size) => {
+                    self.gate.record(frequency, airtime);
+                    transmit = transmit.map(|sent| sent + size);
+                }
+                Err(x) => {
+                    transmit = Err(TransportError::Radio(format!("{:?}", x)));
+                    break;
+                }
+            }
+        }
+        if let Some(budget) = self.gate.budget(frequency) {
+            println!(
+                "Duty-cycle budget of {:?} to {:?} Hz used {:?}, left {:?}",
+                budget.sub_band.low, budget.sub_band.high, budget.used, budget.remaining
+            );
+        }
+        self.listen_on_channel();
+        transmit
+    }
+}